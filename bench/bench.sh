@@ -0,0 +1,47 @@
+#!/usr/bin/env bash
+# Times every program in this directory on the tree-walker and on the VM.
+#
+#   bench/bench.sh [baseline_binary]
+#
+# When a baseline binary is given (for example a release build of an older commit), the
+# tree-walker is also timed with it so the speedup of a change can be read off directly.
+set -euo pipefail
+
+root="$(cd "$(dirname "$0")/.." && pwd)"
+baseline="${1:-}"
+
+cargo build --release --quiet --manifest-path "$root/Cargo.toml"
+binary="$root/target/release/valkyrie-interpreter"
+
+# Best of three runs, in seconds
+best() {
+    local best_time=""
+    for _ in 1 2 3; do
+        local start elapsed
+        start=$(date +%s%N)
+        "$@" > /dev/null
+        elapsed=$(( $(date +%s%N) - start ))
+        if [[ -z "$best_time" ]] || (( elapsed < best_time )); then
+            best_time=$elapsed
+        fi
+    done
+    printf "%d.%03d" $(( best_time / 1000000000 )) $(( best_time / 1000000 % 1000 ))
+}
+
+if [[ -n "$baseline" ]]; then
+    printf "%-20s %12s %12s %12s\n" "program" "baseline" "tree-walker" "vm"
+else
+    printf "%-20s %12s %12s\n" "program" "tree-walker" "vm"
+fi
+
+for program in "$root"/bench/*.valkyrie; do
+    name="$(basename "$program")"
+    walker=$(best "$binary" --run_file "$program")
+    vm=$(best "$binary" --vm --run_file "$program")
+    if [[ -n "$baseline" ]]; then
+        old=$(best "$baseline" --run_file "$program")
+        printf "%-20s %12s %12s %12s\n" "$name" "$old" "$walker" "$vm"
+    else
+        printf "%-20s %12s %12s\n" "$name" "$walker" "$vm"
+    fi
+done
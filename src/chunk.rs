@@ -0,0 +1,139 @@
+use crate::diagnostic::Span;
+use crate::expr::LiteralValue;
+use std::rc::Rc;
+
+// Operands are indexes into the pools of the chunk, stack slots relative to the frame, or
+// jump distances counted in instructions
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OpCode {
+    Constant(u16),
+    Null,
+    True,
+    False,
+    Pop,
+
+    GetLocal(u16),
+    SetLocal(u16),
+    GetUpvalue(u16),
+    SetUpvalue(u16),
+    GetGlobal(u16),
+    DefineGlobal(u16),
+    SetGlobal(u16),
+    // Moves the variable in the top slot to the heap if a closure captured it, then pops it
+    CloseUpvalue,
+
+    GetProperty(u16),
+    SetProperty(u16),
+    // [this, superclass] -> method bound to this
+    GetSuper(u16),
+    Index,
+    IndexSet,
+    // Checks the key on top of the stack without popping it
+    CheckKey,
+    Array(u16),
+    Map(u16),
+
+    Equal,
+    NotEqual,
+    Greater,
+    GreaterEqual,
+    Less,
+    LessEqual,
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Not,
+    Negate,
+
+    Print,
+    Jump(u16),
+    // Does not pop the condition
+    JumpIfFalse(u16),
+    Loop(u16),
+    Call(u8),
+    // A call in tail position, its frame replaces the frame of the function that is returning
+    TailCall(u8),
+    Closure(u16),
+    Return,
+
+    // Errors raised until the matching PopHandler jump forward to the catch block, which gets
+    // the caught value on the stack, or to the finally block when the flag is set
+    PushHandler(u16, bool),
+    PopHandler,
+    Throw,
+    // Raises the error again once the finally block that ran for it is done
+    Rethrow,
+    // Pushes the module at the path, running it the first time
+    Import(u16),
+    // Pops a module and declares its globals as globals of the running file
+    ImportAll,
+
+    // Pushes a new class, it inherits from the value below it when the flag is set
+    Class(u16, bool),
+    // [class, closure] -> [class]
+    Method(u16),
+    // Classes declared below the top level can only replace an existing global
+    AssignClass(u16),
+}
+
+// How a closure finds a captured variable when it is created: a slot of the enclosing frame, or
+// one of the upvalues of the enclosing closure
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct UpvalueRef {
+    pub index: u16,
+    pub is_local: bool,
+}
+
+#[derive(Default)]
+pub struct Chunk {
+    pub code: Vec<OpCode>,
+    // Line table: the span of the expression or statement every instruction was compiled from,
+    // used for runtime errors
+    pub spans: Vec<Span>,
+    pub constants: Vec<LiteralValue>,
+    // Names of globals, properties and methods
+    pub names: Vec<String>,
+    pub functions: Vec<Rc<Function>>,
+}
+
+impl Chunk {
+    pub fn write(&mut self, op: OpCode, span: Span) -> usize {
+        self.code.push(op);
+        self.spans.push(span);
+        self.code.len() - 1
+    }
+}
+
+#[derive(Default)]
+pub struct Function {
+    pub name: String,
+    pub arity: usize,
+    pub chunk: Chunk,
+    pub upvalues: Vec<UpvalueRef>,
+    // The class a method was declared in, used for tracebacks
+    pub class_name: Option<String>,
+}
+
+impl std::fmt::Debug for Function {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.name, self.arity)
+    }
+}
+
+impl Function {
+    #[allow(dead_code)]
+    pub fn disassemble(&self) -> String {
+        let mut result = format!("== {} ==\n", self.name);
+        for (i, op) in self.chunk.code.iter().enumerate() {
+            result.push_str(&format!(
+                "{:04} {:>4} {:?}\n",
+                i, self.chunk.spans[i].start_line, op
+            ));
+        }
+        for function in &self.chunk.functions {
+            result.push_str(&function.disassemble());
+        }
+        result
+    }
+}
@@ -0,0 +1,243 @@
+use crate::error::ErrorCode;
+use crate::expr::{CallableImpl, LiteralValue, NativeFunctionImpl};
+use crate::natives::native_error;
+use crate::sandbox::Sandbox;
+use crate::stdlib::to_str;
+use std::cell::RefCell;
+use std::process::Command;
+use std::rc::Rc;
+
+// Splits the command of `fun name <- "cmd"` into words the way a shell would. Whitespace separates
+// words, quotes group them and a backslash escapes the next char outside single quotes
+pub fn split_words(cmd: &str) -> Result<Vec<String>, String> {
+    let mut words = vec![];
+    // None between words, quotes start a word even when they are empty
+    let mut word: Option<String> = None;
+    let mut chars = cmd.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => {
+                if let Some(word) = word.take() {
+                    words.push(word);
+                }
+            }
+            '\'' => {
+                let word = word.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some(c) => word.push(c),
+                        None => return Err("Unterminated ' in command".to_string()),
+                    }
+                }
+            }
+            '"' => {
+                let word = word.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some(c) if c == '"' || c == '\\' => word.push(c),
+                            Some(c) => {
+                                word.push('\\');
+                                word.push(c);
+                            }
+                            None => return Err("Unterminated \" in command".to_string()),
+                        },
+                        Some(c) => word.push(c),
+                        None => return Err("Unterminated \" in command".to_string()),
+                    }
+                }
+            }
+            '\\' => match chars.next() {
+                Some(c) => word.get_or_insert_with(String::new).push(c),
+                None => return Err("Command ends with a backslash".to_string()),
+            },
+            c => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+    if let Some(word) = word {
+        words.push(word);
+    }
+    if words.is_empty() {
+        return Err("Empty command".to_string());
+    }
+    Ok(words)
+}
+
+// The parts of a word, {n} is replaced with argument n when the command is called
+enum Part {
+    Text(String),
+    Argument(usize),
+}
+
+fn parts(word: &str) -> Vec<Part> {
+    let mut parts = vec![];
+    let mut text = String::new();
+    let mut rest = word;
+    while let Some(open) = rest.find('{') {
+        let after = &rest[open + 1..];
+        let digits = after.chars().take_while(char::is_ascii_digit).count();
+        let index = if after[digits..].starts_with('}') {
+            after[..digits].parse::<usize>().ok()
+        } else {
+            None
+        };
+        match index {
+            Some(index) => {
+                text.push_str(&rest[..open]);
+                if !text.is_empty() {
+                    parts.push(Part::Text(std::mem::take(&mut text)));
+                }
+                parts.push(Part::Argument(index));
+                rest = &after[digits + 1..];
+            }
+            // Other braces are part of the word
+            None => {
+                text.push_str(&rest[..=open]);
+                rest = after;
+            }
+        }
+    }
+    text.push_str(rest);
+    if !text.is_empty() || parts.is_empty() {
+        parts.push(Part::Text(text));
+    }
+    parts
+}
+
+// The words of the process to start. Arguments only ever make up words or parts of words, they are
+// never split or read by a shell
+fn command_line(words: &[Vec<Part>], placeholders: bool, args: &[LiteralValue]) -> Vec<String> {
+    let mut line = words
+        .iter()
+        .map(|parts| {
+            parts
+                .iter()
+                .map(|part| match part {
+                    Part::Text(text) => text.clone(),
+                    Part::Argument(index) => to_str(&args[*index]),
+                })
+                .collect::<String>()
+        })
+        .collect::<Vec<String>>();
+    if !placeholders {
+        line.extend(args.iter().map(to_str));
+    }
+    line
+}
+
+// A callable that runs a command and returns a map of its stdout, stderr and exit status. The
+// status is null when the process was killed by a signal. The sandbox is checked on every call
+pub fn command_function(
+    name: &str,
+    words: &[String],
+    sandbox: Rc<RefCell<Sandbox>>,
+) -> LiteralValue {
+    let words = words.iter().map(|word| parts(word)).collect::<Vec<_>>();
+    // Commands with placeholders take one argument per placeholder, the others any number of
+    // arguments that are added to the end
+    let arity = words
+        .iter()
+        .flatten()
+        .filter_map(|part| match part {
+            Part::Argument(index) => Some(index + 1),
+            Part::Text(_) => None,
+        })
+        .max();
+    let placeholders = arity.is_some();
+
+    let local_fn = {
+        let name = name.to_string();
+        move |args: &[LiteralValue]| {
+            let line = command_line(&words, placeholders, args);
+            sandbox.borrow().check_command(&line[0])?;
+            let output = Command::new(&line[0])
+                .args(&line[1..])
+                .output()
+                .map_err(|err| {
+                    native_error(
+                        ErrorCode::CommandFailed,
+                        format!("Command {} could not run {}: {}", name, line[0], err),
+                    )
+                })?;
+
+            let string = |s: &str| LiteralValue::StringValue(s.to_string());
+            Ok(LiteralValue::new_map(vec![
+                (
+                    string("stdout"),
+                    string(&String::from_utf8_lossy(&output.stdout)),
+                ),
+                (
+                    string("stderr"),
+                    string(&String::from_utf8_lossy(&output.stderr)),
+                ),
+                (
+                    string("status"),
+                    output
+                        .status
+                        .code()
+                        .map_or(LiteralValue::Null, |code| LiteralValue::Number(code as f64)),
+                ),
+            ]))
+        }
+    };
+
+    LiteralValue::Callable(CallableImpl::NativeFunction(NativeFunctionImpl {
+        name: name.to_string(),
+        arity: arity.unwrap_or(0),
+        variadic: !placeholders,
+        fun: Rc::new(local_fn),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn split(cmd: &str) -> Vec<String> {
+        split_words(cmd).unwrap()
+    }
+
+    #[test]
+    fn quotes_group_words() {
+        assert_eq!(
+            split(r#"grep -e 'a  b' "it's" x\ y ''"#),
+            vec!["grep", "-e", "a  b", "it's", "x y", ""]
+        );
+        assert_eq!(split(r#"echo "say \"hi\"""#), vec!["echo", r#"say "hi""#]);
+        assert!(split_words("echo 'open").is_err());
+        assert!(split_words("   ").is_err());
+    }
+
+    #[test]
+    fn placeholders_are_substituted_in_words() {
+        let words = ["echo", "--name={0}", "{1}", "{x}", "{2"]
+            .iter()
+            .map(|word| parts(word))
+            .collect::<Vec<_>>();
+        let args = [
+            LiteralValue::StringValue("a b; rm -rf /".to_string()),
+            LiteralValue::Number(2.0),
+        ];
+        assert_eq!(
+            command_line(&words, true, &args),
+            vec!["echo", "--name=a b; rm -rf /", "2", "{x}", "{2"]
+        );
+    }
+
+    #[test]
+    fn failed_commands_are_errors() {
+        let command = command_function(
+            "missing",
+            &split("valkyrie-no-such-command --flag"),
+            Default::default(),
+        );
+        let native = match command {
+            LiteralValue::Callable(CallableImpl::NativeFunction(native)) => native,
+            _ => unreachable!(),
+        };
+        let err = (native.fun)(&[]).unwrap_err();
+        assert_eq!(err.code(), ErrorCode::CommandFailed);
+    }
+}
@@ -0,0 +1,946 @@
+use crate::chunk::{Chunk, Function, OpCode, UpvalueRef};
+use crate::command::command_function;
+use crate::diagnostic::Span;
+use crate::error::{ErrorCode, ValkyrieError};
+use crate::expr::{Expr, LiteralValue};
+use crate::sandbox::Sandbox;
+use crate::scanner::{Token, TokenType};
+use crate::stmt::Stmt;
+use std::cell::RefCell;
+use std::rc::Rc;
+
+#[derive(Copy, Clone, PartialEq)]
+enum FunctionKind {
+    Script,
+    Function,
+    Method,
+}
+
+struct Local {
+    name: String,
+    depth: usize,
+    // Captured locals are moved to the heap instead of popped when their scope ends
+    captured: bool,
+}
+
+struct Loop {
+    // Scope depth around the loop, break and continue pop every local deeper than it
+    depth: usize,
+    breaks: Vec<usize>,
+    continues: Vec<usize>,
+    // How many try statements were around the loop, break and continue leave the others
+    tries: usize,
+}
+
+// A try statement whose blocks are being compiled
+#[derive(Clone)]
+struct OpenTry {
+    // Compiled again wherever break, continue or return leave the try statement
+    finally: Option<Stmt>,
+    // Handlers still pushed, the catch handler is popped before the catch block runs
+    handlers: usize,
+}
+
+// Operands are 16 bits wide, programs that need more are rejected
+fn to_u16(value: usize, what: &str, span: Span) -> Result<u16, ValkyrieError> {
+    u16::try_from(value).map_err(|_| {
+        ValkyrieError::compile(
+            ErrorCode::LimitExceeded,
+            format!("Too many {} in one function", what),
+            span,
+        )
+    })
+}
+
+// The function being compiled, the ones it is nested in are below it on the stack
+struct FunctionState {
+    function: Function,
+    locals: Vec<Local>,
+    scope_depth: usize,
+    loops: Vec<Loop>,
+    tries: Vec<OpenTry>,
+}
+
+// Compiles the resolved statements to bytecode, the resolver already rejected the programs that
+// are not valid, so variables are looked up the same way here: innermost local first, then the
+// variables captured from the enclosing functions, then the globals
+pub struct Compiler {
+    states: Vec<FunctionState>,
+    // Given to the command functions, which are constants of the chunk
+    sandbox: Rc<RefCell<Sandbox>>,
+}
+
+impl Compiler {
+    pub fn compile(
+        stmts: &Vec<&Stmt>,
+        sandbox: &Rc<RefCell<Sandbox>>,
+    ) -> Result<Rc<Function>, ValkyrieError> {
+        let mut compiler = Compiler {
+            states: vec![],
+            sandbox: sandbox.clone(),
+        };
+        compiler.begin_function("script".to_string(), FunctionKind::Script, None);
+        for stmt in stmts {
+            compiler.statement(stmt)?;
+        }
+        let end = stmts.last().map(|stmt| stmt.get_span()).unwrap_or_default();
+        compiler.emit(OpCode::Null, end);
+        compiler.emit(OpCode::Return, end);
+        let function = compiler.end_function();
+
+        Ok(Rc::new(function))
+    }
+
+    fn state(&mut self) -> &mut FunctionState {
+        self.states.last_mut().unwrap()
+    }
+
+    fn chunk(&mut self) -> &mut Chunk {
+        &mut self.state().function.chunk
+    }
+
+    fn emit(&mut self, op: OpCode, span: Span) -> usize {
+        self.chunk().write(op, span)
+    }
+
+    fn constant(&mut self, value: LiteralValue, span: Span) -> Result<u16, ValkyrieError> {
+        self.chunk().constants.push(value);
+        let index = self.chunk().constants.len() - 1;
+        to_u16(index, "constants", span)
+    }
+
+    fn name(&mut self, name: &str, span: Span) -> Result<u16, ValkyrieError> {
+        let index = match self.chunk().names.iter().position(|n| n == name) {
+            Some(index) => index,
+            None => {
+                self.chunk().names.push(name.to_string());
+                self.chunk().names.len() - 1
+            }
+        };
+        to_u16(index, "names", span)
+    }
+
+    // Emits a forward jump whose distance is filled in by patch_jump
+    fn emit_jump(&mut self, op: fn(u16) -> OpCode, span: Span) -> usize {
+        self.emit(op(0), span)
+    }
+
+    fn patch_jump(&mut self, jump: usize) -> Result<(), ValkyrieError> {
+        let span = self.chunk().spans[jump];
+        let offset = to_u16(self.chunk().code.len() - jump - 1, "instructions", span)?;
+        let chunk = self.chunk();
+        chunk.code[jump] = match chunk.code[jump] {
+            OpCode::Jump(_) => OpCode::Jump(offset),
+            OpCode::JumpIfFalse(_) => OpCode::JumpIfFalse(offset),
+            OpCode::PushHandler(_, finally) => OpCode::PushHandler(offset, finally),
+            op => panic!("Tried to patch {:?}, which is not a jump", op),
+        };
+        Ok(())
+    }
+
+    fn emit_loop(&mut self, start: usize, span: Span) -> Result<(), ValkyrieError> {
+        let offset = to_u16(self.chunk().code.len() - start + 1, "instructions", span)?;
+        self.emit(OpCode::Loop(offset), span);
+        Ok(())
+    }
+
+    fn begin_function(&mut self, name: String, kind: FunctionKind, class_name: Option<String>) {
+        // Slot 0 holds the function that is called, or the instance for methods
+        let slot_zero = if kind == FunctionKind::Method {
+            "this"
+        } else {
+            ""
+        };
+        self.states.push(FunctionState {
+            function: Function {
+                name,
+                class_name,
+                ..Default::default()
+            },
+            locals: vec![Local {
+                name: slot_zero.to_string(),
+                depth: 0,
+                captured: false,
+            }],
+            scope_depth: 0,
+            loops: vec![],
+            tries: vec![],
+        });
+    }
+
+    fn end_function(&mut self) -> Function {
+        self.states.pop().unwrap().function
+    }
+
+    fn begin_scope(&mut self) {
+        self.state().scope_depth += 1;
+    }
+
+    fn end_scope(&mut self, span: Span) {
+        self.state().scope_depth -= 1;
+        let depth = self.state().scope_depth;
+        while let Some(local) = self.state().locals.last() {
+            if local.depth <= depth {
+                break;
+            }
+            let op = if local.captured {
+                OpCode::CloseUpvalue
+            } else {
+                OpCode::Pop
+            };
+            self.state().locals.pop();
+            self.emit(op, span);
+        }
+    }
+
+    // Removes the locals deeper than depth from the stack without forgetting them, for the jumps
+    // that leave a loop. A closure further down the loop may still capture any of them.
+    fn discard_locals(&mut self, depth: usize, span: Span) {
+        let count = self
+            .state()
+            .locals
+            .iter()
+            .rev()
+            .take_while(|local| local.depth > depth)
+            .count();
+        for _ in 0..count {
+            self.emit(OpCode::CloseUpvalue, span);
+        }
+    }
+
+    fn add_local(&mut self, name: &Token) -> Result<(), ValkyrieError> {
+        let depth = self.state().scope_depth;
+        self.state().locals.push(Local {
+            name: name.name().to_string(),
+            depth,
+            captured: false,
+        });
+        to_u16(self.state().locals.len() - 1, "local variables", name.span)?;
+        Ok(())
+    }
+
+    // The value of the variable is on top of the stack
+    fn define_variable(&mut self, name: &Token) -> Result<(), ValkyrieError> {
+        if self.state().scope_depth > 0 {
+            self.add_local(name)
+        } else {
+            let index = self.name(name.name(), name.span)?;
+            self.emit(OpCode::DefineGlobal(index), name.span);
+            Ok(())
+        }
+    }
+
+    fn resolve_local(&self, state: usize, name: &str) -> Option<u16> {
+        self.states[state]
+            .locals
+            .iter()
+            .rposition(|local| local.name == name)
+            .map(|slot| slot as u16)
+    }
+
+    fn resolve_upvalue(&mut self, state: usize, name: &str) -> Option<u16> {
+        if state == 0 {
+            return None;
+        }
+
+        let upvalue = if let Some(slot) = self.resolve_local(state - 1, name) {
+            self.states[state - 1].locals[slot as usize].captured = true;
+            UpvalueRef {
+                index: slot,
+                is_local: true,
+            }
+        } else {
+            UpvalueRef {
+                index: self.resolve_upvalue(state - 1, name)?,
+                is_local: false,
+            }
+        };
+
+        let upvalues = &mut self.states[state].function.upvalues;
+        let index = match upvalues.iter().position(|u| *u == upvalue) {
+            Some(index) => index,
+            None => {
+                upvalues.push(upvalue);
+                upvalues.len() - 1
+            }
+        };
+        Some(index as u16)
+    }
+
+    fn get_variable(&mut self, name: &str, span: Span) -> Result<(), ValkyrieError> {
+        let state = self.states.len() - 1;
+        let op = if let Some(slot) = self.resolve_local(state, name) {
+            OpCode::GetLocal(slot)
+        } else if let Some(index) = self.resolve_upvalue(state, name) {
+            OpCode::GetUpvalue(index)
+        } else {
+            OpCode::GetGlobal(self.name(name, span)?)
+        };
+        self.emit(op, span);
+        Ok(())
+    }
+
+    fn set_variable(&mut self, name: &str, span: Span) -> Result<(), ValkyrieError> {
+        let state = self.states.len() - 1;
+        let op = if let Some(slot) = self.resolve_local(state, name) {
+            OpCode::SetLocal(slot)
+        } else if let Some(index) = self.resolve_upvalue(state, name) {
+            OpCode::SetUpvalue(index)
+        } else {
+            OpCode::SetGlobal(self.name(name, span)?)
+        };
+        self.emit(op, span);
+        Ok(())
+    }
+
+    fn statements(&mut self, stmts: &[Box<Stmt>]) -> Result<(), ValkyrieError> {
+        for stmt in stmts {
+            self.statement(stmt)?;
+        }
+        Ok(())
+    }
+
+    fn statement(&mut self, stmt: &Stmt) -> Result<(), ValkyrieError> {
+        match stmt {
+            Stmt::Expression { span, expression } => {
+                self.expression(expression)?;
+                self.emit(OpCode::Pop, *span);
+            }
+            Stmt::Print { span, expression } => {
+                self.expression(expression)?;
+                self.emit(OpCode::Print, *span);
+            }
+            Stmt::Var {
+                span: _,
+                name,
+                initializer,
+            } => {
+                self.expression(initializer)?;
+                self.define_variable(name)?;
+            }
+            Stmt::Block { span, statements } => {
+                self.begin_scope();
+                self.statements(statements)?;
+                self.end_scope(*span);
+            }
+            Stmt::Class {
+                span,
+                name,
+                methods,
+                superclass,
+            } => self.class(*span, name, methods, superclass)?,
+            Stmt::IfStmt {
+                span,
+                predicate,
+                then,
+                els,
+            } => {
+                self.expression(predicate)?;
+                let else_jump = self.emit_jump(OpCode::JumpIfFalse, *span);
+                self.emit(OpCode::Pop, *span);
+                self.statement(then)?;
+                let end_jump = self.emit_jump(OpCode::Jump, *span);
+                self.patch_jump(else_jump)?;
+                self.emit(OpCode::Pop, *span);
+                if let Some(els) = els {
+                    self.statement(els)?;
+                }
+                self.patch_jump(end_jump)?;
+            }
+            Stmt::WhileStmt {
+                span,
+                condition,
+                body,
+                increment,
+            } => {
+                let start = self.chunk().code.len();
+                self.expression(condition)?;
+                let exit_jump = self.emit_jump(OpCode::JumpIfFalse, *span);
+                self.emit(OpCode::Pop, *span);
+
+                let depth = self.state().scope_depth;
+                let tries = self.state().tries.len();
+                self.state().loops.push(Loop {
+                    depth,
+                    breaks: vec![],
+                    continues: vec![],
+                    tries,
+                });
+                self.statement(body)?;
+                let lp = self.state().loops.pop().unwrap();
+
+                for jump in lp.continues {
+                    self.patch_jump(jump)?;
+                }
+                if let Some(increment) = increment {
+                    self.expression(increment)?;
+                    self.emit(OpCode::Pop, *span);
+                }
+                self.emit_loop(start, *span)?;
+
+                self.patch_jump(exit_jump)?;
+                self.emit(OpCode::Pop, *span);
+                for jump in lp.breaks {
+                    self.patch_jump(jump)?;
+                }
+            }
+            Stmt::Break { span, keyword: _ } | Stmt::Continue { span, keyword: _ } => {
+                let lp = self.state().loops.last().unwrap();
+                let (depth, tries) = (lp.depth, lp.tries);
+                self.exit_tries(tries, *span)?;
+                self.discard_locals(depth, *span);
+                let jump = self.emit_jump(OpCode::Jump, *span);
+                let lp = self.state().loops.last_mut().unwrap();
+                if let Stmt::Break { .. } = stmt {
+                    lp.breaks.push(jump);
+                } else {
+                    lp.continues.push(jump);
+                }
+            }
+            Stmt::Function {
+                span,
+                name,
+                params,
+                body,
+            } => {
+                // A local function is declared before its body so that it can call itself
+                if self.state().scope_depth > 0 {
+                    self.add_local(name)?;
+                }
+                self.function(
+                    name.name().to_string(),
+                    FunctionKind::Function,
+                    None,
+                    params,
+                    body,
+                    *span,
+                )?;
+                if self.state().scope_depth == 0 {
+                    self.define_variable(name)?;
+                }
+            }
+            Stmt::CmdFunction { span, name, cmd } => {
+                let command = command_function(name.name(), cmd, self.sandbox.clone());
+                let index = self.constant(command, *span)?;
+                self.emit(OpCode::Constant(index), *span);
+                self.define_variable(name)?;
+            }
+            Stmt::ReturnStmt {
+                span,
+                keyword: _,
+                value,
+            } => {
+                let in_try = !self.state().tries.is_empty();
+                match value {
+                    // Not inside a try statement, its finally blocks still have to run after the
+                    // call
+                    Some(Expr::Call {
+                        id: _,
+                        span: call_span,
+                        callee,
+                        paren: _,
+                        arguments,
+                    }) if !in_try => {
+                        self.expression(callee)?;
+                        for argument in arguments {
+                            self.expression(argument)?;
+                        }
+                        self.emit(OpCode::TailCall(arguments.len() as u8), *call_span);
+                    }
+                    Some(value) => self.expression(value)?,
+                    None => {
+                        self.emit(OpCode::Null, *span);
+                    }
+                }
+                if in_try {
+                    // The returned value waits in a slot of its own while the finally blocks run
+                    let depth = self.state().scope_depth;
+                    self.state().locals.push(Local {
+                        name: "".to_string(),
+                        depth,
+                        captured: false,
+                    });
+                    self.exit_tries(0, *span)?;
+                    self.state().locals.pop();
+                }
+                self.emit(OpCode::Return, *span);
+            }
+            Stmt::Throw {
+                span,
+                keyword: _,
+                value,
+            } => {
+                self.expression(value)?;
+                self.emit(OpCode::Throw, *span);
+            }
+            Stmt::Try {
+                span,
+                body,
+                catch,
+                finally,
+            } => self.try_statement(*span, body, catch, finally)?,
+            Stmt::Import { span, path, name } => {
+                let index = self.name(path, *span)?;
+                self.emit(OpCode::Import(index), *span);
+                match name {
+                    Some(name) => self.define_variable(name)?,
+                    None => {
+                        self.emit(OpCode::ImportAll, *span);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // The handlers are pushed before the try block, the finally block is compiled twice: once
+    // where the try or catch block ends, and once for the errors, raising them again after it
+    fn try_statement(
+        &mut self,
+        span: Span,
+        body: &Stmt,
+        catch: &Option<(Token, Box<Stmt>)>,
+        finally: &Option<Box<Stmt>>,
+    ) -> Result<(), ValkyrieError> {
+        let finally_handler = finally
+            .as_ref()
+            .map(|_| self.emit(OpCode::PushHandler(0, true), span));
+        let catch_handler = catch
+            .as_ref()
+            .map(|_| self.emit(OpCode::PushHandler(0, false), span));
+        self.state().tries.push(OpenTry {
+            finally: finally.as_ref().map(|finally| (**finally).clone()),
+            handlers: finally_handler.iter().count() + catch_handler.iter().count(),
+        });
+
+        self.statement(body)?;
+
+        if let (Some(catch_handler), Some((name, catch_body))) = (catch_handler, catch) {
+            self.emit(OpCode::PopHandler, span);
+            self.state().tries.last_mut().unwrap().handlers -= 1;
+            let end_jump = self.emit_jump(OpCode::Jump, span);
+            self.patch_jump(catch_handler)?;
+            self.begin_scope();
+            self.add_local(name)?;
+            self.statement(catch_body)?;
+            self.end_scope(span);
+            self.patch_jump(end_jump)?;
+        }
+        self.state().tries.pop();
+
+        if let (Some(finally_handler), Some(finally)) = (finally_handler, finally) {
+            self.emit(OpCode::PopHandler, span);
+            self.statement(finally)?;
+            let end_jump = self.emit_jump(OpCode::Jump, span);
+            self.patch_jump(finally_handler)?;
+            self.statement(finally)?;
+            self.emit(OpCode::Rethrow, span);
+            self.patch_jump(end_jump)?;
+        }
+        Ok(())
+    }
+
+    // Leaves the try statements above level for a break, continue or return: their handlers are
+    // popped and their finally blocks run, innermost first
+    fn exit_tries(&mut self, level: usize, span: Span) -> Result<(), ValkyrieError> {
+        let tries = self.state().tries.split_off(level);
+        for i in (0..tries.len()).rev() {
+            for _ in 0..tries[i].handlers {
+                self.emit(OpCode::PopHandler, span);
+            }
+            if let Some(finally) = &tries[i].finally {
+                // A finally block only sees the try statements and loops around its own try
+                self.state().tries.extend(tries[..i].iter().cloned());
+                let outer = level + i;
+                let position = self.state().loops.partition_point(|lp| lp.tries <= outer);
+                let loops = self.state().loops.split_off(position);
+                self.statement(finally)?;
+                self.state().loops.extend(loops);
+                self.state().tries.truncate(level);
+            }
+        }
+        self.state().tries.extend(tries);
+        Ok(())
+    }
+
+    fn class(
+        &mut self,
+        span: Span,
+        name: &Token,
+        methods: &[Box<Stmt>],
+        superclass: &Option<Expr>,
+    ) -> Result<(), ValkyrieError> {
+        let top_level = self.state().scope_depth == 0;
+        if !top_level {
+            // Like the tree-walker, the name is bound to null in the enclosing scope
+            self.emit(OpCode::Null, name.span);
+            self.add_local(name)?;
+        }
+
+        // The superclass stays on the stack as a local named super while the methods are
+        // compiled, so that they capture it
+        let class_span = match superclass {
+            Some(superclass) => {
+                self.expression(superclass)?;
+                self.begin_scope();
+                let depth = self.state().scope_depth;
+                self.state().locals.push(Local {
+                    name: "super".to_string(),
+                    depth,
+                    captured: false,
+                });
+                superclass.get_span()
+            }
+            None => name.span,
+        };
+
+        let index = self.name(name.name(), name.span)?;
+        self.emit(OpCode::Class(index, superclass.is_some()), class_span);
+
+        for method in methods {
+            if let Stmt::Function {
+                span,
+                name: method_name,
+                params,
+                body,
+            } = method.as_ref()
+            {
+                self.function(
+                    method_name.name().to_string(),
+                    FunctionKind::Method,
+                    Some(name.name().to_string()),
+                    params,
+                    body,
+                    *span,
+                )?;
+                let index = self.name(method_name.name(), method_name.span)?;
+                self.emit(OpCode::Method(index), *span);
+            } else {
+                panic!("Something that was not a function was in the methods of a class");
+            }
+        }
+
+        if top_level {
+            self.emit(OpCode::DefineGlobal(index), name.span);
+        } else {
+            self.emit(OpCode::AssignClass(index), name.span);
+        }
+
+        if superclass.is_some() {
+            self.end_scope(span);
+        }
+        Ok(())
+    }
+
+    // Compiles the body to a new function and emits the instruction that creates its closure
+    fn function(
+        &mut self,
+        name: String,
+        kind: FunctionKind,
+        class_name: Option<String>,
+        params: &[Token],
+        body: &[Stmt],
+        span: Span,
+    ) -> Result<(), ValkyrieError> {
+        self.begin_function(name, kind, class_name);
+        self.begin_scope();
+        self.state().function.arity = params.len();
+        for param in params {
+            self.add_local(param)?;
+        }
+        for stmt in body {
+            self.statement(stmt)?;
+        }
+        self.emit(OpCode::Null, span);
+        self.emit(OpCode::Return, span);
+        let function = self.end_function();
+
+        self.chunk().functions.push(Rc::new(function));
+        let index = to_u16(self.chunk().functions.len() - 1, "functions", span)?;
+        self.emit(OpCode::Closure(index), span);
+        Ok(())
+    }
+
+    fn expression(&mut self, expr: &Expr) -> Result<(), ValkyrieError> {
+        let span = expr.get_span();
+        match expr {
+            Expr::AnonFunction {
+                id: _,
+                span: _,
+                paren: _,
+                arguments,
+                body,
+            } => self.function(
+                "anon_funciton".to_string(),
+                FunctionKind::Function,
+                None,
+                arguments,
+                body,
+                span,
+            )?,
+            Expr::Array {
+                id: _,
+                span: _,
+                elements,
+            } => {
+                for element in elements {
+                    self.expression(element)?;
+                }
+                let count = to_u16(elements.len(), "array elements", span)?;
+                self.emit(OpCode::Array(count), span);
+            }
+            Expr::Assign {
+                id: _,
+                span: _,
+                name,
+                value,
+            } => {
+                self.expression(value)?;
+                self.set_variable(name.name(), span)?;
+            }
+            Expr::Binary {
+                id: _,
+                span: _,
+                left,
+                operator,
+                right,
+            } => {
+                self.expression(left)?;
+                self.expression(right)?;
+                let op = match operator.token_type {
+                    TokenType::EqualEqual => OpCode::Equal,
+                    TokenType::BangEqual => OpCode::NotEqual,
+                    TokenType::Greater => OpCode::Greater,
+                    TokenType::GreaterEqual => OpCode::GreaterEqual,
+                    TokenType::Less => OpCode::Less,
+                    TokenType::LessEqual => OpCode::LessEqual,
+                    TokenType::Plus => OpCode::Add,
+                    TokenType::Minus => OpCode::Subtract,
+                    TokenType::Star => OpCode::Multiply,
+                    TokenType::Slash => OpCode::Divide,
+                    ttype => panic!("{} is not a binary operator", ttype),
+                };
+                self.emit(op, span);
+            }
+            Expr::Call {
+                id: _,
+                span: _,
+                callee,
+                paren: _,
+                arguments,
+            } => {
+                self.expression(callee)?;
+                for argument in arguments {
+                    self.expression(argument)?;
+                }
+                // The parser allows at most 255 arguments
+                self.emit(OpCode::Call(arguments.len() as u8), span);
+            }
+            Expr::Get {
+                id: _,
+                span: _,
+                object,
+                name,
+            } => {
+                self.expression(object)?;
+                let index = self.name(name.name(), name.span)?;
+                self.emit(OpCode::GetProperty(index), span);
+            }
+            Expr::Grouping {
+                id: _,
+                span: _,
+                expression,
+            } => self.expression(expression)?,
+            Expr::Index {
+                id: _,
+                span: _,
+                object,
+                index,
+            } => {
+                self.expression(object)?;
+                self.expression(index)?;
+                self.emit(OpCode::Index, span);
+            }
+            Expr::IndexSet {
+                id: _,
+                span: _,
+                object,
+                index,
+                value,
+            } => {
+                self.expression(object)?;
+                self.expression(index)?;
+                self.expression(value)?;
+                self.emit(OpCode::IndexSet, span);
+            }
+            Expr::Literal {
+                id: _,
+                span: _,
+                value,
+            } => {
+                let op = match value {
+                    LiteralValue::Null => OpCode::Null,
+                    LiteralValue::True => OpCode::True,
+                    LiteralValue::False => OpCode::False,
+                    value => OpCode::Constant(self.constant(value.clone(), span)?),
+                };
+                self.emit(op, span);
+            }
+            Expr::Logical {
+                id: _,
+                span: _,
+                left,
+                operator,
+                right,
+            } => {
+                self.expression(left)?;
+                let falsy_jump = self.emit_jump(OpCode::JumpIfFalse, span);
+                if operator.token_type == TokenType::Or {
+                    // A truthy left side is the result
+                    let end_jump = self.emit_jump(OpCode::Jump, span);
+                    self.patch_jump(falsy_jump)?;
+                    self.emit(OpCode::Pop, span);
+                    self.expression(right)?;
+                    self.patch_jump(end_jump)?;
+                } else {
+                    // A falsy left side makes the result false, not the left side
+                    self.emit(OpCode::Pop, span);
+                    self.expression(right)?;
+                    let end_jump = self.emit_jump(OpCode::Jump, span);
+                    self.patch_jump(falsy_jump)?;
+                    self.emit(OpCode::Pop, span);
+                    self.emit(OpCode::False, span);
+                    self.patch_jump(end_jump)?;
+                }
+            }
+            Expr::Map {
+                id: _,
+                span: _,
+                entries,
+            } => {
+                for (key, value) in entries {
+                    self.expression(key)?;
+                    self.emit(OpCode::CheckKey, key.get_span());
+                    self.expression(value)?;
+                }
+                let count = to_u16(entries.len(), "map entries", span)?;
+                self.emit(OpCode::Map(count), span);
+            }
+            Expr::Set {
+                id: _,
+                span: _,
+                object,
+                name,
+                value,
+            } => {
+                self.expression(object)?;
+                self.expression(value)?;
+                let index = self.name(name.name(), name.span)?;
+                self.emit(OpCode::SetProperty(index), span);
+            }
+            Expr::This {
+                id: _,
+                span: _,
+                keyword: _,
+            } => self.get_variable("this", span)?,
+            Expr::Super {
+                id: _,
+                span: _,
+                keyword: _,
+                method,
+            } => {
+                self.get_variable("this", span)?;
+                self.get_variable("super", span)?;
+                let index = self.name(method.name(), method.span)?;
+                self.emit(OpCode::GetSuper(index), span);
+            }
+            Expr::Unary {
+                id: _,
+                span: _,
+                operator,
+                right,
+            } => {
+                self.expression(right)?;
+                let op = match operator.token_type {
+                    TokenType::Minus => OpCode::Negate,
+                    TokenType::Bang => OpCode::Not,
+                    ttype => panic!("{} is not a unary operator", ttype),
+                };
+                self.emit(op, span);
+            }
+            Expr::Variable {
+                id: _,
+                span: _,
+                name,
+            } => self.get_variable(name.name(), span)?,
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::Parser;
+    use crate::scanner::Scanner;
+
+    fn compile(source: &str) -> Rc<Function> {
+        let tokens = Scanner::new(source).scan_tokens().unwrap();
+        let stmts = Parser::new(tokens).parse().unwrap();
+        Compiler::compile(&stmts.iter().collect(), &Default::default()).unwrap()
+    }
+
+    #[test]
+    fn operands_over_16_bits_are_compile_errors() {
+        let err = to_u16(70000, "constants", Span::default()).unwrap_err();
+        assert_eq!(err.kind(), "Compile");
+        assert_eq!(err.code(), ErrorCode::LimitExceeded);
+        assert_eq!(err.exit_code(), 65);
+    }
+
+    #[test]
+    fn locals_live_in_slots() {
+        let script = compile("var a = 1; { var b = 2; print a + b; }");
+        assert_eq!(
+            script.chunk.code,
+            vec![
+                OpCode::Constant(0),
+                OpCode::DefineGlobal(0),
+                OpCode::Constant(1),
+                OpCode::GetGlobal(0),
+                OpCode::GetLocal(1),
+                OpCode::Add,
+                OpCode::Print,
+                OpCode::Pop,
+                OpCode::Null,
+                OpCode::Return,
+            ]
+        );
+        assert_eq!(script.chunk.spans.len(), script.chunk.code.len());
+    }
+
+    #[test]
+    fn captured_locals_become_upvalues() {
+        let script = compile("fun outer() { var x = 1; fun inner() { return x; } return inner; }");
+        let outer = &script.chunk.functions[0];
+        let inner = &outer.chunk.functions[0];
+        assert_eq!(
+            inner.upvalues,
+            vec![UpvalueRef {
+                index: 1,
+                is_local: true
+            }]
+        );
+        assert_eq!(inner.chunk.code[0], OpCode::GetUpvalue(0));
+    }
+
+    #[test]
+    fn returned_calls_are_tail_calls() {
+        let script = compile("fun f(n) { return f(n - 1); } fun g(n) { return f(n) + 1; }");
+        let f = &script.chunk.functions[0];
+        let g = &script.chunk.functions[1];
+        assert!(f.chunk.code.contains(&OpCode::TailCall(1)));
+        assert!(!g.chunk.code.contains(&OpCode::TailCall(1)));
+        assert!(g.chunk.code.contains(&OpCode::Call(1)));
+    }
+}
@@ -0,0 +1,287 @@
+use crate::error::ErrorCode;
+use std::cell::RefCell;
+use std::rc::Rc;
+
+// Lines and columns are 1-based, columns count chars and both ends are inclusive
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Span {
+    pub start_line: usize,
+    pub start_column: usize,
+    pub end_line: usize,
+    pub end_column: usize,
+    // The imported file the span is in, see add_source. 0 is the program being run
+    pub file: usize,
+}
+
+thread_local! {
+    // The names and sources of the files imported on this thread, file n is at index n - 1
+    static SOURCES: RefCell<Vec<(String, Rc<str>)>> = const { RefCell::new(vec![]) };
+}
+
+// Keeps the source of an imported file, so that an error raised in it later on, such as in one of
+// its functions, is shown against it. Returns the file its spans are marked with
+pub fn add_source(name: String, source: &str) -> usize {
+    SOURCES.with_borrow_mut(|sources| {
+        sources.push((name, Rc::from(source)));
+        sources.len()
+    })
+}
+
+fn source_of(file: usize) -> Option<(String, Rc<str>)> {
+    SOURCES.with_borrow(|sources| file.checked_sub(1).and_then(|i| sources.get(i).cloned()))
+}
+
+// The name of the file a span is in, for the spans of imported files
+fn file_prefix(span: Span) -> String {
+    match source_of(span.file) {
+        Some((name, _)) => format!("{}, ", name),
+        None => String::new(),
+    }
+}
+
+impl Span {
+    pub fn new(start_line: usize, start_column: usize, end_line: usize, end_column: usize) -> Self {
+        Self {
+            start_line,
+            start_column,
+            end_line,
+            end_column,
+            file: 0,
+        }
+    }
+
+    pub fn in_file(self, file: usize) -> Span {
+        Span { file, ..self }
+    }
+
+    // From the start of self to the end of other
+    pub fn to(&self, other: Span) -> Span {
+        Span {
+            start_line: self.start_line,
+            start_column: self.start_column,
+            end_line: other.end_line,
+            end_column: other.end_column,
+            file: self.file,
+        }
+    }
+}
+
+impl std::fmt::Display for Span {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "line {}, column {}", self.start_line, self.start_column)
+    }
+}
+
+// A call to a Valkyrie function or method, the span is the one of the call expression
+#[derive(Debug, Clone, PartialEq)]
+pub struct CallFrame {
+    pub function: String,
+    pub class: Option<String>,
+    pub call_span: Span,
+}
+
+impl std::fmt::Display for CallFrame {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let file = file_prefix(self.call_span);
+        match &self.class {
+            Some(class) => write!(
+                f,
+                "{}line {}: {}.{}()",
+                file, self.call_span.start_line, class, self.function
+            ),
+            None => write!(
+                f,
+                "{}line {}: {}()",
+                file, self.call_span.start_line, self.function
+            ),
+        }
+    }
+}
+
+// Like Python, only the first few frames of a run of the same call are shown, the rest are counted
+const REPEATED_FRAMES: usize = 3;
+
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub code: ErrorCode,
+    pub message: String,
+    pub span: Span,
+    pub notes: Vec<String>,
+    // Outermost call first, only runtime errors have one. A boxed slice keeps errors small, they
+    // are returned from nearly every function
+    pub trace: Box<[CallFrame]>,
+}
+
+impl Diagnostic {
+    pub fn new(code: ErrorCode, message: String, span: Span) -> Self {
+        Self {
+            code,
+            message,
+            span,
+            notes: vec![],
+            trace: Box::new([]),
+        }
+    }
+
+    // The message followed by the offending source line with the span underlined:
+    //
+    // No field named test on this instance
+    // --> line 5, column 7
+    // 5 | print a.test;
+    //   |       ^^^^^^
+    // = note: ...
+    // Traceback (most recent call last):
+    //   line 9: Bagel.eat()
+    // The span of an error raised in an imported file is shown against that file
+    pub fn render(&self, source: &str) -> String {
+        let imported = source_of(self.span.file);
+        let (file, source) = match &imported {
+            Some((name, text)) => (format!("{}, ", name), &**text),
+            None => (String::new(), source),
+        };
+        let mut result = format!("{}\n--> {}{}", self.message, file, self.span);
+
+        let line = match self.span.start_line {
+            0 => None,
+            n => source.lines().nth(n - 1),
+        };
+        if let Some(line) = line {
+            let number = self.span.start_line.to_string();
+            let gutter = " ".repeat(number.len());
+
+            // Keep the tabs of the source line so that the carets line up with it
+            let indent: String = line
+                .chars()
+                .take(self.span.start_column.saturating_sub(1))
+                .map(|c| if c == '\t' { '\t' } else { ' ' })
+                .collect();
+
+            let line_length = line.chars().count();
+            let end_column = if self.span.end_line == self.span.start_line {
+                self.span.end_column.min(line_length)
+            } else {
+                line_length
+            };
+            let width = (end_column + 1)
+                .saturating_sub(self.span.start_column)
+                .max(1);
+
+            result.push_str(&format!("\n{} | {}", number, line));
+            result.push_str(&format!("\n{} | {}{}", gutter, indent, "^".repeat(width)));
+        }
+
+        for note in &self.notes {
+            result.push_str(&format!("\n= note: {}", note));
+        }
+
+        if !self.trace.is_empty() {
+            result.push_str("\nTraceback (most recent call last):");
+            for run in self.trace.chunk_by(|a, b| a == b) {
+                for frame in run.iter().take(REPEATED_FRAMES) {
+                    result.push_str(&format!("\n  {}", frame));
+                }
+                if run.len() > REPEATED_FRAMES {
+                    result.push_str(&format!(
+                        "\n  [Previous line repeated {} more times]",
+                        run.len() - REPEATED_FRAMES
+                    ));
+                }
+            }
+        }
+
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_caret_under_span() {
+        let source = "var a = 1;\nprint a.test;";
+        let diagnostic = Diagnostic::new(
+            ErrorCode::UndefinedProperty,
+            "No field named test on this instance".to_string(),
+            Span::new(2, 7, 2, 12),
+        );
+
+        assert_eq!(
+            diagnostic.render(source),
+            "No field named test on this instance\n--> line 2, column 7\n2 | print a.test;\n  |       ^^^^^^"
+        );
+    }
+
+    #[test]
+    fn render_runes_by_column() {
+        let source = "𖤍 ᚨ = ᛒ;";
+        let diagnostic = Diagnostic::new(
+            ErrorCode::UndefinedVariable,
+            "Undefined".to_string(),
+            Span::new(1, 7, 1, 7),
+        );
+
+        assert!(diagnostic
+            .render(source)
+            .ends_with("1 | 𖤍 ᚨ = ᛒ;\n  |       ^"));
+    }
+
+    #[test]
+    fn render_without_source_line() {
+        let diagnostic = Diagnostic::new(
+            ErrorCode::ExpectedExpression,
+            "Oops".to_string(),
+            Span::default(),
+        );
+
+        assert_eq!(diagnostic.render(""), "Oops\n--> line 0, column 0");
+    }
+
+    #[test]
+    fn render_traceback_innermost_last() {
+        let source = "fun f() {\n  return 1 + nil;\n}\nf();";
+        let mut diagnostic = Diagnostic::new(
+            ErrorCode::TypeMismatch,
+            "Bad operands".to_string(),
+            Span::new(2, 10, 2, 16),
+        );
+        diagnostic.trace = vec![
+            CallFrame {
+                function: "f".to_string(),
+                class: None,
+                call_span: Span::new(4, 1, 4, 3),
+            },
+            CallFrame {
+                function: "init".to_string(),
+                class: Some("Bagel".to_string()),
+                call_span: Span::new(2, 10, 2, 16),
+            },
+        ]
+        .into();
+
+        assert!(diagnostic.render(source).ends_with(
+            "Traceback (most recent call last):\n  line 4: f()\n  line 2: Bagel.init()"
+        ));
+    }
+
+    #[test]
+    fn render_traceback_collapses_recursion() {
+        let frame = |line| CallFrame {
+            function: "f".to_string(),
+            class: None,
+            call_span: Span::new(line, 1, line, 3),
+        };
+        let mut diagnostic = Diagnostic::new(
+            ErrorCode::StackOverflow,
+            "Stack overflow".to_string(),
+            Span::default(),
+        );
+        diagnostic.trace = std::iter::once(frame(4))
+            .chain(std::iter::repeat_n(frame(2), 10))
+            .collect();
+
+        assert!(diagnostic.render("").ends_with(
+            "Traceback (most recent call last):\n  line 4: f()\n  line 2: f()\n  line 2: f()\n  line 2: f()\n  [Previous line repeated 7 more times]"
+        ));
+    }
+}
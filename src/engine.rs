@@ -0,0 +1,428 @@
+use crate::error::ValkyrieError;
+use crate::expr::{LiteralValue, MAX_DEPTH};
+use crate::interpreter::Interpreter;
+use crate::limits::Limits;
+use crate::natives::{define_native, define_variadic};
+use crate::output::{Capture, Output};
+use crate::parser::Parser;
+use crate::resolver::Resolver;
+use crate::sandbox::Sandbox;
+use crate::scanner::Scanner;
+use crate::vm::Vm;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::rc::Rc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+// Which backend runs the resolved program, the VM is picked with --vm
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Backend {
+    TreeWalker,
+    Vm,
+}
+
+// Set by the flags that go before the other arguments
+#[derive(Clone, Debug)]
+pub struct Options {
+    pub backend: Backend,
+    pub max_depth: usize,
+    // How many bytes of the Rust stack the calls of the tree-walker can use, the VM keeps its
+    // calls on the heap
+    pub max_stack: usize,
+    pub limits: Limits,
+    pub sandbox: Sandbox,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            backend: Backend::TreeWalker,
+            max_depth: MAX_DEPTH,
+            max_stack: THREAD_STACK / 2,
+            limits: Limits::default(),
+            sandbox: Sandbox::default(),
+        }
+    }
+}
+
+// Every call that is in progress uses some of the Rust stack, programs run on a thread with enough
+// of it for the deepest recursion the limit allows
+const STACK_PER_CALL: usize = 256 * 1024;
+const MIN_STACK: usize = 64 * 1024 * 1024;
+// What a thread gets when it does not ask for more, programs embedded on one use half of it
+const THREAD_STACK: usize = 2 * 1024 * 1024;
+
+impl Options {
+    // Takes the flags from the front of args and leaves the rest:
+    //   --vm               runs the program on the bytecode VM
+    //   --max-depth <n>    how deeply calls can nest before a stack overflow error
+    //   --max-steps <n>    how many loop iterations and calls a run can make
+    //   --timeout <ms>     how long a run can take
+    //   --max-size <n>     the most bytes of a string or elements of an array or map
+    //   --sandbox          denies command functions and reading files other than the program
+    //   --allow-command <program>, --allow-dir <dir>
+    //                      sandbox, but allow running <program> or reading the files in <dir>
+    // A flag without its value is an error with its usage
+    pub fn from_flags(args: &mut Vec<String>) -> Result<Self, String> {
+        let mut options = Options::default();
+        loop {
+            match args.first().map(String::as_str) {
+                Some("--vm") => {
+                    args.remove(0);
+                    options.backend = Backend::Vm;
+                }
+                Some("--max-depth") => match args.get(1).and_then(|n| n.parse().ok()) {
+                    Some(max_depth) => {
+                        options.max_depth = max_depth;
+                        args.drain(..2);
+                    }
+                    None => return Err("Usage: --max-depth <number_of_calls>".to_string()),
+                },
+                Some(flag @ ("--max-steps" | "--timeout" | "--max-size")) => {
+                    let n = match args.get(1).and_then(|n| n.parse::<u64>().ok()) {
+                        Some(n) => n,
+                        None => return Err(format!("Usage: {} <number>", flag)),
+                    };
+                    let limits = &mut options.limits;
+                    match flag {
+                        "--max-steps" => limits.max_steps = Some(n),
+                        "--timeout" => limits.timeout = Some(Duration::from_millis(n)),
+                        _ => limits.max_size = Some(n as usize),
+                    }
+                    args.drain(..2);
+                }
+                Some("--sandbox") => {
+                    args.remove(0);
+                    options.strict_sandbox();
+                }
+                Some(flag @ ("--allow-command" | "--allow-dir")) => {
+                    let name = match args.get(1) {
+                        Some(name) => name.clone(),
+                        None => return Err(format!("Usage: {} <name>", flag)),
+                    };
+                    let sandbox = options.strict_sandbox();
+                    if flag == "--allow-command" {
+                        sandbox.allow_command(&name);
+                    } else {
+                        sandbox.allow_dir(&name);
+                    }
+                    args.drain(..2);
+                }
+                _ => return Ok(options),
+            }
+        }
+    }
+
+    // The first of the sandbox flags turns the sandbox on, the others add to its allowlists
+    fn strict_sandbox(&mut self) -> &mut Sandbox {
+        if self.sandbox == Sandbox::default() {
+            self.sandbox = Sandbox::strict();
+        }
+        &mut self.sandbox
+    }
+
+    // The stack a thread running programs with these options needs
+    pub fn stack_size(&self) -> usize {
+        self.max_depth.saturating_mul(STACK_PER_CALL).max(MIN_STACK)
+    }
+
+    // Runs f on a thread with that stack, the options it gets let calls use half of it
+    pub fn spawn<T: Send + 'static>(
+        mut self,
+        f: impl FnOnce(Options) -> T + Send + 'static,
+    ) -> io::Result<JoinHandle<T>> {
+        let stack_size = self.stack_size();
+        self.max_stack = stack_size / 2;
+        thread::Builder::new()
+            .stack_size(stack_size)
+            .spawn(move || f(self))
+    }
+}
+
+enum Runtime {
+    TreeWalker(Interpreter),
+    Vm(Vm),
+}
+
+impl Runtime {
+    fn new(options: &Options) -> Self {
+        match options.backend {
+            Backend::TreeWalker => {
+                let mut interpreter = Interpreter::new();
+                interpreter.set_max_depth(options.max_depth);
+                interpreter.set_max_stack(options.max_stack);
+                interpreter.set_limits(options.limits);
+                interpreter.set_sandbox(options.sandbox.clone());
+                Runtime::TreeWalker(interpreter)
+            }
+            Backend::Vm => {
+                let mut vm = Vm::new();
+                vm.set_max_depth(options.max_depth);
+                vm.set_limits(options.limits);
+                vm.set_sandbox(options.sandbox.clone());
+                Runtime::Vm(vm)
+            }
+        }
+    }
+
+    fn set_main_file(&mut self, path: &Path) {
+        match self {
+            Runtime::TreeWalker(interpreter) => interpreter.set_main_file(path),
+            Runtime::Vm(vm) => vm.set_main_file(path),
+        }
+    }
+
+    fn define(&mut self, name: &str, value: LiteralValue) {
+        match self {
+            Runtime::TreeWalker(interpreter) => interpreter.define_global(name, value),
+            Runtime::Vm(vm) => vm.define_global(name, value),
+        }
+    }
+
+    fn global_scope(&self) -> Rc<RefCell<HashMap<String, LiteralValue>>> {
+        match self {
+            Runtime::TreeWalker(interpreter) => interpreter.environment.global_scope(),
+            Runtime::Vm(vm) => vm.global_scope(),
+        }
+    }
+
+    fn set_output(&mut self, output: impl Output + 'static) {
+        match self {
+            Runtime::TreeWalker(interpreter) => interpreter.set_output(output),
+            Runtime::Vm(vm) => vm.set_output(output),
+        }
+    }
+}
+
+// An interpreter for programs that embed Valkyrie. The globals a run declares are kept for the
+// next one, like the lines of the prompt, and values can be passed in and read back through them
+pub struct Valkyrie {
+    runtime: Runtime,
+}
+
+impl Default for Valkyrie {
+    fn default() -> Self {
+        Self::new(&Options::default())
+    }
+}
+
+impl Valkyrie {
+    pub fn new(options: &Options) -> Self {
+        Self {
+            runtime: Runtime::new(options),
+        }
+    }
+
+    // Scans, parses, resolves and runs the source. The errors can be shown with render_all
+    pub fn eval(&mut self, source: &str) -> Result<(), Vec<ValkyrieError>> {
+        let tokens = Scanner::new(source).scan_tokens()?;
+        let stmts = Parser::new(tokens).parse()?;
+        let locals = Resolver::new()
+            .resolve(&stmts.iter().collect())
+            .map_err(|err| vec![err])?;
+
+        match &mut self.runtime {
+            Runtime::TreeWalker(interpreter) => {
+                interpreter.resolve(locals);
+                interpreter.interpret(stmts.iter().collect())
+            }
+            Runtime::Vm(vm) => vm.interpret(stmts.iter().collect()),
+        }
+        .map_err(|err| vec![err])
+    }
+
+    // Imports in the file are relative to it. The errors are rendered against its source, a file
+    // that cannot be read is a File error
+    pub fn run_file(&mut self, path: &Path) -> Result<(), Vec<ValkyrieError>> {
+        let contents =
+            fs::read_to_string(path).map_err(|err| vec![ValkyrieError::file(path, err)])?;
+        self.runtime.set_main_file(path);
+        self.eval(&contents)
+    }
+
+    // Globals defined from Rust are seen by the programs run here and by the files they import
+    pub fn define(&mut self, name: &str, value: LiteralValue) {
+        self.runtime.define(name, value);
+    }
+
+    fn define_all(&mut self, globals: HashMap<String, LiteralValue>) {
+        for (name, value) in globals {
+            self.define(&name, value);
+        }
+    }
+
+    // A native function the programs can call, checked for arity like the built in ones. Errors
+    // made with natives::native_error are reported at the call
+    pub fn define_native(
+        &mut self,
+        name: &str,
+        arity: usize,
+        fun: impl Fn(&[LiteralValue]) -> Result<LiteralValue, ValkyrieError> + 'static,
+    ) {
+        let mut natives = HashMap::new();
+        define_native(&mut natives, name, arity, fun);
+        self.define_all(natives);
+    }
+
+    // For natives that take at least min_arity arguments
+    pub fn define_variadic(
+        &mut self,
+        name: &str,
+        min_arity: usize,
+        fun: impl Fn(&[LiteralValue]) -> Result<LiteralValue, ValkyrieError> + 'static,
+    ) {
+        let mut natives = HashMap::new();
+        define_variadic(&mut natives, name, min_arity, fun);
+        self.define_all(natives);
+    }
+
+    pub fn get(&self, name: &str) -> Option<LiteralValue> {
+        self.runtime.global_scope().borrow().get(name).cloned()
+    }
+
+    // Where print writes from then on, stdout until it is set
+    pub fn set_output(&mut self, output: impl Output + 'static) {
+        self.runtime.set_output(output);
+    }
+
+    // Keeps what is printed from then on in memory, the capture returned reads it
+    pub fn capture_output(&mut self) -> Capture {
+        let capture = Capture::default();
+        self.set_output(capture.clone());
+        capture
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::ErrorCode;
+    use crate::natives::arguments;
+    use crate::stdlib::to_str;
+
+    fn engines() -> Vec<Valkyrie> {
+        [Backend::TreeWalker, Backend::Vm]
+            .into_iter()
+            .map(|backend| {
+                Valkyrie::new(&Options {
+                    backend,
+                    ..Options::default()
+                })
+            })
+            .collect()
+    }
+
+    #[test]
+    fn globals_are_kept_between_runs() {
+        for mut engine in engines() {
+            let output = engine.capture_output();
+            engine.define("base", LiteralValue::Number(40.0));
+            engine.eval("var answer = base + 2;").unwrap();
+            engine.eval("print answer;").unwrap();
+            assert_eq!(engine.get("answer"), Some(LiteralValue::Number(42.0)));
+            assert_eq!(output.take(), "42\n");
+            assert_eq!(output.take(), "");
+        }
+    }
+
+    #[test]
+    fn output_can_be_a_closure() {
+        for mut engine in engines() {
+            let lines = Rc::new(RefCell::new(vec![]));
+            let printed = lines.clone();
+            engine.set_output(move |line: &str| printed.borrow_mut().push(line.to_string()));
+            engine
+                .eval("fun greet(name) { print \"hail \" + name; } greet(\"Odin\"); print [1, 2];")
+                .unwrap();
+            assert_eq!(*lines.borrow(), vec!["\"hail Odin\"", "[1, 2]"]);
+        }
+    }
+
+    #[test]
+    fn natives_can_be_closures() {
+        for mut engine in engines() {
+            let calls = Rc::new(RefCell::new(vec![]));
+            let seen = calls.clone();
+            engine.define_native("record", 1, move |args| {
+                let [value] = arguments("record", args)?;
+                seen.borrow_mut().push(value.clone());
+                Ok(LiteralValue::Null)
+            });
+            engine.eval("record(1); record(\"two\");").unwrap();
+            assert_eq!(
+                *calls.borrow(),
+                vec![
+                    LiteralValue::Number(1.0),
+                    LiteralValue::StringValue("two".to_string())
+                ]
+            );
+
+            let errs = engine.eval("record();").unwrap_err();
+            assert_eq!(errs[0].code(), ErrorCode::ArityMismatch);
+        }
+    }
+
+    #[test]
+    fn rust_globals_are_seen_by_imports() {
+        for mut engine in engines() {
+            let output = engine.capture_output();
+            engine.define("name", LiteralValue::StringValue("Freya".to_string()));
+            engine.define_native("greet", 1, |args| {
+                let [name] = arguments("greet", args)?;
+                Ok(LiteralValue::StringValue(format!("hail {}", to_str(name))))
+            });
+            engine
+                .eval("import m from \"src/tests/modules/embedded\"; print m.greeting;")
+                .unwrap();
+            assert_eq!(output.take(), "\"hail Freya\"\n");
+
+            // They are not exported again by the file
+            let errs = engine.eval("print m.greet;").unwrap_err();
+            assert_eq!(errs[0].code(), ErrorCode::UndefinedProperty);
+        }
+    }
+
+    #[test]
+    fn unreadable_files_are_errors() {
+        for mut engine in engines() {
+            let errs = engine
+                .run_file(Path::new("src/tests/modules/missing.valkyrie"))
+                .unwrap_err();
+            assert_eq!(errs[0].code(), ErrorCode::ReadFailed);
+            assert_eq!(errs[0].exit_code(), 74);
+        }
+    }
+
+    #[test]
+    fn deep_recursion_fits_the_stack_of_a_thread() {
+        // Threads start with 2 MB of stack unless they ask for more
+        let codes = std::thread::spawn(|| {
+            engines()
+                .into_iter()
+                .map(|mut engine| {
+                    let errs = engine
+                        .eval("fun f(n) { return 1 + f(n); } f(0);")
+                        .unwrap_err();
+                    errs[0].code()
+                })
+                .collect::<Vec<_>>()
+        })
+        .join()
+        .unwrap();
+        assert_eq!(codes, vec![ErrorCode::StackOverflow; 2]);
+    }
+
+    #[test]
+    fn errors_are_returned() {
+        for mut engine in engines() {
+            let errs = engine.eval("print 1 +;").unwrap_err();
+            assert_eq!(errs[0].exit_code(), 65);
+            let errs = engine.eval("print \"a\" - 1;").unwrap_err();
+            assert_eq!(errs[0].exit_code(), 70);
+        }
+    }
+}
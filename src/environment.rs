@@ -1,148 +1,243 @@
-use crate::expr::{CallableImpl, LiteralValue, NativeFunctionImpl};
-use std::cell::RefCell;
+use crate::diagnostic::{CallFrame, Span};
+use crate::error::ValkyrieError;
+use crate::expr::{stack_exhausted, stack_overflow, LiteralValue, MAX_DEPTH};
+use crate::limits::{Budget, Limits};
+use crate::natives::get_globals;
+use crate::output::{stdout, Output, SharedOutput};
+use crate::sandbox::Sandbox;
+use std::cell::{Cell, RefCell};
 use std::collections::HashMap;
 use std::rc::Rc;
 
+// The global scope is keyed by name, every other scope is a vector indexed by the slots the
+// resolver assigned, so a local is found by walking up a number of scopes and indexing
 #[derive(Clone)]
 pub struct Environment {
-    pub values: Rc<RefCell<HashMap<String, LiteralValue>>>,
-    locals: Rc<RefCell<HashMap<usize, usize>>>,
-    pub enclosing: Option<Box<Environment>>,
-}
-
-fn clock_impl(_args: &Vec<LiteralValue>) -> LiteralValue {
-    let now = std::time::SystemTime::now()
-        .duration_since(std::time::SystemTime::UNIX_EPOCH)
-        .expect("Could not get system time")
-        .as_millis();
-
-    LiteralValue::Number(now as f64 / 1000.0)
-}
-
-fn get_globals() -> Rc<RefCell<HashMap<String, LiteralValue>>> {
-    let mut env = HashMap::new();
-    let fun_impl = NativeFunctionImpl {
-        name: "clock".to_string(),
-        arity: 0,
-        fun: Rc::new(clock_impl),
-    };
-    let callable_impl = CallableImpl::NativeFunction(fun_impl);
-    env.insert("clock".to_string(), LiteralValue::Callable(callable_impl));
-
-    Rc::new(RefCell::new(env))
+    pub values: Rc<RefCell<Vec<LiteralValue>>>,
+    globals: Rc<RefCell<HashMap<String, LiteralValue>>>,
+    // Expression id -> (depth, slot), shared by every environment of an interpreter
+    locals: Rc<RefCell<HashMap<usize, (usize, usize)>>>,
+    // Shared by every environment of an interpreter, like the locals
+    call_stack: Rc<RefCell<Vec<CallFrame>>>,
+    max_depth: Rc<Cell<usize>>,
+    // Where the stack was when the run started, calls fail before they use more than max_stack
+    // bytes below it
+    stack_base: Rc<Cell<usize>>,
+    max_stack: Rc<Cell<usize>>,
+    sandbox: Rc<RefCell<Sandbox>>,
+    budget: Rc<Budget>,
+    output: SharedOutput,
+    pub enclosing: Option<Rc<Environment>>,
 }
 
 impl Environment {
-    pub fn new(locals: HashMap<usize, usize>) -> Self {
+    pub fn new(locals: HashMap<usize, (usize, usize)>) -> Self {
         Self {
-            values: get_globals(),
+            values: Rc::new(RefCell::new(vec![])),
+            globals: get_globals(),
             locals: Rc::new(RefCell::new(locals)),
+            call_stack: Rc::new(RefCell::new(vec![])),
+            max_depth: Rc::new(Cell::new(MAX_DEPTH)),
+            stack_base: Rc::new(Cell::new(stack_address())),
+            max_stack: Rc::new(Cell::new(usize::MAX)),
+            sandbox: Rc::new(RefCell::new(Sandbox::default())),
+            budget: Rc::new(Budget::default()),
+            output: stdout(),
             enclosing: None,
         }
     }
 
-    pub fn resolve(&self, locals: HashMap<usize, usize>) {
+    pub fn resolve(&self, locals: HashMap<usize, (usize, usize)>) {
         // self.locals = locals --! Bad because it wont update enclosing
         for (key, val) in locals.iter() {
             self.locals.borrow_mut().insert(*key, *val);
         }
     }
 
+    // The global scope of an imported file, calls into it still count towards the depth limit
+    pub fn for_module(&self, globals: Rc<RefCell<HashMap<String, LiteralValue>>>) -> Environment {
+        Self {
+            values: Rc::new(RefCell::new(vec![])),
+            globals,
+            locals: Rc::new(RefCell::new(HashMap::new())),
+            call_stack: self.call_stack.clone(),
+            max_depth: self.max_depth.clone(),
+            stack_base: self.stack_base.clone(),
+            max_stack: self.max_stack.clone(),
+            sandbox: self.sandbox.clone(),
+            budget: self.budget.clone(),
+            output: self.output.clone(),
+            enclosing: None,
+        }
+    }
+
+    pub fn globals(&self) -> HashMap<String, LiteralValue> {
+        self.globals.borrow().clone()
+    }
+
+    // The globals themselves rather than a copy, for values defined from outside of the program
+    pub fn global_scope(&self) -> Rc<RefCell<HashMap<String, LiteralValue>>> {
+        self.globals.clone()
+    }
+
     pub fn enclose(&self) -> Environment {
         Self {
-            values: Rc::new(RefCell::new(HashMap::new())),
+            values: Rc::new(RefCell::new(vec![])),
+            globals: self.globals.clone(),
             locals: self.locals.clone(),
-            enclosing: Some(Box::new(self.clone())),
+            call_stack: self.call_stack.clone(),
+            max_depth: self.max_depth.clone(),
+            stack_base: self.stack_base.clone(),
+            max_stack: self.max_stack.clone(),
+            sandbox: self.sandbox.clone(),
+            budget: self.budget.clone(),
+            output: self.output.clone(),
+            enclosing: Some(Rc::new(self.clone())),
         }
     }
 
-    pub fn define(&self, name: String, value: LiteralValue) {
-        self.values.borrow_mut().insert(name, value);
+    // Variables of a local scope must be defined in the order the resolver declared them, as the
+    // name is only kept for the globals
+    pub fn define(&self, name: &str, value: LiteralValue) {
+        match self.enclosing {
+            None => {
+                self.globals.borrow_mut().insert(name.to_string(), value);
+            }
+            Some(_) => self.values.borrow_mut().push(value),
+        }
+    }
+
+    pub fn push_frame(&self, frame: CallFrame) {
+        self.call_stack.borrow_mut().push(frame);
+    }
+
+    pub fn pop_frame(&self) {
+        self.call_stack.borrow_mut().pop();
+    }
+
+    pub fn call_stack(&self) -> Vec<CallFrame> {
+        self.call_stack.borrow().clone()
+    }
+
+    pub fn set_max_depth(&self, max_depth: usize) {
+        self.max_depth.set(max_depth);
+    }
+
+    pub fn set_sandbox(&self, sandbox: Sandbox) {
+        *self.sandbox.borrow_mut() = sandbox;
+    }
+
+    pub fn sandbox(&self) -> Rc<RefCell<Sandbox>> {
+        self.sandbox.clone()
+    }
+
+    pub fn set_limits(&self, limits: Limits) {
+        self.budget.set_limits(limits);
+    }
+
+    pub fn budget(&self) -> &Budget {
+        &self.budget
+    }
+
+    pub fn set_output(&self, output: impl Output + 'static) {
+        *self.output.borrow_mut() = Box::new(output);
+    }
+
+    pub fn output(&self) -> SharedOutput {
+        self.output.clone()
+    }
+
+    pub fn set_max_stack(&self, max_stack: usize) {
+        self.max_stack.set(max_stack);
+    }
+
+    // Called when a run starts, the stack it is on may be deeper than the one of the last run
+    pub fn mark_stack(&self) {
+        self.stack_base.set(stack_address());
+    }
+
+    // Fails when one more call would nest deeper than the limit, or when the calls already use
+    // all the stack they can
+    pub fn check_depth(&self, call_span: Span) -> Result<(), ValkyrieError> {
+        let depth = self.call_stack.borrow().len();
+        if depth >= self.max_depth.get() {
+            return Err(
+                stack_overflow(self.max_depth.get(), call_span).with_trace(self.call_stack())
+            );
+        }
+        if self.stack_base.get().abs_diff(stack_address()) > self.max_stack.get() {
+            return Err(stack_exhausted(depth, call_span).with_trace(self.call_stack()));
+        }
+        Ok(())
     }
 
     pub fn get(&self, name: &str, expr_id: usize) -> Option<LiteralValue> {
-        let distance = self.locals.borrow().get(&expr_id).cloned();
-        self.get_internal(name, distance)
+        let address = self.locals.borrow().get(&expr_id).cloned();
+        match address {
+            Some((depth, slot)) => self.ancestor(depth).values.borrow().get(slot).cloned(),
+            None => self.globals.borrow().get(name).cloned(),
+        }
     }
 
     pub fn get_this_instance(&self, super_id: usize) -> Option<LiteralValue> {
-        let distance = self
+        let (depth, _) = self
             .locals
             .borrow()
             .get(&super_id)
             .cloned()
             .expect("Could not find 'this' even though 'super' was defined");
-        self.get_internal("this", Some(distance - 1))
+        // this is the only variable of the scope right inside the one of super
+        self.ancestor(depth - 1).values.borrow().first().cloned()
     }
 
     pub fn get_distance(&self, expr_id: usize) -> Option<usize> {
-        self.locals.borrow().get(&expr_id).cloned()
+        self.locals.borrow().get(&expr_id).map(|(depth, _)| *depth)
     }
 
-    fn get_internal(&self, name: &str, distance: Option<usize>) -> Option<LiteralValue> {
-        if let None = distance {
-            match &self.enclosing {
-                None => self.values.borrow().get(name).cloned(),
-                Some(env) => env.get_internal(name, distance),
-            }
-        } else {
-            let distance = distance.unwrap();
-            if distance == 0 {
-                self.values.borrow().get(name).cloned()
-            } else {
-                match &self.enclosing {
-                    None => panic!("Tried to resolve a variable that was defined deeper than the current environment depth"),
-                    Some(env) => {
-                        assert!(distance > 0);
-                        env.get_internal(name, Some(distance - 1))
-                    }
-                }
-            }
+    fn ancestor(&self, depth: usize) -> &Environment {
+        let mut environment = self;
+        for _ in 0..depth {
+            environment = match &environment.enclosing {
+                Some(env) => env,
+                None => panic!("Tried to resolve a variable that was defined deeper than the current environment depth"),
+            };
         }
+        environment
     }
 
     pub fn assign_global(&self, name: &str, value: LiteralValue) -> bool {
-        self.assign_internal(name, value, None)
+        match self.globals.borrow_mut().get_mut(name) {
+            Some(slot) => {
+                *slot = value;
+                true
+            }
+            None => false,
+        }
     }
 
     pub fn assign(&self, name: &str, value: LiteralValue, expr_id: usize) -> bool {
         // ! Important that this ID matches with the resolver
-        let distance = self.locals.borrow().get(&expr_id).cloned();
-        self.assign_internal(name, value, distance)
-    }
-
-    fn assign_internal(&self, name: &str, value: LiteralValue, distance: Option<usize>) -> bool {
-        if let None = distance {
-            match &self.enclosing {
-                Some(env) => env.assign_internal(name, value, distance),
-                None => match self.values.borrow_mut().insert(name.to_string(), value) {
-                    Some(_) => true,
-                    None => false,
-                },
-            }
-        } else {
-            let distance = distance.unwrap();
-            if distance == 0 {
-                self.values.borrow_mut().insert(name.to_string(), value);
-                true
-            } else {
-                match &self.enclosing {
-                    None => panic!("Tried to define a variable in a too deep level"),
-                    Some(env) => env.assign_internal(name, value, Some(distance - 1)),
-                };
-                true
-            }
+        let address = self.locals.borrow().get(&expr_id).cloned();
+        match address {
+            Some((depth, slot)) => match self.ancestor(depth).values.borrow_mut().get_mut(slot) {
+                Some(variable) => {
+                    *variable = value;
+                    true
+                }
+                None => false,
+            },
+            None => self.assign_global(name, value),
         }
     }
 
     #[allow(dead_code)]
     pub fn dump(&self, indent: usize) -> String {
         let mut result = String::new();
-        for (key, val) in self.values.borrow().iter() {
+        for (slot, val) in self.values.borrow().iter().enumerate() {
             for _ in 0..indent {
                 result.push_str(" ");
             }
-            result.push_str(&format!("{}: {:?}\n", key, val));
+            result.push_str(&format!("{}: {:?}\n", slot, val));
         }
         if let Some(env) = &self.enclosing {
             result.push_str(&env.dump(indent + 2));
@@ -152,6 +247,14 @@ impl Environment {
     }
 }
 
+// Where the stack is at the time of the call, how far two of these are apart is how much stack the
+// calls between them use
+#[inline(never)]
+fn stack_address() -> usize {
+    let marker = 0u8;
+    std::hint::black_box(&marker) as *const u8 as usize
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -159,4 +262,24 @@ mod tests {
     fn try_init() {
         let _environment = Environment::new(HashMap::new());
     }
+
+    #[test]
+    fn locals_by_depth_and_slot() {
+        // { var a = 1; var b = 2; { var c = 3; a; c; b = 5; } }
+        let locals = HashMap::from([(10, (1, 0)), (11, (0, 0)), (12, (1, 1)), (13, (0, 1))]);
+        let globals = Environment::new(locals);
+        let outer = globals.enclose();
+        outer.define("a", LiteralValue::Number(1.0));
+        outer.define("b", LiteralValue::Number(2.0));
+        let inner = outer.enclose();
+        inner.define("c", LiteralValue::Number(3.0));
+
+        assert_eq!(inner.get("a", 10), Some(LiteralValue::Number(1.0)));
+        assert_eq!(inner.get("c", 11), Some(LiteralValue::Number(3.0)));
+        assert!(inner.assign("b", LiteralValue::Number(5.0), 12));
+        assert_eq!(outer.get("b", 13), Some(LiteralValue::Number(5.0)));
+        // Without an address the name is looked up in the globals
+        assert_eq!(inner.get("a", 99), None);
+        assert!(inner.get("clock", 99).is_some());
+    }
 }
@@ -0,0 +1,323 @@
+use crate::diagnostic::{CallFrame, Diagnostic, Span};
+use crate::expr::LiteralValue;
+use std::path::Path;
+
+// Every error the interpreter can report has a stable code so that embedders and tests can
+// branch on it instead of matching on the message
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    // Files
+    ReadFailed,
+
+    // Scanner
+    UnexpectedCharacter,
+    InvalidNumber,
+    UnterminatedString,
+
+    // Parser
+    ExpectedToken,
+    ExpectedExpression,
+    InvalidAssignmentTarget,
+    TooManyArguments,
+    InvalidCommand,
+
+    // Resolver
+    AlreadyDeclared,
+    ReadInOwnInitializer,
+    ReturnOutsideFunction,
+    ThisOutsideClass,
+    SuperOutsideClass,
+    NoSuperclass,
+    InheritFromSelf,
+    OutsideLoop,
+    ImportOutsideTopLevel,
+
+    // Compiler
+    LimitExceeded,
+
+    // Interpreter
+    UndefinedVariable,
+    UndefinedProperty,
+    NotCallable,
+    ArityMismatch,
+    TypeMismatch,
+    InvalidOperator,
+    InvalidSuperclass,
+    IndexOutOfRange,
+    MissingKey,
+    StackOverflow,
+    Uncaught,
+    ImportFailed,
+    CircularImport,
+    InvalidArgument,
+    CommandFailed,
+    SandboxDenied,
+    StepLimitExceeded,
+    TimeLimitExceeded,
+    SizeLimitExceeded,
+}
+
+impl ErrorCode {
+    pub fn as_str(&self) -> &'static str {
+        use ErrorCode::*;
+        match self {
+            ReadFailed => "F001",
+
+            UnexpectedCharacter => "L001",
+            InvalidNumber => "L002",
+            UnterminatedString => "L003",
+
+            ExpectedToken => "P001",
+            ExpectedExpression => "P002",
+            InvalidAssignmentTarget => "P003",
+            TooManyArguments => "P004",
+            InvalidCommand => "P005",
+
+            AlreadyDeclared => "R001",
+            ReadInOwnInitializer => "R002",
+            ReturnOutsideFunction => "R003",
+            ThisOutsideClass => "R004",
+            SuperOutsideClass => "R005",
+            NoSuperclass => "R006",
+            InheritFromSelf => "R007",
+            OutsideLoop => "R008",
+            ImportOutsideTopLevel => "R009",
+
+            LimitExceeded => "C001",
+
+            UndefinedVariable => "E001",
+            UndefinedProperty => "E002",
+            NotCallable => "E003",
+            ArityMismatch => "E004",
+            TypeMismatch => "E005",
+            InvalidOperator => "E006",
+            InvalidSuperclass => "E007",
+            IndexOutOfRange => "E008",
+            MissingKey => "E009",
+            StackOverflow => "E010",
+            Uncaught => "E011",
+            ImportFailed => "E012",
+            CircularImport => "E013",
+            InvalidArgument => "E014",
+            CommandFailed => "E015",
+            SandboxDenied => "E016",
+            StepLimitExceeded => "E017",
+            TimeLimitExceeded => "E018",
+            SizeLimitExceeded => "E019",
+        }
+    }
+}
+
+impl ErrorCode {
+    // Going over an execution limit stops the whole program
+    pub fn is_limit(&self) -> bool {
+        matches!(
+            self,
+            ErrorCode::StepLimitExceeded
+                | ErrorCode::TimeLimitExceeded
+                | ErrorCode::SizeLimitExceeded
+        )
+    }
+
+    // So does a denial of the sandbox, a script that caught it could keep probing the policy
+    pub fn is_fatal(&self) -> bool {
+        self.is_limit() || *self == ErrorCode::SandboxDenied
+    }
+
+    // The type of the error value a script catches
+    pub fn name(&self) -> String {
+        format!("{:?}", self)
+    }
+}
+
+impl std::fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum ValkyrieError {
+    // A file to run that could not be read, it has no source to point into
+    File(Diagnostic),
+    Lex(Diagnostic),
+    Parse(Diagnostic),
+    Resolve(Diagnostic),
+    // A program the bytecode compiler cannot encode
+    Compile(Diagnostic),
+    Runtime(Diagnostic),
+    // A value thrown by the script, the diagnostic is reported if nothing catches it
+    Throw(Diagnostic, Box<LiteralValue>),
+}
+
+impl ValkyrieError {
+    pub fn file(path: &Path, err: std::io::Error) -> Self {
+        ValkyrieError::File(Diagnostic::new(
+            ErrorCode::ReadFailed,
+            format!("Could not read {}: {}", path.display(), err),
+            Span::default(),
+        ))
+    }
+
+    pub fn lex(code: ErrorCode, message: String, span: Span) -> Self {
+        ValkyrieError::Lex(Diagnostic::new(code, message, span))
+    }
+
+    pub fn parse(code: ErrorCode, message: String, span: Span) -> Self {
+        ValkyrieError::Parse(Diagnostic::new(code, message, span))
+    }
+
+    pub fn resolve(code: ErrorCode, message: String, span: Span) -> Self {
+        ValkyrieError::Resolve(Diagnostic::new(code, message, span))
+    }
+
+    pub fn compile(code: ErrorCode, message: String, span: Span) -> Self {
+        ValkyrieError::Compile(Diagnostic::new(code, message, span))
+    }
+
+    pub fn runtime(code: ErrorCode, message: String, span: Span) -> Self {
+        ValkyrieError::Runtime(Diagnostic::new(code, message, span))
+    }
+
+    pub fn diagnostic(&self) -> &Diagnostic {
+        match self {
+            ValkyrieError::File(diagnostic)
+            | ValkyrieError::Lex(diagnostic)
+            | ValkyrieError::Parse(diagnostic)
+            | ValkyrieError::Resolve(diagnostic)
+            | ValkyrieError::Compile(diagnostic)
+            | ValkyrieError::Runtime(diagnostic)
+            | ValkyrieError::Throw(diagnostic, _) => diagnostic,
+        }
+    }
+
+    fn diagnostic_mut(&mut self) -> &mut Diagnostic {
+        match self {
+            ValkyrieError::File(diagnostic)
+            | ValkyrieError::Lex(diagnostic)
+            | ValkyrieError::Parse(diagnostic)
+            | ValkyrieError::Resolve(diagnostic)
+            | ValkyrieError::Compile(diagnostic)
+            | ValkyrieError::Runtime(diagnostic)
+            | ValkyrieError::Throw(diagnostic, _) => diagnostic,
+        }
+    }
+
+    // Runtime errors can be caught by a try statement, except the fatal ones which have to stop
+    // the program
+    pub fn is_catchable(&self) -> bool {
+        match self {
+            ValkyrieError::Runtime(diagnostic) => !diagnostic.code.is_fatal(),
+            ValkyrieError::Throw(..) => true,
+            _ => false,
+        }
+    }
+
+    pub fn code(&self) -> ErrorCode {
+        self.diagnostic().code
+    }
+
+    pub fn span(&self) -> Span {
+        self.diagnostic().span
+    }
+
+    pub fn message(&self) -> &str {
+        &self.diagnostic().message
+    }
+
+    pub fn with_note(mut self, note: String) -> Self {
+        self.diagnostic_mut().notes.push(note);
+        self
+    }
+
+    pub fn trace(&self) -> &[CallFrame] {
+        &self.diagnostic().trace
+    }
+
+    // For the errors of natives, which do not know where they were called from
+    pub fn with_span(mut self, span: Span) -> Self {
+        self.diagnostic_mut().span = span;
+        self
+    }
+
+    pub fn with_trace(mut self, trace: Vec<CallFrame>) -> Self {
+        self.diagnostic_mut().trace = trace.into_boxed_slice();
+        self
+    }
+
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ValkyrieError::File(_) => "File",
+            ValkyrieError::Lex(_) => "Syntax",
+            ValkyrieError::Parse(_) => "Syntax",
+            ValkyrieError::Resolve(_) => "Resolve",
+            ValkyrieError::Compile(_) => "Compile",
+            ValkyrieError::Runtime(_) | ValkyrieError::Throw(..) => "Runtime",
+        }
+    }
+
+    // Same convention as clox: 65 for errors in the source, 70 for errors while running it and 74
+    // for a file that cannot be read
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            ValkyrieError::File(_) => 74,
+            ValkyrieError::Runtime(_) | ValkyrieError::Throw(..) => 70,
+            _ => 65,
+        }
+    }
+
+    pub fn render(&self, source: &str) -> String {
+        if let ValkyrieError::File(diagnostic) = self {
+            return format!("File error [{}]: {}", self.code(), diagnostic.message);
+        }
+        format!(
+            "{} error [{}]: {}",
+            self.kind(),
+            self.code(),
+            self.diagnostic().render(source)
+        )
+    }
+}
+
+pub fn render_all(errors: &[ValkyrieError], source: &str) -> String {
+    errors
+        .iter()
+        .map(|error| error.render(source))
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_with_kind_code_and_notes() {
+        let source = "print a;";
+        let error = ValkyrieError::runtime(
+            ErrorCode::UndefinedVariable,
+            "Variable a has not been declared".to_string(),
+            Span::new(1, 7, 1, 7),
+        )
+        .with_note("Declare it first with 'var a;'".to_string());
+
+        assert_eq!(
+            error.render(source),
+            "Runtime error [E001]: Variable a has not been declared\n--> line 1, column 7\n1 | print a;\n  |       ^\n= note: Declare it first with 'var a;'"
+        );
+        assert_eq!(error.exit_code(), 70);
+    }
+
+    #[test]
+    fn syntax_errors_exit_with_65() {
+        let error = ValkyrieError::parse(
+            ErrorCode::ExpectedExpression,
+            "Expected expression".to_string(),
+            Span::default(),
+        );
+
+        assert_eq!(error.kind(), "Syntax");
+        assert_eq!(error.code().as_str(), "P002");
+        assert_eq!(error.exit_code(), 65);
+    }
+}
@@ -1,7 +1,13 @@
+use crate::chunk::Function;
+use crate::diagnostic::{CallFrame, Diagnostic, Span};
 use crate::environment::Environment;
-use crate::interpreter::Interpreter;
+use crate::error::{ErrorCode, ValkyrieError};
+use crate::interpreter::{ControlFlow, Interpreter};
+use crate::limits::Budget;
+use crate::module::Module;
 use crate::scanner;
 use crate::scanner::{Token, TokenType};
+use crate::vm;
 use std::cell::RefCell;
 use std::cmp::{Eq, PartialEq};
 use std::collections::HashMap;
@@ -12,6 +18,9 @@ use std::rc::Rc;
 pub enum CallableImpl {
     LoxFunction(LoxFunctionImpl),
     NativeFunction(NativeFunctionImpl),
+    // Functions of the bytecode VM
+    Closure(Rc<vm::Closure>),
+    BoundMethod(Rc<vm::BoundMethod>),
 }
 use CallableImpl::*;
 
@@ -20,15 +29,29 @@ pub struct LoxFunctionImpl {
     pub name: String,
     pub arity: usize,
     pub parent_env: Environment,
-    pub params: Vec<Token>,
-    pub body: Vec<Box<Stmt>>,
+    pub params: Rc<[Token]>,
+    pub body: Rc<[Stmt]>,
+    // The class a method was declared in, used for tracebacks
+    pub class_name: Option<String>,
 }
 
+pub type NativeFn = dyn Fn(&[LiteralValue]) -> Result<LiteralValue, ValkyrieError>;
+
 #[derive(Clone)]
 pub struct NativeFunctionImpl {
     pub name: String,
     pub arity: usize,
-    pub fun: Rc<dyn Fn(&Vec<LiteralValue>) -> LiteralValue>,
+    // Takes arity or more arguments
+    pub variadic: bool,
+    pub fun: Rc<NativeFn>,
+}
+
+// What a catch clause gets for an error raised by the interpreter, or what the Error native makes
+pub struct ErrorValue {
+    pub message: String,
+    pub error_type: String,
+    // Throwing a caught runtime error again raises the original error
+    pub error: Option<ValkyrieError>,
 }
 
 #[derive(Clone)]
@@ -49,6 +72,14 @@ pub enum LiteralValue {
         class: Box<LiteralValue>,
         fields: Rc<RefCell<Vec<(String, LiteralValue)>>>,
     },
+    // Shared, so that every copy of an array sees the same elements
+    Array(Rc<RefCell<Vec<LiteralValue>>>),
+    // Entries are kept in insertion order, keys are compared with ==
+    Map(Rc<RefCell<Vec<(LiteralValue, LiteralValue)>>>),
+    // Classes of the bytecode VM, their instances are LoxInstances too
+    VmClass(Rc<vm::Class>),
+    Error(Rc<ErrorValue>),
+    Module(Rc<Module>),
 }
 use LiteralValue::*;
 
@@ -60,6 +91,14 @@ impl std::fmt::Debug for LiteralValue {
 
 impl PartialEq for LiteralValue {
     fn eq(&self, other: &Self) -> bool {
+        self.eq_within(other, &mut vec![])
+    }
+}
+
+impl LiteralValue {
+    // `within` holds the pairs of arrays and maps being compared, a pair met again inside itself
+    // is taken as equal so that collections containing themselves can be compared
+    fn eq_within(&self, other: &Self, within: &mut Vec<(*const (), *const ())>) -> bool {
         match (self, other) {
             (Number(x), Number(y)) => x == y,
             (
@@ -78,7 +117,45 @@ impl PartialEq for LiteralValue {
                     ..
                 })),
             ) => name == name2 && arity == arity2,
+            (Callable(x), Callable(y)) => match (x.compiled(), y.compiled()) {
+                (Some(f), Some(g)) => f.name == g.name && f.arity == g.arity,
+                _ => false,
+            },
             (StringValue(x), StringValue(y)) => x == y,
+            (Array(x), Array(y)) => {
+                let pair = (Rc::as_ptr(x) as *const (), Rc::as_ptr(y) as *const ());
+                if Rc::ptr_eq(x, y) || within.contains(&pair) {
+                    return true;
+                }
+                within.push(pair);
+                let (xs, ys) = (x.borrow(), y.borrow());
+                let equal = xs.len() == ys.len()
+                    && xs
+                        .iter()
+                        .zip(ys.iter())
+                        .all(|(x, y)| x.eq_within(y, within));
+                within.pop();
+                equal
+            }
+            (Map(x), Map(y)) => {
+                let pair = (Rc::as_ptr(x) as *const (), Rc::as_ptr(y) as *const ());
+                if Rc::ptr_eq(x, y) || within.contains(&pair) {
+                    return true;
+                }
+                within.push(pair);
+                // Equal when they have the same entries, whatever the order
+                let (xs, ys) = (x.borrow(), y.borrow());
+                let equal = xs.len() == ys.len()
+                    && xs.iter().all(|(key, value)| {
+                        map_get(&ys, key).is_some_and(|other| value.eq_within(&other, within))
+                    });
+                within.pop();
+                equal
+            }
+            (Error(x), Error(y)) => {
+                Rc::ptr_eq(x, y) || x.message == y.message && x.error_type == y.error_type
+            }
+            (Module(x), Module(y)) => Rc::ptr_eq(x, y),
             (True, True) => true,
             (False, False) => true,
             (Null, Null) => true,
@@ -103,21 +180,37 @@ fn unwrap_as_string(literal: Option<scanner::LiteralValue>) -> String {
 
 macro_rules! class_name {
     ($class:expr) => {{
-        if let LiteralValue::LoxClass {
-            name,
-            methods: _,
-            superclass: _,
-        } = &**$class
-        {
-            name
-        } else {
-            panic!("Unreachable")
+        match &**$class {
+            LiteralValue::LoxClass {
+                name,
+                methods: _,
+                superclass: _,
+            } => name,
+            LiteralValue::VmClass(class) => &class.name,
+            _ => panic!("Unreachable"),
         }
     }};
 }
 
+impl CallableImpl {
+    // The compiled function behind a callable of the bytecode VM
+    pub fn compiled(&self) -> Option<&Function> {
+        match self {
+            Closure(closure) => Some(&closure.function),
+            BoundMethod(bound) => Some(&bound.method.function),
+            _ => None,
+        }
+    }
+}
+
 impl LiteralValue {
     pub fn to_string(&self) -> String {
+        self.to_string_within(&mut vec![])
+    }
+
+    // `within` holds the arrays and maps being printed, one that contains itself is printed as
+    // [...] or {...} where it appears inside itself
+    fn to_string_within(&self, within: &mut Vec<*const ()>) -> String {
         match self {
             LiteralValue::Number(x) => x.to_string(),
             LiteralValue::StringValue(x) => format!("\"{}\"", x),
@@ -129,19 +222,63 @@ impl LiteralValue {
                 arity,
                 ..
             })) => format!("{name}/{arity}"),
+            LiteralValue::Callable(CallableImpl::NativeFunction(NativeFunctionImpl {
+                name,
+                arity,
+                variadic: true,
+                ..
+            })) => format!("{name}/{arity}+"),
             LiteralValue::Callable(CallableImpl::NativeFunction(NativeFunctionImpl {
                 name,
                 arity,
                 ..
             })) => format!("{name}/{arity}"),
+            LiteralValue::Callable(callable) => {
+                let function = callable.compiled().unwrap();
+                format!("{}/{}", function.name, function.arity)
+            }
             LiteralValue::LoxClass {
                 name,
                 methods: _,
                 superclass: _,
             } => format!("Class '{name}'"),
+            LiteralValue::VmClass(class) => format!("Class '{}'", class.name),
             LiteralValue::LoxInstance { class, fields: _ } => {
                 format!("Instance of '{}'", class_name!(class))
             }
+            LiteralValue::Array(xs) => {
+                let ptr = Rc::as_ptr(xs) as *const ();
+                if within.contains(&ptr) {
+                    return "[...]".to_string();
+                }
+                within.push(ptr);
+                let elements: Vec<String> = xs
+                    .borrow()
+                    .iter()
+                    .map(|x| x.to_string_within(within))
+                    .collect();
+                within.pop();
+                format!("[{}]", elements.join(", "))
+            }
+            LiteralValue::Map(entries) => {
+                let ptr = Rc::as_ptr(entries) as *const ();
+                if within.contains(&ptr) {
+                    return "{...}".to_string();
+                }
+                within.push(ptr);
+                let entries: Vec<String> = entries
+                    .borrow()
+                    .iter()
+                    .map(|(key, value)| {
+                        let key = key.to_string_within(within);
+                        format!("{}: {}", key, value.to_string_within(within))
+                    })
+                    .collect();
+                within.pop();
+                format!("{{{}}}", entries.join(", "))
+            }
+            LiteralValue::Error(error) => format!("{}: {}", error.error_type, error.message),
+            LiteralValue::Module(module) => format!("Module '{}'", module.name),
         }
     }
 
@@ -159,6 +296,11 @@ impl LiteralValue {
                 superclass: _,
             } => "Class",
             LiteralValue::LoxInstance { class, fields: _ } => &class_name!(class),
+            LiteralValue::Array(_) => "Array",
+            LiteralValue::Map(_) => "Map",
+            LiteralValue::VmClass(_) => "Class",
+            LiteralValue::Error(_) => "Error",
+            LiteralValue::Module(_) => "Module",
         }
     }
 
@@ -173,6 +315,27 @@ impl LiteralValue {
         }
     }
 
+    pub fn new_array(elements: Vec<LiteralValue>) -> Self {
+        Array(Rc::new(RefCell::new(elements)))
+    }
+
+    pub fn new_map(entries: Vec<(LiteralValue, LiteralValue)>) -> Self {
+        Map(Rc::new(RefCell::new(entries)))
+    }
+
+    pub fn new_error(message: String, error_type: String) -> Self {
+        Error(Rc::new(ErrorValue {
+            message,
+            error_type,
+            error: None,
+        }))
+    }
+
+    // Only plain values can be map keys, their equality cannot change after insertion
+    pub fn is_valid_key(&self) -> bool {
+        matches!(self, Number(_) | StringValue(_) | True | False | Null)
+    }
+
     pub fn from_bool(b: bool) -> Self {
         if b {
             True
@@ -197,12 +360,18 @@ impl LiteralValue {
                     False
                 }
             }
+            Array(xs) => LiteralValue::from_bool(xs.borrow().is_empty()),
+            Map(entries) => LiteralValue::from_bool(entries.borrow().is_empty()),
             True => False,
             False => True,
             Null => True,
-            Callable(_) => panic!("Cannot use Callable as a falsy value"),
-            LoxClass { .. } => panic!("Cannot use class as a falsy value"),
-            _ => panic!("Not valid as a boolean value"),
+            // Functions, classes, instances, errors and modules are always true, as in Lox
+            Callable(_)
+            | LoxClass { .. }
+            | LoxInstance { .. }
+            | VmClass(_)
+            | Error(_)
+            | Module(_) => False,
         }
     }
 
@@ -222,14 +391,292 @@ impl LiteralValue {
                     True
                 }
             }
+            Array(xs) => LiteralValue::from_bool(!xs.borrow().is_empty()),
+            Map(entries) => LiteralValue::from_bool(!entries.borrow().is_empty()),
             True => True,
             False => False,
             Null => False,
-            Callable(_) => panic!("Cannot use Callable as a truthy value"),
-            LoxClass { .. } => panic!("Cannot use class as a truthy value"),
-            _ => panic!("Not valid as a boolean value"),
+            Callable(_)
+            | LoxClass { .. }
+            | LoxInstance { .. }
+            | VmClass(_)
+            | Error(_)
+            | Module(_) => True,
+        }
+    }
+}
+
+pub fn map_get(
+    entries: &[(LiteralValue, LiteralValue)],
+    key: &LiteralValue,
+) -> Option<LiteralValue> {
+    entries
+        .iter()
+        .find(|(k, _)| k == key)
+        .map(|(_, value)| value.clone())
+}
+
+// Replaces the value of an existing key in place, so that it keeps its position
+pub fn map_insert(
+    entries: &mut Vec<(LiteralValue, LiteralValue)>,
+    key: LiteralValue,
+    value: LiteralValue,
+) {
+    match entries.iter_mut().find(|(k, _)| *k == key) {
+        Some(entry) => entry.1 = value,
+        None => entries.push((key, value)),
+    }
+}
+
+// The value a catch clause binds for an error that reached it
+pub fn caught_value(err: ValkyrieError) -> LiteralValue {
+    match err {
+        ValkyrieError::Throw(_, value) => *value,
+        err => Error(Rc::new(ErrorValue {
+            message: err.message().to_string(),
+            error_type: err.code().name(),
+            error: Some(err),
+        })),
+    }
+}
+
+// The error a throw statement raises, it is only reported if nothing catches it
+pub fn thrown_error(value: LiteralValue, span: Span) -> ValkyrieError {
+    if let Error(error) = &value {
+        if let Some(err) = &error.error {
+            return err.clone();
         }
     }
+    ValkyrieError::Throw(
+        Diagnostic::new(
+            ErrorCode::Uncaught,
+            format!("Uncaught {}", value.to_string()),
+            span,
+        ),
+        Box::new(value),
+    )
+}
+
+pub fn error_property(
+    error: &ErrorValue,
+    name: &str,
+    span: Span,
+) -> Result<LiteralValue, ValkyrieError> {
+    match name {
+        "message" => Ok(StringValue(error.message.clone())),
+        "type" => Ok(StringValue(error.error_type.clone())),
+        _ => Err(ValkyrieError::runtime(
+            ErrorCode::UndefinedProperty,
+            format!("No field named {} on this error", name),
+            span,
+        )
+        .with_note("Errors have a message and a type".to_string())),
+    }
+}
+
+pub fn module_property(
+    module: &Module,
+    name: &str,
+    span: Span,
+) -> Result<LiteralValue, ValkyrieError> {
+    match module.exports.get(name) {
+        Some(value) => Ok(value.clone()),
+        None => Err(ValkyrieError::runtime(
+            ErrorCode::UndefinedProperty,
+            format!("Module {} has no global named {}", module.name, name),
+            span,
+        )),
+    }
+}
+
+// How many calls can be in progress at once unless the embedder or --max-depth changes it
+pub const MAX_DEPTH: usize = 1000;
+
+// Raised by both backends before the Rust stack overflows
+pub fn stack_overflow(max_depth: usize, call_span: Span) -> ValkyrieError {
+    ValkyrieError::runtime(
+        ErrorCode::StackOverflow,
+        format!("Stack overflow, more than {} calls are nested", max_depth),
+        call_span,
+    )
+    .with_note("Run with --max-depth <n> to allow deeper recursion".to_string())
+}
+
+// Raised by the tree-walker when its calls use all the stack they can, which happens before the
+// depth limit on threads that have less stack than Options::spawn gives them
+pub fn stack_exhausted(depth: usize, call_span: Span) -> ValkyrieError {
+    ValkyrieError::runtime(
+        ErrorCode::StackOverflow,
+        format!(
+            "Stack overflow, {} nested calls used all of the stack",
+            depth
+        ),
+        call_span,
+    )
+    .with_note("Run on a thread with a bigger stack to allow deeper recursion".to_string())
+}
+
+// The operators are shared by both backends, so that they fail with the same errors
+pub fn unary_op(
+    operator: TokenType,
+    right: &LiteralValue,
+    span: Span,
+) -> Result<LiteralValue, ValkyrieError> {
+    match (right, operator) {
+        (Number(x), TokenType::Minus) => Ok(Number(-x)),
+        (_, TokenType::Minus) => Err(ValkyrieError::runtime(
+            ErrorCode::TypeMismatch,
+            format!("Minus not implemented for {}", right.to_type()),
+            span,
+        )),
+        (any, TokenType::Bang) => Ok(any.is_falsy()),
+        (_, ttype) => Err(ValkyrieError::runtime(
+            ErrorCode::InvalidOperator,
+            format!("{} is not a valid unary operator", ttype),
+            span,
+        )),
+    }
+}
+
+pub fn binary_op(
+    left: &LiteralValue,
+    operator: TokenType,
+    right: &LiteralValue,
+    span: Span,
+) -> Result<LiteralValue, ValkyrieError> {
+    match (left, operator, right) {
+        (Number(x), TokenType::Plus, Number(y)) => Ok(Number(x + y)),
+        (Number(x), TokenType::Minus, Number(y)) => Ok(Number(x - y)),
+        (Number(x), TokenType::Star, Number(y)) => Ok(Number(x * y)),
+        (Number(x), TokenType::Slash, Number(y)) => Ok(Number(x / y)),
+        (Number(x), TokenType::Greater, Number(y)) => Ok(LiteralValue::from_bool(x > y)),
+        (Number(x), TokenType::GreaterEqual, Number(y)) => Ok(LiteralValue::from_bool(x >= y)),
+        (Number(x), TokenType::Less, Number(y)) => Ok(LiteralValue::from_bool(x < y)),
+        (Number(x), TokenType::LessEqual, Number(y)) => Ok(LiteralValue::from_bool(x <= y)),
+
+        (StringValue(_), op, Number(_)) => Err(ValkyrieError::runtime(
+            ErrorCode::TypeMismatch,
+            format!("{} is not defined for string and number", op),
+            span,
+        )),
+        (Number(_), op, StringValue(_)) => Err(ValkyrieError::runtime(
+            ErrorCode::TypeMismatch,
+            format!("{} is not defined for string and number", op),
+            span,
+        )),
+
+        (StringValue(s1), TokenType::Plus, StringValue(s2)) => {
+            Ok(StringValue(format!("{}{}", s1, s2)))
+        }
+
+        (x, TokenType::BangEqual, y) => Ok(LiteralValue::from_bool(x != y)),
+        (x, TokenType::EqualEqual, y) => Ok(LiteralValue::from_bool(x == y)),
+        (StringValue(s1), TokenType::Greater, StringValue(s2)) => {
+            Ok(LiteralValue::from_bool(s1 > s2))
+        }
+        (StringValue(s1), TokenType::GreaterEqual, StringValue(s2)) => {
+            Ok(LiteralValue::from_bool(s1 >= s2))
+        }
+        (StringValue(s1), TokenType::Less, StringValue(s2)) => Ok(LiteralValue::from_bool(s1 < s2)),
+        (StringValue(s1), TokenType::LessEqual, StringValue(s2)) => {
+            Ok(LiteralValue::from_bool(s1 <= s2))
+        }
+        (x, ttype, y) => Err(ValkyrieError::runtime(
+            ErrorCode::TypeMismatch,
+            format!(
+                "{} is not implemented for operands {:?} and {:?}",
+                ttype, x, y
+            ),
+            span,
+        )),
+    }
+}
+
+// Checks that an index is a whole number inside an array of the given length
+fn array_index(index: &LiteralValue, len: usize, span: Span) -> Result<usize, ValkyrieError> {
+    match index {
+        Number(i) if i.fract() == 0.0 && *i >= 0.0 && (*i as usize) < len => Ok(*i as usize),
+        Number(i) => Err(ValkyrieError::runtime(
+            ErrorCode::IndexOutOfRange,
+            format!("Index {} is out of range for an array of length {}", i, len),
+            span,
+        )),
+        other => Err(ValkyrieError::runtime(
+            ErrorCode::TypeMismatch,
+            format!("Array index must be a Number, not {}", other.to_type()),
+            span,
+        )),
+    }
+}
+
+pub fn check_key(key: &LiteralValue, span: Span) -> Result<(), ValkyrieError> {
+    if key.is_valid_key() {
+        Ok(())
+    } else {
+        Err(ValkyrieError::runtime(
+            ErrorCode::TypeMismatch,
+            format!("{} cannot be used as a map key", key.to_type()),
+            span,
+        ))
+    }
+}
+
+// object[index]
+pub fn index_get(
+    object: &LiteralValue,
+    index: &LiteralValue,
+    span: Span,
+) -> Result<LiteralValue, ValkyrieError> {
+    match object {
+        Array(xs) => {
+            let xs = xs.borrow();
+            let i = array_index(index, xs.len(), span)?;
+            Ok(xs[i].clone())
+        }
+        Map(entries) => {
+            check_key(index, span)?;
+            match map_get(&entries.borrow(), index) {
+                Some(value) => Ok(value),
+                None => Err(ValkyrieError::runtime(
+                    ErrorCode::MissingKey,
+                    format!("Key {} is not in the map", index.to_string()),
+                    span,
+                )),
+            }
+        }
+        _ => Err(ValkyrieError::runtime(
+            ErrorCode::TypeMismatch,
+            format!("Cannot index into type {}", object.to_type()),
+            span,
+        )),
+    }
+}
+
+// object[index] = value, evaluates to the value
+pub fn index_set(
+    object: &LiteralValue,
+    index: LiteralValue,
+    value: LiteralValue,
+    span: Span,
+) -> Result<LiteralValue, ValkyrieError> {
+    match object {
+        Array(xs) => {
+            let mut xs = xs.borrow_mut();
+            let i = array_index(&index, xs.len(), span)?;
+            xs[i] = value.clone();
+            Ok(value)
+        }
+        Map(entries) => {
+            check_key(&index, span)?;
+            map_insert(&mut entries.borrow_mut(), index, value.clone());
+            Ok(value)
+        }
+        _ => Err(ValkyrieError::runtime(
+            ErrorCode::TypeMismatch,
+            format!("Cannot index into type {}", object.to_type()),
+            span,
+        )),
+    }
 }
 
 use crate::stmt::Stmt;
@@ -239,17 +686,26 @@ use crate::stmt::Stmt;
 pub enum Expr {
     AnonFunction {
         id: usize,
+        span: Span,
         paren: Token,
-        arguments: Vec<Token>,
-        body: Vec<Box<Stmt>>,
+        arguments: Rc<[Token]>,
+        body: Rc<[Stmt]>,
+    },
+    // [a, b, c]
+    Array {
+        id: usize,
+        span: Span,
+        elements: Vec<Expr>,
     },
     Assign {
         id: usize,
+        span: Span,
         name: Token,
         value: Box<Expr>,
     },
     Binary {
         id: usize,
+        span: Span,
         left: Box<Expr>,
         operator: Token,
         right: Box<Expr>,
@@ -258,51 +714,82 @@ pub enum Expr {
     Call {
         // x |> f -> Call { id, f, paren (pipe), arguments: [x]}
         id: usize,
+        span: Span,
         callee: Box<Expr>,
         paren: Token,
         arguments: Vec<Expr>,
     },
     Get {
         id: usize,
+        span: Span,
         object: Box<Expr>,
         name: Token,
     },
     Grouping {
         id: usize,
+        span: Span,
         expression: Box<Expr>,
     },
+    // xs[i]
+    Index {
+        id: usize,
+        span: Span,
+        object: Box<Expr>,
+        index: Box<Expr>,
+    },
+    // xs[i] = value
+    IndexSet {
+        id: usize,
+        span: Span,
+        object: Box<Expr>,
+        index: Box<Expr>,
+        value: Box<Expr>,
+    },
     Literal {
         id: usize,
+        span: Span,
         value: LiteralValue,
     },
     Logical {
         id: usize,
+        span: Span,
         left: Box<Expr>,
         operator: Token,
         right: Box<Expr>,
     },
+    // {"key": value}
+    Map {
+        id: usize,
+        span: Span,
+        entries: Vec<(Expr, Expr)>,
+    },
     Set {
         id: usize,
+        span: Span,
         object: Box<Expr>,
         name: Token,
         value: Box<Expr>,
     },
     This {
         id: usize,
+        span: Span,
         keyword: Token,
     },
     Super {
         id: usize,
+        span: Span,
         keyword: Token,
         method: Token,
     },
     Unary {
         id: usize,
+        span: Span,
         operator: Token,
         right: Box<Expr>,
     },
     Variable {
         id: usize,
+        span: Span,
         name: Token,
     },
 }
@@ -330,21 +817,51 @@ impl PartialEq for Expr {
 impl Eq for Expr {}
 
 impl Expr {
+    pub fn get_span(&self) -> Span {
+        match self {
+            Expr::AnonFunction { span, .. } => *span,
+            Expr::Array { span, .. } => *span,
+            Expr::Assign { span, .. } => *span,
+            Expr::Binary { span, .. } => *span,
+            Expr::Call { span, .. } => *span,
+            Expr::Get { span, .. } => *span,
+            Expr::Grouping { span, .. } => *span,
+            Expr::Index { span, .. } => *span,
+            Expr::IndexSet { span, .. } => *span,
+            Expr::Literal { span, .. } => *span,
+            Expr::Logical { span, .. } => *span,
+            Expr::Map { span, .. } => *span,
+            Expr::Set { span, .. } => *span,
+            Expr::This { span, .. } => *span,
+            Expr::Super { span, .. } => *span,
+            Expr::Unary { span, .. } => *span,
+            Expr::Variable { span, .. } => *span,
+        }
+    }
+
     pub fn get_id(&self) -> usize {
         match self {
             Expr::AnonFunction {
                 id,
+                span: _,
                 paren: _,
                 arguments: _,
                 body: _,
             } => *id,
+            Expr::Array {
+                id,
+                span: _,
+                elements: _,
+            } => *id,
             Expr::Assign {
                 id,
+                span: _,
                 name: _,
                 value: _,
             } => *id,
             Expr::Binary {
                 id,
+                span: _,
                 left: _,
                 operator: _,
                 right: _,
@@ -352,41 +869,81 @@ impl Expr {
 
             Expr::Call {
                 id,
+                span: _,
                 callee: _,
                 paren: _,
                 arguments: _,
             } => *id,
             Expr::Get {
                 id,
+                span: _,
                 object: _,
                 name: _,
             } => *id,
-            Expr::Grouping { id, expression: _ } => *id,
-            Expr::Literal { id, value: _ } => *id,
+            Expr::Grouping {
+                id,
+                span: _,
+                expression: _,
+            } => *id,
+            Expr::Index {
+                id,
+                span: _,
+                object: _,
+                index: _,
+            } => *id,
+            Expr::IndexSet {
+                id,
+                span: _,
+                object: _,
+                index: _,
+                value: _,
+            } => *id,
+            Expr::Literal {
+                id,
+                span: _,
+                value: _,
+            } => *id,
             Expr::Logical {
                 id,
+                span: _,
                 left: _,
                 operator: _,
                 right: _,
             } => *id,
+            Expr::Map {
+                id,
+                span: _,
+                entries: _,
+            } => *id,
             Expr::Set {
                 id,
+                span: _,
                 object: _,
                 name: _,
                 value: _,
             } => *id,
-            Expr::This { id, keyword: _ } => *id,
+            Expr::This {
+                id,
+                span: _,
+                keyword: _,
+            } => *id,
             Expr::Super {
                 id,
+                span: _,
                 keyword: _,
                 method: _,
             } => *id,
             Expr::Unary {
                 id,
+                span: _,
                 operator: _,
                 right: _,
             } => *id,
-            Expr::Variable { id, name: _ } => *id,
+            Expr::Variable {
+                id,
+                span: _,
+                name: _,
+            } => *id,
         }
     }
 }
@@ -397,13 +954,32 @@ impl Expr {
         match self {
             Expr::AnonFunction {
                 id: _,
+                span: _,
                 paren: _,
                 arguments,
                 body: _,
             } => format!("anon/{}", arguments.len()),
-            Expr::Assign { id: _, name, value } => format!("({name:?} = {}", value.to_string()),
+            Expr::Array {
+                id: _,
+                span: _,
+                elements,
+            } => format!(
+                "(array {})",
+                elements
+                    .iter()
+                    .map(|e| e.to_string())
+                    .collect::<Vec<String>>()
+                    .join(" ")
+            ),
+            Expr::Assign {
+                id: _,
+                span: _,
+                name,
+                value,
+            } => format!("({name:?} = {}", value.to_string()),
             Expr::Binary {
                 id: _,
+                span: _,
                 left,
                 operator,
                 right,
@@ -415,21 +991,50 @@ impl Expr {
             ),
             Expr::Call {
                 id: _,
+                span: _,
                 callee,
                 paren: _,
                 arguments,
             } => format!("({} {:?})", (*callee).to_string(), arguments),
             Expr::Get {
                 id: _,
+                span: _,
                 object,
                 name,
             } => format!("(get {} {})", object.to_string(), name.lexeme),
-            Expr::Grouping { id: _, expression } => {
+            Expr::Grouping {
+                id: _,
+                span: _,
+                expression,
+            } => {
                 format!("(group {})", (*expression).to_string())
             }
-            Expr::Literal { id: _, value } => format!("{}", value.to_string()),
+            Expr::Index {
+                id: _,
+                span: _,
+                object,
+                index,
+            } => format!("(index {} {})", object.to_string(), index.to_string()),
+            Expr::IndexSet {
+                id: _,
+                span: _,
+                object,
+                index,
+                value,
+            } => format!(
+                "(index-set {} {} {})",
+                object.to_string(),
+                index.to_string(),
+                value.to_string()
+            ),
+            Expr::Literal {
+                id: _,
+                span: _,
+                value,
+            } => value.to_string(),
             Expr::Logical {
                 id: _,
+                span: _,
                 left,
                 operator,
                 right,
@@ -439,8 +1044,21 @@ impl Expr {
                 left.to_string(),
                 right.to_string()
             ),
+            Expr::Map {
+                id: _,
+                span: _,
+                entries,
+            } => format!(
+                "(map {})",
+                entries
+                    .iter()
+                    .map(|(key, value)| format!("({} {})", key.to_string(), value.to_string()))
+                    .collect::<Vec<String>>()
+                    .join(" ")
+            ),
             Expr::Set {
                 id: _,
+                span: _,
                 object,
                 name,
                 value,
@@ -450,14 +1068,20 @@ impl Expr {
                 name.to_string(),
                 value.to_string()
             ),
-            Expr::This { id: _, keyword: _ } => format!("(this)"),
+            Expr::This {
+                id: _,
+                span: _,
+                keyword: _,
+            } => "(this)".to_string(),
             Expr::Super {
                 id: _,
+                span: _,
                 keyword: _,
                 method,
             } => format!("(super {})", method.lexeme),
             Expr::Unary {
                 id: _,
+                span: _,
                 operator,
                 right,
             } => {
@@ -465,150 +1089,142 @@ impl Expr {
                 let right_str = (*right).to_string();
                 format!("({} {})", operator_str, right_str)
             }
-            Expr::Variable { id: _, name } => format!("(var {})", name.lexeme),
+            Expr::Variable {
+                id: _,
+                span: _,
+                name,
+            } => format!("(var {})", name.lexeme),
         }
     }
 
-    pub fn evaluate(&self, environment: Environment) -> Result<LiteralValue, String> {
+    fn error(&self, code: ErrorCode, msg: String) -> ValkyrieError {
+        ValkyrieError::runtime(code, msg, self.get_span())
+    }
+
+    pub fn evaluate(&self, environment: &Environment) -> Result<LiteralValue, ValkyrieError> {
         match self {
             Expr::AnonFunction {
                 id: _,
+                span: _,
                 paren: _,
                 arguments,
                 body,
             } => {
-                // We have to clone everything so the borrow checker doesnt get scared about us taking ownership of the values in the Expr
-                let arity = arguments.len();
-                let arguments: Vec<Token> = arguments.iter().map(|t| (*t).clone()).collect();
-                let body: Vec<Box<Stmt>> = body.iter().map(|b| (*b).clone()).collect();
-
                 let callable_impl = CallableImpl::LoxFunction(LoxFunctionImpl {
                     name: "anon_funciton".to_string(),
-                    arity,
+                    arity: arguments.len(),
                     parent_env: environment.clone(),
-                    params: arguments,
-                    body,
+                    params: arguments.clone(),
+                    body: body.clone(),
+                    class_name: None,
                 });
 
                 Ok(Callable(callable_impl))
             }
-            Expr::Assign { id: _, name, value } => {
-                let new_value = (*value).evaluate(environment.clone())?;
+            Expr::Array {
+                id: _,
+                span: _,
+                elements,
+            } => {
+                let mut values = vec![];
+                for element in elements {
+                    values.push(element.evaluate(environment)?);
+                }
+                let array = LiteralValue::new_array(values);
+                environment.budget().check_size(&array, self.get_span())?;
+                Ok(array)
+            }
+            Expr::Assign {
+                id: _,
+                span: _,
+                name,
+                value,
+            } => {
+                let new_value = (*value).evaluate(environment)?;
                 let assign_success =
-                    environment.assign(&name.lexeme, new_value.clone(), self.get_id());
+                    environment.assign(name.name(), new_value.clone(), self.get_id());
 
                 if assign_success {
                     Ok(new_value)
                 } else {
-                    Err(format!("Variable {} has not been declared", name.lexeme))
+                    Err(self.error(
+                        ErrorCode::UndefinedVariable,
+                        format!("Variable {} has not been declared", name.name()),
+                    ))
                 }
             }
-            Expr::Variable { id: _, name } => match environment.get(&name.lexeme, self.get_id()) {
+            Expr::Variable {
+                id: _,
+                span: _,
+                name,
+            } => match environment.get(name.name(), self.get_id()) {
                 Some(value) => Ok(value.clone()),
-                None => Err(format!(
-                    "Variable '{}' has not been declared at distance {:?}",
-                    name.lexeme,
-                    environment.get_distance(self.get_id())
+                None => Err(self.error(
+                    ErrorCode::UndefinedVariable,
+                    format!(
+                        "Variable '{}' has not been declared at distance {:?}",
+                        name.name(),
+                        environment.get_distance(self.get_id())
+                    ),
                 )),
             },
             Expr::Call {
                 id: _,
+                span: _,
                 callee,
                 paren: _,
                 arguments,
             } => {
-                // Look up function definition in environment
-                // let callable_distance = locals.borrow().get(&self.get_id());
-                let callable: LiteralValue = (*callee).evaluate(environment.clone())?;
-                let callable_clone = callable.clone();
-                match callable {
-                    Callable(CallableImpl::LoxFunction(loxfun)) => {
-                        run_lox_function(loxfun, arguments, environment)
-                    }
-                    Callable(CallableImpl::NativeFunction(nativefun)) => {
-                        let mut evaluated_arguments = vec![];
-                        for argument in arguments {
-                            evaluated_arguments.push(argument.evaluate(environment.clone())?);
-                        }
-                        Ok((nativefun.fun)(&evaluated_arguments))
-                    }
-                    LoxClass {
-                        name: _,
-                        methods,
-                        superclass: _,
-                    } => {
-                        let instance = LoxInstance {
-                            class: Box::new(callable_clone.clone()),
-                            fields: Rc::new(RefCell::new(vec![])),
-                        };
-
-                        // Call constructor if present
-                        if let Some(init_method) = methods.get("init") {
-                            if init_method.arity != arguments.len() {
-                                return Err(
-                                    "Invalid number of arguments in constructor".to_string()
-                                );
-                            }
-
-                            // let new_env = environment.enclose();
-                            // new_env.define("this".to_string(), instance.clone());
-                            // let mut init_method = init_method.clone();
-                            // init_method.parent_env = new_env.clone();
-                            let mut init_method = init_method.clone();
-                            init_method.parent_env = init_method.parent_env.enclose();
-                            init_method
-                                .parent_env
-                                .define("this".to_string(), instance.clone());
-
-                            if let Err(msg) = run_lox_function(init_method, arguments, environment)
-                            {
-                                return Err(msg);
-                            }
-                        }
-
-                        Ok(instance)
-                    }
-                    other => Err(format!("{} is not callable", other.to_type())),
-                }
+                let callable = callee.evaluate(environment)?;
+                call(callable, arguments, environment, self.get_span())
             }
-            Expr::Literal { id: _, value } => Ok((*value).clone()),
+            Expr::Literal {
+                id: _,
+                span: _,
+                value,
+            } => Ok((*value).clone()),
             Expr::Logical {
                 id: _,
+                span: _,
                 left,
                 operator,
                 right,
             } => match operator.token_type {
                 TokenType::Or => {
-                    let lhs_value = left.evaluate(environment.clone())?;
+                    let lhs_value = left.evaluate(environment)?;
                     let lhs_true = lhs_value.is_truthy();
                     if lhs_true == True {
                         Ok(lhs_value)
                     } else {
-                        right.evaluate(environment.clone())
+                        right.evaluate(environment)
                     }
                 }
                 TokenType::And => {
-                    let lhs_value = left.evaluate(environment.clone())?;
+                    let lhs_value = left.evaluate(environment)?;
                     let lhs_true = lhs_value.is_truthy();
                     if lhs_true == False {
                         Ok(lhs_true)
                     } else {
-                        right.evaluate(environment.clone())
+                        right.evaluate(environment)
                     }
                 }
-                ttype => Err(format!("Invalid token in logical expression: {}", ttype)),
+                ttype => Err(self.error(
+                    ErrorCode::InvalidOperator,
+                    format!("Invalid token in logical expression: {}", ttype),
+                )),
             },
             Expr::Get {
                 id: _,
+                span: _,
                 object,
                 name,
             } => {
-                let obj_value = object.evaluate(environment.clone())?;
+                let obj_value = object.evaluate(environment)?;
                 // Now obj_value should be a LoxInstance
                 if let LoxInstance { class, fields } = obj_value.clone() {
                     for (field_name, value) in (*fields.borrow()).iter() {
                         // Are we getting a field on the object?
-                        if field_name == &name.lexeme {
+                        if field_name == name.name() {
                             return Ok(value.clone());
                         }
                     }
@@ -622,39 +1238,47 @@ impl Expr {
                         superclass: _,
                     } = class.as_ref()
                     {
-                        if let Some(method) = find_method(&name.lexeme, *class.clone()) {
+                        if let Some(method) = find_method(name.name(), *class.clone()) {
                             let mut callable_impl = method.clone();
                             let new_env = callable_impl.parent_env.enclose();
-                            new_env.define("this".to_string(), obj_value.clone());
+                            new_env.define("this", obj_value.clone());
                             callable_impl.parent_env = new_env;
                             return Ok(Callable(LoxFunction(callable_impl)));
                         }
                     } else {
                         panic!("The class field on an instance was not a LoxClass");
                     }
-                    Err(format!("No field named {} on this instance", name.lexeme))
+                    Err(self.error(
+                        ErrorCode::UndefinedProperty,
+                        format!("No field named {} on this instance", name.name()),
+                    ))
+                } else if let Error(error) = &obj_value {
+                    error_property(error, name.name(), self.get_span())
+                } else if let Module(module) = &obj_value {
+                    module_property(module, name.name(), self.get_span())
                 } else {
-                    Err(format!(
-                        "Cannot access property on type {}",
-                        obj_value.to_type()
+                    Err(self.error(
+                        ErrorCode::TypeMismatch,
+                        format!("Cannot access property on type {}", obj_value.to_type()),
                     ))
                 }
             }
             Expr::Set {
                 id: _,
+                span: _,
                 object, //object.name = value
                 name,
                 value,
             } => {
-                let obj_value = object.evaluate(environment.clone())?;
+                let obj_value = object.evaluate(environment)?;
                 if let LoxInstance { class: _, fields } = obj_value {
-                    let value = value.evaluate(environment.clone())?;
+                    let value = value.evaluate(environment)?;
 
                     let mut idx = 0;
                     let mut found = false;
                     for i in 0..(*fields.borrow()).len() {
                         let field_name = &(*fields.borrow())[i].0;
-                        if field_name == &name.lexeme {
+                        if field_name == name.name() {
                             idx = i;
                             found = true;
                             break;
@@ -664,18 +1288,22 @@ impl Expr {
                     if found {
                         (*fields.borrow_mut())[idx].1 = value.clone();
                     } else {
-                        (*fields.borrow_mut()).push((name.lexeme.clone(), value));
+                        (*fields.borrow_mut()).push((name.name().to_string(), value));
                     }
 
                     Ok(Null)
                 } else {
-                    Err(format!(
-                        "Cannot set property on type {}",
-                        obj_value.to_type()
+                    Err(self.error(
+                        ErrorCode::TypeMismatch,
+                        format!("Cannot set property on type {}", obj_value.to_type()),
                     ))
                 }
             }
-            Expr::This { id: _, keyword: _ } => {
+            Expr::This {
+                id: _,
+                span: _,
+                keyword: _,
+            } => {
                 let this = environment
                     .get("this", self.get_id())
                     .expect("Couldn't lookup 'this'");
@@ -683,6 +1311,7 @@ impl Expr {
             }
             Expr::Super {
                 id: _,
+                span: _,
                 keyword: _,
                 method,
             } => {
@@ -694,7 +1323,7 @@ impl Expr {
                 let instance = environment.get_this_instance(self.get_id()).unwrap();
 
                 // let new_env = environment.enclose();
-                // new_env.define("this".to_string(), instance.clone());
+                // new_env.define("this", instance.clone());
 
                 if let LoxClass {
                     name: _,
@@ -702,96 +1331,93 @@ impl Expr {
                     superclass: _,
                 } = superclass.clone()
                 {
-                    if let Some(method_value) = methods.get(&method.lexeme) {
+                    if let Some(method_value) = methods.get(method.name()) {
                         let mut method = method_value.clone();
                         method.parent_env = method.parent_env.enclose();
-                        method
-                            .parent_env
-                            .define("this".to_string(), instance.clone());
+                        method.parent_env.define("this", instance.clone());
                         Ok(Callable(LoxFunction(method)))
                     } else {
-                        Err(format!(
-                            "No method named {} on superclass {}",
-                            method.lexeme,
-                            superclass.to_type()
+                        Err(self.error(
+                            ErrorCode::UndefinedProperty,
+                            format!(
+                                "No method named {} on superclass {}",
+                                method.name(),
+                                superclass.to_type()
+                            ),
                         ))
                     }
                 } else {
                     panic!("The superclass field on an instance was not a LoxClass");
                 }
             }
-            Expr::Grouping { id: _, expression } => expression.evaluate(environment),
+            Expr::Grouping {
+                id: _,
+                span: _,
+                expression,
+            } => expression.evaluate(environment),
+            Expr::Index {
+                id: _,
+                span: _,
+                object,
+                index,
+            } => {
+                let obj_value = object.evaluate(environment)?;
+                let index_value = index.evaluate(environment)?;
+                index_get(&obj_value, &index_value, self.get_span())
+            }
+            Expr::IndexSet {
+                id: _,
+                span: _,
+                object,
+                index,
+                value,
+            } => {
+                let obj_value = object.evaluate(environment)?;
+                let index_value = index.evaluate(environment)?;
+                let value = value.evaluate(environment)?;
+                let value = index_set(&obj_value, index_value, value, self.get_span())?;
+                environment
+                    .budget()
+                    .check_size(&obj_value, self.get_span())?;
+                Ok(value)
+            }
+            Expr::Map {
+                id: _,
+                span: _,
+                entries,
+            } => {
+                let mut values = vec![];
+                for (key, value) in entries {
+                    let key_value = key.evaluate(environment)?;
+                    check_key(&key_value, key.get_span())?;
+                    let value = value.evaluate(environment)?;
+                    map_insert(&mut values, key_value, value);
+                }
+                let map = LiteralValue::new_map(values);
+                environment.budget().check_size(&map, self.get_span())?;
+                Ok(map)
+            }
             Expr::Unary {
                 id: _,
+                span: _,
                 operator,
                 right,
             } => {
                 let right = right.evaluate(environment)?;
-
-                match (&right, operator.token_type) {
-                    (Number(x), TokenType::Minus) => Ok(Number(-x)),
-                    (_, TokenType::Minus) => {
-                        Err(format!("Minus not implemented for {}", right.to_type()))
-                    }
-                    (any, TokenType::Bang) => Ok(any.is_falsy()),
-                    (_, ttype) => Err(format!("{} is not a valid unary operator", ttype)),
-                }
+                unary_op(operator.token_type, &right, self.get_span())
             }
             Expr::Binary {
                 id: _,
+                span: _,
                 left,
                 operator,
                 right,
             } => {
-                let left = left.evaluate(environment.clone())?;
-                let right = right.evaluate(environment.clone())?;
-
-                match (&left, operator.token_type, &right) {
-                    (Number(x), TokenType::Plus, Number(y)) => Ok(Number(x + y)),
-                    (Number(x), TokenType::Minus, Number(y)) => Ok(Number(x - y)),
-                    (Number(x), TokenType::Star, Number(y)) => Ok(Number(x * y)),
-                    (Number(x), TokenType::Slash, Number(y)) => Ok(Number(x / y)),
-                    (Number(x), TokenType::Greater, Number(y)) => {
-                        Ok(LiteralValue::from_bool(x > y))
-                    }
-                    (Number(x), TokenType::GreaterEqual, Number(y)) => {
-                        Ok(LiteralValue::from_bool(x >= y))
-                    }
-                    (Number(x), TokenType::Less, Number(y)) => Ok(LiteralValue::from_bool(x < y)),
-                    (Number(x), TokenType::LessEqual, Number(y)) => {
-                        Ok(LiteralValue::from_bool(x <= y))
-                    }
-
-                    (StringValue(_), op, Number(_)) => {
-                        Err(format!("{} is not defined for string and number", op))
-                    }
-                    (Number(_), op, StringValue(_)) => {
-                        Err(format!("{} is not defined for string and number", op))
-                    }
-
-                    (StringValue(s1), TokenType::Plus, StringValue(s2)) => {
-                        Ok(StringValue(format!("{}{}", s1, s2)))
-                    }
-
-                    (x, TokenType::BangEqual, y) => Ok(LiteralValue::from_bool(x != y)),
-                    (x, TokenType::EqualEqual, y) => Ok(LiteralValue::from_bool(x == y)),
-                    (StringValue(s1), TokenType::Greater, StringValue(s2)) => {
-                        Ok(LiteralValue::from_bool(s1 > s2))
-                    }
-                    (StringValue(s1), TokenType::GreaterEqual, StringValue(s2)) => {
-                        Ok(LiteralValue::from_bool(s1 >= s2))
-                    }
-                    (StringValue(s1), TokenType::Less, StringValue(s2)) => {
-                        Ok(LiteralValue::from_bool(s1 < s2))
-                    }
-                    (StringValue(s1), TokenType::LessEqual, StringValue(s2)) => {
-                        Ok(LiteralValue::from_bool(s1 <= s2))
-                    }
-                    (x, ttype, y) => Err(format!(
-                        "{} is not implemented for operands {:?} and {:?}",
-                        ttype, x, y
-                    )),
-                }
+                let left = left.evaluate(environment)?;
+                let right = right.evaluate(environment)?;
+                let value = binary_op(&left, operator.token_type, &right, self.get_span())?;
+                environment.budget().check_size(&value, self.get_span())?;
+                Ok(value)
             }
         }
     }
@@ -802,46 +1428,239 @@ impl Expr {
     }
 }
 
-pub fn run_lox_function(
-    loxfun: LoxFunctionImpl,
-    arguments: &Vec<Expr>,
-    eval_env: Environment,
-) -> Result<LiteralValue, String> {
-    // Do some checking (correct number of args?)
-    if arguments.len() != loxfun.arity {
-        return Err(format!(
-            "Callable {} expected {} arguments but got {}",
-            loxfun.name,
-            loxfun.arity,
-            arguments.len()
-        ));
-    }
+// Calls a value that was already evaluated, the arguments are evaluated in the environment of
+// the call
+pub fn call(
+    callable: LiteralValue,
+    arguments: &[Expr],
+    environment: &Environment,
+    call_span: Span,
+) -> Result<LiteralValue, ValkyrieError> {
+    let callable_clone = callable.clone();
+    match callable {
+        Callable(CallableImpl::LoxFunction(loxfun)) => {
+            run_lox_function(&loxfun, arguments, environment, call_span)
+        }
+        Callable(CallableImpl::NativeFunction(nativefun)) => {
+            environment.check_depth(call_span)?;
+            let mut evaluated_arguments = vec![];
+            for argument in arguments {
+                evaluated_arguments.push(argument.evaluate(environment)?);
+            }
+            nativefun.check_arity(evaluated_arguments.len(), call_span)?;
+            environment.budget().step(call_span)?;
+            let value = environment
+                .budget()
+                .call_native(|| (nativefun.fun)(&evaluated_arguments))
+                .map_err(|err| err.with_span(call_span))?;
+            check_native_sizes(
+                environment.budget(),
+                &value,
+                &evaluated_arguments,
+                call_span,
+            )?;
+            Ok(value)
+        }
+        LoxClass {
+            name: _,
+            methods,
+            superclass: _,
+        } => {
+            let instance = LoxInstance {
+                class: Box::new(callable_clone.clone()),
+                fields: Rc::new(RefCell::new(vec![])),
+            };
+
+            // Call constructor if present
+            if let Some(init_method) = methods.get("init") {
+                if init_method.arity != arguments.len() {
+                    return Err(ValkyrieError::runtime(
+                        ErrorCode::ArityMismatch,
+                        "Invalid number of arguments in constructor".to_string(),
+                        call_span,
+                    ));
+                }
+
+                // let new_env = environment.enclose();
+                // new_env.define("this", instance.clone());
+                // let mut init_method = init_method.clone();
+                // init_method.parent_env = new_env.clone();
+                let mut init_method = init_method.clone();
+                init_method.parent_env = init_method.parent_env.enclose();
+                init_method.parent_env.define("this", instance.clone());
+
+                run_lox_function(&init_method, arguments, environment, call_span)?;
+            }
 
-    // Evaluate arguments
-    let mut arg_vals = vec![];
-    for arg in arguments {
-        let val = arg.evaluate(eval_env.clone())?;
-        arg_vals.push(val);
+            Ok(instance)
+        }
+        other => Err(ValkyrieError::runtime(
+            ErrorCode::NotCallable,
+            format!("{} is not callable", other.to_type()),
+            call_span,
+        )),
     }
+}
 
+pub fn run_lox_function(
+    loxfun: &LoxFunctionImpl,
+    arguments: &[Expr],
+    eval_env: &Environment,
+    call_span: Span,
+) -> Result<LiteralValue, ValkyrieError> {
+    check_arity(loxfun, arguments.len(), call_span)?;
+    eval_env.check_depth(call_span)?;
+
+    // Evaluate arguments, straight into the slots of the parameters
     let fun_env = loxfun.parent_env.enclose();
+    for (param, arg) in loxfun.params.iter().zip(arguments) {
+        fun_env.define(param.name(), arg.evaluate(eval_env)?);
+    }
 
-    for (i, val) in arg_vals.iter().enumerate() {
-        fun_env.define(loxfun.params[i].lexeme.clone(), (*val).clone());
+    run_bound_function(loxfun, fun_env, call_span)
+}
+
+// Makes a call in tail position that could not be unwound, because a try statement still has to
+// see how it ends
+pub fn run_tail_call(
+    loxfun: &LoxFunctionImpl,
+    arguments: Vec<LiteralValue>,
+    eval_env: &Environment,
+    call_span: Span,
+) -> Result<LiteralValue, ValkyrieError> {
+    eval_env.check_depth(call_span)?;
+
+    let fun_env = loxfun.parent_env.enclose();
+    for (param, value) in loxfun.params.iter().zip(arguments) {
+        fun_env.define(param.name(), value);
     }
 
-    let mut int = Interpreter::with_env(fun_env);
-    for i in 0..(loxfun.body.len()) {
-        let result = int.interpret(vec![&loxfun.body[i]]);
-        if let Err(e) = result {
-            return Err(e);
+    run_bound_function(loxfun, fun_env, call_span)
+}
+
+fn run_bound_function(
+    loxfun: &LoxFunctionImpl,
+    fun_env: Environment,
+    call_span: Span,
+) -> Result<LiteralValue, ValkyrieError> {
+    // Calls in tail position come back here instead of nesting, so that the Rust stack does not
+    // grow. A chain of them keeps the frame it started from and the frame of the latest call
+    let mut tail_function;
+    let mut loxfun = loxfun;
+    let mut fun_env = fun_env;
+    let mut call_span = call_span;
+    let mut frames = 0;
+    let result = loop {
+        // Counted while the caller is still on the stack, as the VM does
+        if let Err(err) = fun_env.budget().step(call_span) {
+            break Err(err.with_trace(fun_env.call_stack()));
+        }
+        if frames == 2 {
+            fun_env.pop_frame();
+            frames -= 1;
         }
-        if let Some(value) = int.specials.get("return") {
-            return Ok(value.clone());
+        fun_env.push_frame(CallFrame {
+            function: loxfun.name.clone(),
+            class: loxfun.class_name.clone(),
+            call_span,
+        });
+        frames += 1;
+        let result = run_lox_body(loxfun, fun_env.clone()).map_err(|err| {
+            // The innermost call that sees the error still has the whole stack
+            if err.trace().is_empty() {
+                err.with_trace(fun_env.call_stack())
+            } else {
+                err
+            }
+        });
+
+        match result {
+            Ok(Some(ControlFlow::TailCall {
+                function,
+                arguments,
+                call_span: span,
+            })) => {
+                fun_env = function.parent_env.enclose();
+                for (param, value) in function.params.iter().zip(arguments) {
+                    fun_env.define(param.name(), value);
+                }
+                tail_function = function;
+                loxfun = &tail_function;
+                call_span = span;
+            }
+            Ok(Some(ControlFlow::Return(value))) => break Ok(value),
+            Ok(_) => break Ok(LiteralValue::Null),
+            Err(err) => break Err(err),
         }
+    };
+    for _ in 0..frames {
+        fun_env.pop_frame();
     }
 
-    Ok(LiteralValue::Null)
+    result
+}
+
+pub fn check_arity(
+    loxfun: &LoxFunctionImpl,
+    count: usize,
+    call_span: Span,
+) -> Result<(), ValkyrieError> {
+    if count != loxfun.arity {
+        return Err(ValkyrieError::runtime(
+            ErrorCode::ArityMismatch,
+            format!(
+                "Callable {} expected {} arguments but got {}",
+                loxfun.name, loxfun.arity, count
+            ),
+            call_span,
+        ));
+    }
+    Ok(())
+}
+
+// A native can make a value or grow one it was given, like push does
+pub fn check_native_sizes(
+    budget: &Budget,
+    value: &LiteralValue,
+    arguments: &[LiteralValue],
+    call_span: Span,
+) -> Result<(), ValkyrieError> {
+    budget.check_size(value, call_span)?;
+    for argument in arguments {
+        budget.check_size(argument, call_span)?;
+    }
+    Ok(())
+}
+
+impl NativeFunctionImpl {
+    pub fn check_arity(&self, count: usize, call_span: Span) -> Result<(), ValkyrieError> {
+        let message = if self.variadic && count < self.arity {
+            format!(
+                "Callable {} expected at least {} arguments but got {}",
+                self.name, self.arity, count
+            )
+        } else if !self.variadic && count != self.arity {
+            format!(
+                "Callable {} expected {} arguments but got {}",
+                self.name, self.arity, count
+            )
+        } else {
+            return Ok(());
+        };
+        Err(ValkyrieError::runtime(
+            ErrorCode::ArityMismatch,
+            message,
+            call_span,
+        ))
+    }
+}
+
+fn run_lox_body(
+    loxfun: &LoxFunctionImpl,
+    fun_env: Environment,
+) -> Result<Option<ControlFlow>, ValkyrieError> {
+    let mut int = Interpreter::with_env(fun_env);
+    int.execute(loxfun.body.iter())
 }
 
 pub fn find_method(name: &str, class: LiteralValue) -> Option<LoxFunctionImpl> {
@@ -875,16 +1694,19 @@ mod tests {
             token_type: TokenType::Minus,
             lexeme: "-".to_string(),
             literal: None,
-            line_number: 0,
+            span: Span::default(),
         };
         let onetwothree = Literal {
             id: 0,
+            span: Span::default(),
             value: Number(123.0),
         };
         let group = Grouping {
             id: 1,
+            span: Span::default(),
             expression: Box::from(Literal {
                 id: 2,
+                span: Span::default(),
                 value: Number(45.67),
             }),
         };
@@ -892,12 +1714,14 @@ mod tests {
             token_type: TokenType::Star,
             lexeme: "*".to_string(),
             literal: None,
-            line_number: 0,
+            span: Span::default(),
         };
         let ast = Binary {
             id: 3,
+            span: Span::default(),
             left: Box::from(Unary {
                 id: 4,
+                span: Span::default(),
                 operator: minus_token,
                 right: Box::from(onetwothree),
             }),
@@ -916,16 +1740,19 @@ mod tests {
             token_type: TokenType::Minus,
             lexeme: "-".to_string(),
             literal: None,
-            line_number: 0,
+            span: Span::default(),
         };
         let onetwothree = Literal {
             id: 0,
+            span: Span::default(),
             value: Number(123.0),
         };
         let group = Grouping {
             id: 1,
+            span: Span::default(),
             expression: Box::from(Literal {
                 id: 2,
+                span: Span::default(),
                 value: Number(45.67),
             }),
         };
@@ -933,12 +1760,14 @@ mod tests {
             token_type: TokenType::Star,
             lexeme: "*".to_string(),
             literal: None,
-            line_number: 0,
+            span: Span::default(),
         };
         let expr = Binary {
             id: 3,
+            span: Span::default(),
             left: Box::from(Unary {
                 id: 4,
+                span: Span::default(),
                 operator: minus_token,
                 right: Box::from(onetwothree),
             }),
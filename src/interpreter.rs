@@ -1,32 +1,97 @@
+use crate::command::command_function;
+use crate::diagnostic::Span;
 use crate::environment::Environment;
-use crate::expr::{CallableImpl, LiteralValue, LoxFunctionImpl, NativeFunctionImpl};
-use crate::scanner::Token;
+use crate::error::{ErrorCode, ValkyrieError};
+use crate::expr::{
+    call, caught_value, check_arity, run_tail_call, thrown_error, CallableImpl, Expr, LiteralValue,
+    LoxFunctionImpl,
+};
+use crate::limits::Limits;
+use crate::module::{import, Modules};
+use crate::output::Output;
+use crate::sandbox::Sandbox;
 use crate::stmt::Stmt;
+use std::cell::RefCell;
 use std::collections::HashMap;
-use std::process::Command;
+use std::path::Path;
 use std::rc::Rc;
 
+// Why a list of statements stopped running before its end, it unwinds through every enclosing
+// block until the loop or function that handles it
+#[derive(Clone)]
+pub enum ControlFlow {
+    Break,
+    Continue,
+    Return(LiteralValue),
+    // A function returning the result of a call to another one, the function that is returning
+    // makes the call once its own frame is gone
+    TailCall {
+        function: LoxFunctionImpl,
+        arguments: Vec<LiteralValue>,
+        call_span: Span,
+    },
+}
+
 pub struct Interpreter {
-    pub specials: HashMap<String, LiteralValue>,
     pub environment: Environment,
+    // Only the interpreters of whole files have them, function bodies cannot import
+    modules: Option<Rc<RefCell<Modules>>>,
 }
 
 impl Interpreter {
     pub fn new() -> Self {
         Self {
-            specials: HashMap::new(),
             environment: Environment::new(HashMap::new()),
+            modules: Some(Rc::new(RefCell::new(Modules::default()))),
+        }
+    }
+
+    // Imports are resolved relative to the file being run
+    pub fn set_main_file(&mut self, path: &Path) {
+        if let Some(modules) = &self.modules {
+            modules.borrow_mut().set_main_file(path);
+        }
+    }
+
+    // Globals defined from Rust are also there in the files the program imports
+    pub fn define_global(&mut self, name: &str, value: LiteralValue) {
+        if let Some(modules) = &self.modules {
+            modules.borrow_mut().define(name, value.clone());
         }
+        self.environment
+            .global_scope()
+            .borrow_mut()
+            .insert(name.to_string(), value);
     }
 
-    pub fn resolve(&mut self, locals: HashMap<usize, usize>) {
+    pub fn resolve(&mut self, locals: HashMap<usize, (usize, usize)>) {
         self.environment.resolve(locals);
     }
 
+    pub fn set_max_depth(&mut self, max_depth: usize) {
+        self.environment.set_max_depth(max_depth);
+    }
+
+    pub fn set_max_stack(&mut self, max_stack: usize) {
+        self.environment.set_max_stack(max_stack);
+    }
+
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.environment.set_limits(limits);
+    }
+
+    pub fn set_sandbox(&mut self, sandbox: Sandbox) {
+        self.environment.set_sandbox(sandbox);
+    }
+
+    pub fn set_output(&mut self, output: impl Output + 'static) {
+        self.environment.set_output(output);
+    }
+
     pub fn with_env(env: Environment) -> Self {
         Self {
-            specials: HashMap::new(),
             environment: env,
+            modules: None,
         }
     }
 
@@ -34,189 +99,355 @@ impl Interpreter {
     pub fn for_anon(parent: Environment) -> Self {
         let env = parent.enclose();
         Self {
-            specials: HashMap::new(),
             environment: env,
+            modules: None,
         }
     }
 
-    pub fn interpret(&mut self, stmts: Vec<&Stmt>) -> Result<(), String> {
+    // Runs a whole program, or a line of the prompt, with the full budget of the limits
+    pub fn interpret(&mut self, stmts: Vec<&Stmt>) -> Result<(), ValkyrieError> {
+        self.environment.budget().start();
+        self.environment.mark_stack();
+        // The resolver makes sure that break, continue and return only appear inside loops and
+        // functions
+        self.execute(stmts)?;
+        Ok(())
+    }
+
+    pub fn execute<'a>(
+        &mut self,
+        stmts: impl IntoIterator<Item = &'a Stmt>,
+    ) -> Result<Option<ControlFlow>, ValkyrieError> {
         for stmt in stmts {
-            match stmt {
-                Stmt::Expression { expression } => {
-                    expression.evaluate(self.environment.clone())?;
-                }
-                Stmt::Print { expression } => {
-                    let value = expression.evaluate(self.environment.clone())?;
-                    println!("{}", value.to_string());
-                }
-                Stmt::Var { name, initializer } => {
-                    let value = initializer.evaluate(self.environment.clone())?;
-                    self.environment.define(name.lexeme.clone(), value);
-                }
-                Stmt::Block { statements } => {
-                    let new_environment = self.environment.enclose();
-
-                    //     Environment::new();
-                    // new_environment.enclosing = Some(Box::new(self.environment.clone()));
-                    let old_environment = self.environment.clone();
-                    self.environment = new_environment;
-                    let block_result =
-                        self.interpret((*statements).iter().map(|b| b.as_ref()).collect());
-                    self.environment = old_environment;
-                    // self.environment = self.environment.enclosing.unwrap();
-                    block_result?;
+            if let Some(flow) = self.execute_stmt(stmt)? {
+                return Ok(Some(flow));
+            }
+        }
+
+        Ok(None)
+    }
+
+    fn execute_stmt(&mut self, stmt: &Stmt) -> Result<Option<ControlFlow>, ValkyrieError> {
+        match stmt {
+            Stmt::Expression {
+                span: _,
+                expression,
+            } => {
+                expression.evaluate(&self.environment)?;
+            }
+            Stmt::Print {
+                span: _,
+                expression,
+            } => {
+                let value = expression.evaluate(&self.environment)?;
+                self.environment
+                    .output()
+                    .borrow_mut()
+                    .print(&value.to_string());
+            }
+            Stmt::Var {
+                span: _,
+                name,
+                initializer,
+            } => {
+                let value = initializer.evaluate(&self.environment)?;
+                self.environment.define(name.name(), value);
+            }
+            Stmt::Block {
+                span: _,
+                statements,
+            } => {
+                let new_environment = self.environment.enclose();
+                let old_environment = std::mem::replace(&mut self.environment, new_environment);
+                let block_result = self.execute(statements.iter().map(|b| b.as_ref()));
+                self.environment = old_environment;
+                if let Some(flow) = block_result? {
+                    return Ok(Some(flow));
                 }
-                Stmt::Class {
-                    name,
-                    methods,
-                    superclass,
-                } => {
-                    let mut methods_map = HashMap::new();
-
-                    // Insert the methods of the superclass into the methods of this class
-                    let superclass_value;
-                    if let Some(superclass) = superclass {
-                        let superclass = superclass.evaluate(self.environment.clone())?;
-                        if let LiteralValue::LoxClass { .. } = superclass {
-                            superclass_value = Some(Box::new(superclass));
-                        } else {
-                            return Err(format!(
-                                "Superclass must be a class, not {}",
-                                superclass.to_type()
-                            ));
-                        }
+            }
+            Stmt::Class {
+                span: _,
+                name,
+                methods,
+                superclass,
+            } => {
+                let mut methods_map = HashMap::new();
+
+                // Insert the methods of the superclass into the methods of this class
+                let superclass_value;
+                if let Some(superclass_expr) = superclass {
+                    let superclass = superclass_expr.evaluate(&self.environment)?;
+                    if let LiteralValue::LoxClass { .. } = superclass {
+                        superclass_value = Some(Box::new(superclass));
                     } else {
-                        superclass_value = None;
+                        return Err(ValkyrieError::runtime(
+                            ErrorCode::InvalidSuperclass,
+                            format!("Superclass must be a class, not {}", superclass.to_type()),
+                            superclass_expr.get_span(),
+                        ));
                     }
+                } else {
+                    superclass_value = None;
+                }
 
-                    self.environment
-                        .define(name.lexeme.clone(), LiteralValue::Null);
+                self.environment.define(name.name(), LiteralValue::Null);
 
-                    self.environment = self.environment.enclose();
-                    if let Some(sc) = superclass_value.clone() {
-                        self.environment.define("super".to_string(), *sc);
-                    }
+                self.environment = self.environment.enclose();
+                if let Some(sc) = superclass_value.clone() {
+                    self.environment.define("super", *sc);
+                }
 
-                    for method in methods {
-                        if let Stmt::Function {
-                            name,
-                            params: _,
-                            body: _,
-                        } = method.as_ref()
-                        {
-                            let function = self.make_function(method);
-                            methods_map.insert(name.lexeme.clone(), function);
-                        } else {
-                            panic!(
-                                "Something that was not a function was in the methods of a class"
-                            );
-                        }
+                for method in methods {
+                    if let Stmt::Function {
+                        span: _,
+                        name: method_name,
+                        params: _,
+                        body: _,
+                    } = method.as_ref()
+                    {
+                        let mut function = self.make_function(method);
+                        function.class_name = Some(name.name().to_string());
+                        methods_map.insert(method_name.name().to_string(), function);
+                    } else {
+                        let enclosing = self.environment.enclosing.clone().unwrap();
+                        self.environment = (*enclosing).clone();
+                        return Err(ValkyrieError::runtime(
+                            ErrorCode::TypeMismatch,
+                            "Methods of a class must be functions".to_string(),
+                            method.get_span(),
+                        ));
                     }
+                }
 
-                    let klass = LiteralValue::LoxClass {
-                        name: name.lexeme.clone(),
-                        methods: methods_map,
-                        superclass: superclass_value,
-                    };
+                let klass = LiteralValue::LoxClass {
+                    name: name.name().to_string(),
+                    methods: methods_map,
+                    superclass: superclass_value,
+                };
 
-                    if !self.environment.assign_global(&name.lexeme, klass) {
-                        return Err(format!("Class definition failed for {}", name.lexeme));
-                    }
+                if !self.environment.assign_global(name.name(), klass) {
+                    let enclosing = self.environment.enclosing.clone().unwrap();
+                    self.environment = (*enclosing).clone();
+                    return Err(ValkyrieError::runtime(
+                        ErrorCode::UndefinedVariable,
+                        format!("Class definition failed for {}", name.name()),
+                        name.span,
+                    )
+                    .with_note("Classes can only be declared at the top level".to_string()));
+                }
 
-                    self.environment = *self.environment.enclosing.clone().unwrap();
+                let enclosing = self.environment.enclosing.clone().unwrap();
+                self.environment = (*enclosing).clone();
+            }
+            Stmt::IfStmt {
+                span: _,
+                predicate,
+                then,
+                els,
+            } => {
+                let truth_value = predicate.evaluate(&self.environment)?;
+                let flow = if truth_value.is_truthy() == LiteralValue::True {
+                    self.execute_stmt(then)?
+                } else if let Some(els_stmt) = els {
+                    self.execute_stmt(els_stmt)?
+                } else {
+                    None
+                };
+                if flow.is_some() {
+                    return Ok(flow);
                 }
-                Stmt::IfStmt {
-                    predicate,
-                    then,
-                    els,
-                } => {
-                    let truth_value = predicate.evaluate(self.environment.clone())?;
-                    if truth_value.is_truthy() == LiteralValue::True {
-                        let statements = vec![then.as_ref()];
-                        self.interpret(statements)?;
-                    } else if let Some(els_stmt) = els {
-                        let statements = vec![els_stmt.as_ref()];
-                        self.interpret(statements)?;
+            }
+            Stmt::WhileStmt {
+                span,
+                condition,
+                body,
+                increment,
+            } => {
+                let mut flag = condition.evaluate(&self.environment)?;
+                while flag.is_truthy() == LiteralValue::True {
+                    match self.execute_stmt(body)? {
+                        Some(ControlFlow::Break) => break,
+                        Some(ControlFlow::Continue) | None => (),
+                        flow => return Ok(flow),
                     }
+                    if let Some(increment) = increment {
+                        increment.evaluate(&self.environment)?;
+                    }
+                    // Counted where the VM jumps back to the condition
+                    self.environment.budget().step(*span)?;
+                    flag = condition.evaluate(&self.environment)?;
+                }
+            }
+            Stmt::Break { .. } => return Ok(Some(ControlFlow::Break)),
+            Stmt::Continue { .. } => return Ok(Some(ControlFlow::Continue)),
+            Stmt::Function {
+                span: _,
+                name,
+                params: _,
+                body: _,
+            } => {
+                let callable = self.make_function(stmt);
+                let fun = LiteralValue::Callable(CallableImpl::LoxFunction(callable));
+                self.environment.define(name.name(), fun);
+            }
+            Stmt::CmdFunction { span: _, name, cmd } => {
+                let fun_val = command_function(name.name(), cmd, self.environment.sandbox());
+                self.environment.define(name.name(), fun_val);
+            }
+            Stmt::ReturnStmt {
+                span: _,
+                keyword: _,
+                value,
+            } => {
+                let eval_val;
+                if let Some(Expr::Call {
+                    id: _,
+                    span,
+                    callee,
+                    paren: _,
+                    arguments,
+                }) = value
+                {
+                    return self.tail_call(callee, arguments, *span);
+                } else if let Some(value) = value {
+                    eval_val = value.evaluate(&self.environment)?;
+                } else {
+                    eval_val = LiteralValue::Null;
                 }
-                Stmt::WhileStmt { condition, body } => {
-                    let mut flag = condition.evaluate(self.environment.clone())?;
-                    while flag.is_truthy() == LiteralValue::True {
-                        let statements = vec![body.as_ref()];
-                        self.interpret(statements)?;
-                        flag = condition.evaluate(self.environment.clone())?;
+                return Ok(Some(ControlFlow::Return(eval_val)));
+            }
+            Stmt::Throw {
+                span,
+                keyword: _,
+                value,
+            } => {
+                let value = value.evaluate(&self.environment)?;
+                return Err(thrown_error(value, *span));
+            }
+            Stmt::Try {
+                span: _,
+                body,
+                catch,
+                finally,
+            } => {
+                let result = match (self.execute_guarded(body), catch) {
+                    (Err(err), Some((name, catch_body))) if err.is_catchable() => {
+                        let new_environment = self.environment.enclose();
+                        let old_environment =
+                            std::mem::replace(&mut self.environment, new_environment);
+                        self.environment.define(name.name(), caught_value(err));
+                        let catch_result = self.execute_guarded(catch_body);
+                        self.environment = old_environment;
+                        catch_result
                     }
+                    (result, _) => result,
+                };
+                // Errors that cannot be caught do not run finally blocks either
+                if result.as_ref().is_err_and(|err| !err.is_catchable()) {
+                    return result;
                 }
-                Stmt::Function {
-                    name,
-                    params: _,
-                    body: _,
-                } => {
-                    let callable = self.make_function(stmt);
-                    let fun = LiteralValue::Callable(CallableImpl::LoxFunction(callable));
-                    self.environment.define(name.lexeme.clone(), fun);
+                // A break, continue or return out of the finally block wins over how the rest
+                // of the statement ended
+                if let Some(finally) = finally {
+                    if let Some(flow) = self.execute_stmt(finally)? {
+                        return Ok(Some(flow));
+                    }
                 }
-                Stmt::CmdFunction { name, cmd } => {
-                    // Return a callable that runs a shell command, captures the stdout and returns
-                    // it in a String
-
-                    let cmd = cmd.clone();
-                    let local_fn = move |_args: &Vec<LiteralValue>| {
-                        let cmd = cmd.clone();
-                        let parts = cmd.split(" ").collect::<Vec<&str>>();
-                        let mut command = Command::new(parts[0].replace("\"", ""));
-                        for part in parts[1..].iter() {
-                            command.arg(part.replace("\"", ""));
-                        }
-                        let output = command.output().expect("Failed to run command");
-
-
-                        return LiteralValue::StringValue(
-                            std::str::from_utf8(output.stdout.as_slice())
-                                .unwrap()
-                                .to_string(),
-                        );
+                return result;
+            }
+            Stmt::Import { span, path, name } => {
+                let modules = self
+                    .modules
+                    .clone()
+                    .expect("The resolver only allows imports at the top level");
+                let sandbox = self.environment.sandbox();
+                let module = import(&modules, &sandbox.borrow(), path, *span, |stmts, locals| {
+                    let mut interpreter = Interpreter {
+                        environment: self.environment.for_module(modules.borrow().globals()),
+                        modules: Some(modules.clone()),
                     };
-
-                    let fun_val =
-                        LiteralValue::Callable(CallableImpl::NativeFunction(NativeFunctionImpl {
-                            name: name.lexeme.clone(),
-                            arity: 0,
-                            fun: Rc::new(local_fn),
-                        }));
-                    self.environment.define(name.lexeme.clone(), fun_val);
-                }
-                Stmt::ReturnStmt { keyword: _, value } => {
-                    let eval_val;
-                    if let Some(value) = value {
-                        eval_val = value.evaluate(self.environment.clone())?;
-                    } else {
-                        eval_val = LiteralValue::Null;
+                    interpreter.resolve(locals);
+                    interpreter.execute(stmts)?;
+                    Ok(interpreter.environment.globals())
+                })?;
+                match name {
+                    Some(name) => self
+                        .environment
+                        .define(name.name(), LiteralValue::Module(module)),
+                    None => {
+                        for (name, value) in &module.exports {
+                            self.environment.define(name, value.clone());
+                        }
                     }
-                    self.specials.insert("return".to_string(), eval_val);
                 }
-            };
+            }
+        };
+
+        Ok(None)
+    }
+
+    // Runs a block of a try statement, a call in tail position is made right away so that its
+    // errors reach the catch and finally blocks
+    fn execute_guarded(&mut self, stmt: &Stmt) -> Result<Option<ControlFlow>, ValkyrieError> {
+        match self.execute_stmt(stmt)? {
+            Some(ControlFlow::TailCall {
+                function,
+                arguments,
+                call_span,
+            }) => {
+                let value = run_tail_call(&function, arguments, &self.environment, call_span)?;
+                Ok(Some(ControlFlow::Return(value)))
+            }
+            flow => Ok(flow),
         }
+    }
 
-        Ok(())
+    // Only calls to Valkyrie functions are unwound, anything else is called right away
+    fn tail_call(
+        &mut self,
+        callee: &Expr,
+        arguments: &[Expr],
+        call_span: Span,
+    ) -> Result<Option<ControlFlow>, ValkyrieError> {
+        let callable = callee.evaluate(&self.environment)?;
+        if let LiteralValue::Callable(CallableImpl::LoxFunction(function)) = callable {
+            check_arity(&function, arguments.len(), call_span)?;
+            let mut values = Vec::with_capacity(arguments.len());
+            for argument in arguments {
+                values.push(argument.evaluate(&self.environment)?);
+            }
+            Ok(Some(ControlFlow::TailCall {
+                function,
+                arguments: values,
+                call_span,
+            }))
+        } else {
+            let value = call(callable, arguments, &self.environment, call_span)?;
+            Ok(Some(ControlFlow::Return(value)))
+        }
     }
 
     fn make_function(&self, fn_stmt: &Stmt) -> LoxFunctionImpl {
-        if let Stmt::Function { name, params, body } = fn_stmt {
+        if let Stmt::Function {
+            span: _,
+            name,
+            params,
+            body,
+        } = fn_stmt
+        {
             let arity = params.len();
-            let params: Vec<Token> = params.iter().map(|t| (*t).clone()).collect();
-            let body: Vec<Box<Stmt>> = body.iter().map(|b| (*b).clone()).collect();
-            let name_clone = name.lexeme.clone();
+            let name_clone = name.name().to_string();
 
-            // TODO: Don't clone the whole environment, just the captured variables
+            // Cloning the environment only copies the handles to its scopes
             let parent_env = self.environment.clone();
 
             let callable_impl = LoxFunctionImpl {
                 name: name_clone,
                 arity,
                 parent_env,
-                params,
-                body,
+                params: params.clone(),
+                body: body.clone(),
+                class_name: None,
             };
 
             callable_impl
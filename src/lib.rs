@@ -0,0 +1,27 @@
+mod chunk;
+mod command;
+mod compiler;
+pub mod diagnostic;
+pub mod engine;
+mod environment;
+pub mod error;
+pub mod expr;
+mod interpreter;
+pub mod limits;
+mod module;
+pub mod natives;
+pub mod output;
+mod parser;
+mod resolver;
+pub mod runic;
+pub mod sandbox;
+mod scanner;
+mod stdlib;
+mod stmt;
+mod tests;
+mod vm;
+
+pub use crate::engine::{Backend, Options, Valkyrie};
+pub use crate::error::{render_all, ErrorCode, ValkyrieError};
+pub use crate::expr::LiteralValue;
+pub use crate::output::{Capture, Output};
@@ -0,0 +1,222 @@
+use crate::diagnostic::Span;
+use crate::error::{ErrorCode, ValkyrieError};
+use crate::expr::LiteralValue;
+use std::cell::Cell;
+use std::time::{Duration, Instant};
+
+// How much work a program may do before it is stopped, so that one that never ends cannot hang the
+// interpreter. None is no limit
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Limits {
+    // Loop iterations and calls, which every program that runs for long has to make
+    pub max_steps: Option<u64>,
+    pub timeout: Option<Duration>,
+    // The most bytes of a string or elements of an array or map
+    pub max_size: Option<usize>,
+}
+
+// The clock is only read every so many steps
+const CLOCK_INTERVAL: u64 = 1024;
+
+thread_local! {
+    // The size limit of the run whose native is being called. Natives have no budget of their
+    // own, they check the values they are about to build against this with reserve
+    static NATIVE_MAX_SIZE: Cell<Option<usize>> = const { Cell::new(None) };
+}
+
+// Counts the steps of a run against the limits. Both backends count the same steps, so a program
+// stops at the same point on either
+pub struct Budget {
+    limits: Cell<Limits>,
+    steps: Cell<u64>,
+    // The step at which the limits are checked next, so that a step is one compare
+    next_check: Cell<u64>,
+    started: Cell<Instant>,
+}
+
+impl Default for Budget {
+    fn default() -> Self {
+        Self {
+            limits: Cell::new(Limits::default()),
+            steps: Cell::new(0),
+            next_check: Cell::new(u64::MAX),
+            started: Cell::new(Instant::now()),
+        }
+    }
+}
+
+impl Budget {
+    pub fn set_limits(&self, limits: Limits) {
+        self.limits.set(limits);
+        self.start();
+    }
+
+    // Every run of a program gets the whole budget, each line of the prompt is a run
+    pub fn start(&self) {
+        self.steps.set(0);
+        self.started.set(Instant::now());
+        self.schedule();
+    }
+
+    fn schedule(&self) {
+        let limits = self.limits.get();
+        let mut next = limits
+            .max_steps
+            .map_or(u64::MAX, |max| max.saturating_add(1));
+        if limits.timeout.is_some() {
+            next = next.min(self.steps.get() + CLOCK_INTERVAL);
+        }
+        self.next_check.set(next);
+    }
+
+    pub fn step(&self, span: Span) -> Result<(), ValkyrieError> {
+        let steps = self.steps.get() + 1;
+        self.steps.set(steps);
+        if steps < self.next_check.get() {
+            return Ok(());
+        }
+        self.check(span)
+    }
+
+    // Once a limit is exceeded, every step after it fails too until the next run starts
+    fn check(&self, span: Span) -> Result<(), ValkyrieError> {
+        let limits = self.limits.get();
+        if let Some(max_steps) = limits.max_steps {
+            if self.steps.get() > max_steps {
+                return Err(ValkyrieError::runtime(
+                    ErrorCode::StepLimitExceeded,
+                    format!(
+                        "Step limit exceeded, more than {} loop iterations and calls were made",
+                        max_steps
+                    ),
+                    span,
+                )
+                .with_note("Run with --max-steps <n> to allow more".to_string()));
+            }
+        }
+        if let Some(timeout) = limits.timeout {
+            if self.started.get().elapsed() > timeout {
+                return Err(ValkyrieError::runtime(
+                    ErrorCode::TimeLimitExceeded,
+                    format!(
+                        "Time limit exceeded, the program ran for more than {} ms",
+                        timeout.as_millis()
+                    ),
+                    span,
+                )
+                .with_note("Run with --timeout <ms> to allow more".to_string()));
+            }
+        }
+        self.schedule();
+        Ok(())
+    }
+
+    // Only the value itself is measured, not the values inside it
+    pub fn check_size(&self, value: &LiteralValue, span: Span) -> Result<(), ValkyrieError> {
+        let max_size = match self.limits.get().max_size {
+            Some(max_size) => max_size,
+            None => return Ok(()),
+        };
+        let (size, unit) = match value {
+            LiteralValue::StringValue(s) => (s.len(), "bytes"),
+            LiteralValue::Array(xs) => (xs.borrow().len(), "elements"),
+            LiteralValue::Map(entries) => (entries.borrow().len(), "entries"),
+            _ => return Ok(()),
+        };
+        if size <= max_size {
+            return Ok(());
+        }
+        Err(size_exceeded(value.to_type(), size, unit, max_size).with_span(span))
+    }
+
+    // Natives called through here can reserve the size of a value before they build it
+    pub fn call_native<T>(&self, native: impl FnOnce() -> T) -> T {
+        let previous = NATIVE_MAX_SIZE.replace(self.limits.get().max_size);
+        let result = native();
+        NATIVE_MAX_SIZE.set(previous);
+        result
+    }
+}
+
+// For natives, fails before a value of size units is built when it would be over the size limit.
+// The span is the one of the call, filled in by the caller
+pub fn reserve(type_name: &str, size: usize, unit: &str) -> Result<(), ValkyrieError> {
+    match NATIVE_MAX_SIZE.get() {
+        Some(max_size) if size > max_size => Err(size_exceeded(type_name, size, unit, max_size)),
+        _ => Ok(()),
+    }
+}
+
+fn size_exceeded(type_name: &str, size: usize, unit: &str, max_size: usize) -> ValkyrieError {
+    ValkyrieError::runtime(
+        ErrorCode::SizeLimitExceeded,
+        format!(
+            "Size limit exceeded, the {} has {} {}, more than the limit of {}",
+            type_name, size, unit, max_size
+        ),
+        Span::default(),
+    )
+    .with_note("Run with --max-size <n> to allow larger values".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn steps_past_the_limit_keep_failing() {
+        let budget = Budget::default();
+        budget.set_limits(Limits {
+            max_steps: Some(3),
+            ..Limits::default()
+        });
+        for _ in 0..3 {
+            budget.step(Span::default()).unwrap();
+        }
+        for _ in 0..2 {
+            let err = budget.step(Span::default()).unwrap_err();
+            assert_eq!(err.code(), ErrorCode::StepLimitExceeded);
+            assert!(!err.is_catchable());
+        }
+        budget.start();
+        assert!(budget.step(Span::default()).is_ok());
+    }
+
+    #[test]
+    fn timeout_is_checked_between_steps() {
+        let budget = Budget::default();
+        budget.set_limits(Limits {
+            timeout: Some(Duration::ZERO),
+            ..Limits::default()
+        });
+        std::thread::sleep(Duration::from_millis(1));
+        let err = (0..CLOCK_INTERVAL)
+            .find_map(|_| budget.step(Span::default()).err())
+            .unwrap();
+        assert_eq!(err.code(), ErrorCode::TimeLimitExceeded);
+    }
+
+    #[test]
+    fn sizes_are_checked_against_the_limit() {
+        let budget = Budget::default();
+        budget
+            .check_size(&LiteralValue::StringValue("x".repeat(100)), Span::default())
+            .unwrap();
+        budget.set_limits(Limits {
+            max_size: Some(2),
+            ..Limits::default()
+        });
+        let xs = LiteralValue::new_array(vec![LiteralValue::Null; 2]);
+        assert!(budget.check_size(&xs, Span::default()).is_ok());
+        let err = budget
+            .check_size(
+                &LiteralValue::StringValue("abc".to_string()),
+                Span::default(),
+            )
+            .unwrap_err();
+        assert_eq!(
+            err.message(),
+            "Size limit exceeded, the String has 3 bytes, more than the limit of 2"
+        );
+    }
+}
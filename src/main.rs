@@ -1,55 +1,26 @@
-mod environment;
-mod expr;
-mod interpreter;
-mod parser;
-mod resolver;
-mod scanner;
-mod stmt;
-mod tests;
-use crate::interpreter::*;
-use crate::parser::*;
-use crate::resolver::*;
-use crate::scanner::*;
-use std::fs;
-use std::fs::File;
+use std::fs::{self, File};
 use std::io::Cursor;
 use std::io::{self, BufRead, Write};
 use std::path::Path;
 use std::process::exit;
+use valkyrie_interpreter::runic;
+use valkyrie_interpreter::{render_all, Options, Valkyrie, ValkyrieError};
 use zip::ZipArchive;
 
-pub fn run_file(path: &str) -> Result<(), String> {
-    // let mut interpreter = Interpreter::new();
-    match fs::read_to_string(path) {
-        Err(msg) => return Err(msg.to_string()),
-        Ok(contents) => return run_string(&contents),
-    }
-}
-
-pub fn run_string(contents: &str) -> Result<(), String> {
-    let mut interpreter = Interpreter::new();
-
-    run(&mut interpreter, contents)
+pub fn run_file(path: &str, options: &Options) -> Result<(), Vec<ValkyrieError>> {
+    Valkyrie::new(options).run_file(Path::new(path))
 }
 
-fn run(interpreter: &mut Interpreter, contents: &str) -> Result<(), String> {
-    let mut scanner = Scanner::new(contents);
-    let tokens = scanner.scan_tokens()?;
-
-    let mut parser = Parser::new(tokens);
-    let stmts = parser.parse()?;
-
-    let resolver = Resolver::new();
-    let locals = resolver.resolve(&stmts.iter().collect())?;
-
-    interpreter.resolve(locals);
-
-    interpreter.interpret(stmts.iter().collect())?;
-    return Ok(());
+pub fn run_string(contents: &str, options: &Options) -> Result<(), Vec<ValkyrieError>> {
+    Valkyrie::new(options).eval(contents)
 }
 
-fn run_prompt(stdout: &mut io::Stdout, stdin: &mut io::Stdin) -> Result<(), String> {
-    let mut interpreter = Interpreter::new();
+fn run_prompt(
+    stdout: &mut io::Stdout,
+    stdin: &mut io::Stdin,
+    options: &Options,
+) -> Result<(), String> {
+    let mut engine = Valkyrie::new(options);
     loop {
         println!("Running in prompt mode - simply press enter to exit");
         print!("> ");
@@ -74,9 +45,9 @@ fn run_prompt(stdout: &mut io::Stdout, stdin: &mut io::Stdin) -> Result<(), Stri
         }
 
         println!("ECHO: {}", buffer);
-        match run(&mut interpreter, &buffer) {
+        match engine.eval(&buffer) {
             Ok(_) => (),
-            Err(msg) => println!("{}", msg),
+            Err(errs) => println!("{}", render_all(&errs, &buffer)),
         }
     }
 }
@@ -84,12 +55,11 @@ fn run_prompt(stdout: &mut io::Stdout, stdin: &mut io::Stdin) -> Result<(), Stri
 fn run_prompt_runic(
     stdout: &mut io::Stdout,
     stdin: &mut io::Stdin,
-    python_script: &[u8],
+    options: &Options,
 ) -> Result<(), String> {
-    let mut interpreter = Interpreter::new();
+    let mut engine = Valkyrie::new(options);
     loop {
         println!("Running in prompt mode in runic(Rᚢᚾᛁᚲ) - simply press enter to exit");
-        println!("WARNING: In this mode custom characters are not supported ()");
         print!("> ");
         match stdout.flush() {
             Ok(_) => (),
@@ -111,50 +81,10 @@ fn run_prompt_runic(
             Err(_) => return Err("Couldnt read line".to_string()),
         }
 
-        // Call Python function to convert the input
-        let converted_buffer = match convert_input_with_python(&buffer, python_script) {
-            Ok(result) => result,
-            Err(msg) => {
-                println!("Error during conversion: {}", msg);
-                continue;
-            }
-        };
-
-        println!("Converted: {}", converted_buffer);
-        match run(&mut interpreter, &converted_buffer) {
+        match engine.eval(&buffer) {
             Ok(_) => (),
-            Err(msg) => println!("{}", msg),
-        }
-    }
-}
-
-fn convert_input_with_python(input: &str, python_script: &[u8]) -> Result<String, String> {
-    let mut temp_file = std::env::temp_dir();
-    temp_file.push("runic-translator.py");
-
-    if let Err(_) = fs::write(&temp_file, python_script) {
-        return Err("Could not write temporary Python script".to_string());
-    }
-
-    let output = std::process::Command::new("python")
-        .arg(temp_file.to_str().unwrap())
-        .arg("-s")
-        .arg(input.to_string()) // Pass the input to the Python script
-        .output();
-
-    match output {
-        Ok(output) => {
-            if output.status.success() {
-                let converted_string = String::from_utf8_lossy(&output.stdout).trim().to_string();
-                Ok(converted_string)
-            } else {
-                Err(format!(
-                    "Python script failed with error: {}",
-                    String::from_utf8_lossy(&output.stderr)
-                ))
-            }
+            Err(errs) => println!("{}", render_all(&errs, &buffer)),
         }
-        Err(e) => Err(format!("Failed to execute Python script: {}", e)),
     }
 }
 
@@ -177,38 +107,39 @@ fn get_user_input(stdout: &mut io::Stdout) -> String {
     buffer
 }
 
-fn run_file_option(stdout: &mut io::Stdout, python_script: &[u8], file_path: Option<String>) {
+// Returns the exit code of the run, the one of its first error if it failed
+fn run_file_option(stdout: &mut io::Stdout, file_path: Option<String>, options: &Options) -> i32 {
     let file_path = match file_path {
         Some(path) => path,
         None => get_file_path(stdout),
     };
 
-    let valkyrie: String;
-    match file_path {
-        ref path if path.ends_with(".runic") => {
-            translator_python_script(path.clone(), python_script);
-            valkyrie = file_path.replace(".runic", ".valkyrie");
-        }
-        ref path if path.ends_with(".valkyrie") => {
-            valkyrie = path.clone();
-        }
-        _ => {
-            println!("Error: File path must end with .runic or .valkyrie");
-            return;
-        }
+    if !file_path.ends_with(".runic") && !file_path.ends_with(".valkyrie") {
+        println!("Error: File path must end with .runic or .valkyrie");
+        return 64;
     }
 
-    match run_file(&valkyrie.trim()) {
-        Ok(_) => println!("File executed successfully"),
-        Err(msg) => println!("ERROR:\n{}", msg),
+    let file_path = file_path.trim();
+    match run_file(file_path, options) {
+        Ok(_) => {
+            println!("File executed successfully");
+            0
+        }
+        Err(errs) => {
+            // The errors point into the file, one that could not be read has no source
+            let source = fs::read_to_string(file_path).unwrap_or_default();
+            println!("ERROR:\n{}", render_all(&errs, &source));
+            errs[0].exit_code()
+        }
     }
 }
 
-fn run_string_option(stdout: &mut io::Stdout) {
+// Returns the exit code of the run like run_file_option
+fn run_string_option(stdout: &mut io::Stdout, options: &Options) -> i32 {
     print!("Enter string to run: ");
     if let Err(_) = stdout.flush() {
         println!("Could not flush stdout");
-        return;
+        return 74;
     }
 
     let mut input_string = String::new();
@@ -216,44 +147,53 @@ fn run_string_option(stdout: &mut io::Stdout) {
     let mut handle = stdin.lock();
     if let Err(_) = handle.read_line(&mut input_string) {
         println!("Could not read line");
-        return;
+        return 74;
     }
 
-    match run_string(input_string.trim()) {
-        Ok(_) => println!("String executed successfully"),
-        Err(msg) => println!("ERROR:\n{}", msg),
+    let input_string = input_string.trim();
+    match run_string(input_string, options) {
+        Ok(_) => {
+            println!("String executed successfully");
+            0
+        }
+        Err(errs) => {
+            println!("ERROR:\n{}", render_all(&errs, input_string));
+            errs[0].exit_code()
+        }
     }
 }
 
-fn run_prompt_option(stdout: &mut io::Stdout) {
+fn run_prompt_option(stdout: &mut io::Stdout, options: &Options) {
     let mut stdin = io::stdin();
-    match run_prompt(stdout, &mut stdin) {
+    match run_prompt(stdout, &mut stdin, options) {
         Ok(_) => println!("Exited prompt mode"),
         Err(msg) => println!("ERROR\n{}", msg),
     }
 }
 
-fn run_prompt_option_runic(stdout: &mut io::Stdout, python_script: &[u8]) {
+fn run_prompt_option_runic(stdout: &mut io::Stdout, options: &Options) {
     let mut stdin = io::stdin();
-    match run_prompt_runic(stdout, &mut stdin, python_script) {
+    match run_prompt_runic(stdout, &mut stdin, options) {
         Ok(_) => println!("Exited prompt mode"),
         Err(msg) => println!("ERROR\n{}", msg),
     }
 }
 
-fn translate_only_option(stdout: &mut io::Stdout, python_script: &[u8], file_path: Option<String>) {
+fn translate_only_option(stdout: &mut io::Stdout, file_path: Option<String>) {
     let file_path = match file_path {
         Some(path) => path,
         None => get_file_path(stdout),
     };
 
-    if !file_path.ends_with(".runic") {
-        println!("Error: File path must end with .runic");
+    if !file_path.ends_with(".runic") && !file_path.ends_with(".valkyrie") {
+        println!("Error: File path must end with .runic or .valkyrie");
         return;
     }
 
-    translator_python_script(file_path.clone(), python_script);
-    println!("File translated to Valkyrie format");
+    match runic::translate_file(&file_path) {
+        Ok(new_path) => println!("File translated to {}", new_path),
+        Err(msg) => println!("ERROR:\n{}", msg),
+    }
 }
 
 fn get_file_path(stdout: &mut io::Stdout) -> String {
@@ -272,41 +212,13 @@ fn get_file_path(stdout: &mut io::Stdout) -> String {
     file_path.trim().to_string()
 }
 
-fn translator_python_script(file_path: String, python_script: &[u8]) {
-    let mut temp_file = std::env::temp_dir();
-    temp_file.push("runic-translator.py");
-
-    if let Err(_) = fs::write(&temp_file, python_script) {
-        println!("Could not write temporary Python script");
-        return;
-    }
-
-    let output = std::process::Command::new("python")
-        .arg(temp_file.to_str().unwrap())
-        .arg("-f")
-        .arg(file_path.trim())
-        .output()
-        .expect("Failed to execute python script");
-
-    if let Err(_) = fs::remove_file(&temp_file) {
-        println!("Warning: Could not remove temporary Python script");
-    }
-
-    if !output.status.success() {
-        println!(
-            "Python script failed with error: {}",
-            String::from_utf8_lossy(&output.stderr)
-        );
-    }
-}
-
 const WELCOME_MSG: &str = "
                                     𖤍 𖤍  Valkyrie Interpreter 𖤍 𖤍
                             This is a simple interpreter for the Valkyrie language.
                  Valkyrie is a simple language that is designed to be easy to learn and use.
                     It has the capability to run using both runes and Latin characters.
                          It is a dynamically typed language with a simple syntax.
-    The interpreter is written in Rust and uses a Python script to convert Runic code to Valkyrie code.
+           The interpreter is written in Rust and converts Runic code to Valkyrie code itself.
              The interpreter can run Runic code from a file, a string, or in prompt mode.
                         In prompt mode, you can enter Runic code line by line.
               The interpreter can also compile Runic code to Valkyrie code without running it.
@@ -326,12 +238,37 @@ const MENU_OPTIONS: &str = "
 ";
 
 fn main() {
-    let python_script: &[u8] = include_bytes!("py/runic-translator.py");
+    let mut args: Vec<String> = std::env::args().collect();
+
+    // Options go before the other arguments
+    let mut flags = args.split_off(1);
+    let options = match Options::from_flags(&mut flags) {
+        Ok(options) => options,
+        Err(usage) => {
+            println!("{}", usage);
+            return;
+        }
+    };
+    args.extend(flags);
+
+    let interpreter = options.spawn(move |options| run_cli(args, options));
+    match interpreter {
+        Ok(handle) => {
+            if handle.join().is_err() {
+                exit(101);
+            }
+        }
+        Err(err) => {
+            println!("Could not start the interpreter: {}", err);
+            exit(1);
+        }
+    }
+}
+
+fn run_cli(args: Vec<String>, options: Options) {
     let test_folder: &[u8] = include_bytes!("examples/examples.zip");
     // let HELP = include_bytes!("help.txt");
 
-    let args: Vec<String> = std::env::args().collect();
-
     if args.len() > 1 {
         let mut stdout = io::stdout();
         match args[1].as_str() {
@@ -340,29 +277,33 @@ fn main() {
                     println!("Usage: run_file <file_path>");
                     return;
                 }
-                run_file_option(&mut stdout, python_script, Some(args[2].clone()));
+                exit(run_file_option(
+                    &mut stdout,
+                    Some(args[2].clone()),
+                    &options,
+                ));
             }
             "--run_string" => {
                 if args.len() < 3 {
                     println!("Usage: run_string <string>");
                     return;
                 }
-                run_string_option(&mut stdout);
+                exit(run_string_option(&mut stdout, &options));
             }
-            "--run_prompt" => run_prompt_option(&mut stdout),
-            "--run_prompt_runic" => run_prompt_option_runic(&mut stdout, python_script),
+            "--run_prompt" => run_prompt_option(&mut stdout, &options),
+            "--run_prompt_runic" => run_prompt_option_runic(&mut stdout, &options),
             "--translate_file" => {
                 if args.len() < 3 {
                     println!("Usage: translate_file <file_path>");
                     return;
                 }
-                translate_only_option(&mut stdout, python_script, Some(args[2].clone()));
+                translate_only_option(&mut stdout, Some(args[2].clone()));
             }
-            "--run_test" => match run_string(&args[2]) {
+            "--run_test" => match run_string(&args[2], &options) {
                 Ok(_) => exit(0),
-                Err(msg) => {
-                    println!("ERROR:\n{msg}");
-                    exit(1);
+                Err(errs) => {
+                    println!("ERROR:\n{}", render_all(&errs, &args[2]));
+                    exit(errs[0].exit_code());
                 }
             },
             "--help" => run_help(&mut stdout, test_folder),
@@ -378,11 +319,15 @@ fn main() {
         let buffer = get_user_input(&mut stdout);
 
         match buffer.trim() {
-            "1" => run_file_option(&mut stdout, python_script, None),
-            "2" => run_string_option(&mut stdout),
-            "3" => run_prompt_option(&mut stdout),
-            "4" => run_prompt_option_runic(&mut stdout, python_script),
-            "5" => translate_only_option(&mut stdout, python_script, None),
+            "1" => {
+                run_file_option(&mut stdout, None, &options);
+            }
+            "2" => {
+                run_string_option(&mut stdout, &options);
+            }
+            "3" => run_prompt_option(&mut stdout, &options),
+            "4" => run_prompt_option_runic(&mut stdout, &options),
+            "5" => translate_only_option(&mut stdout, None),
             "6" => {
                 run_help(&mut stdout, test_folder);
             }
@@ -474,6 +419,7 @@ fn decompress_examples(test_folder: &[u8]) {
         // Set file permissions if needed
         #[cfg(unix)]
         if let Some(mode) = file.unix_mode() {
+            use std::os::unix::fs::PermissionsExt;
             let _ = std::fs::set_permissions(&outpath, std::fs::Permissions::from_mode(mode));
         }
 
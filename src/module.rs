@@ -0,0 +1,203 @@
+use crate::diagnostic::{add_source, Span};
+use crate::error::{ErrorCode, ValkyrieError};
+use crate::expr::LiteralValue;
+use crate::natives::get_globals;
+use crate::parser::Parser;
+use crate::resolver::Resolver;
+use crate::sandbox::Sandbox;
+use crate::scanner::Scanner;
+use crate::stmt::Stmt;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+// Expression id -> (depth, slot), as the resolver returns them
+type Locals = HashMap<usize, (usize, usize)>;
+
+// Tried in order for an import path without an extension
+const EXTENSIONS: [&str; 2] = ["valkyrie", "runic"];
+
+// The globals a file declared, `import x from "path"` binds x to it
+pub struct Module {
+    pub name: String,
+    pub exports: HashMap<String, LiteralValue>,
+}
+
+// Every file a program imported, shared by the files it imports so that each runs only once
+#[derive(Default)]
+pub struct Modules {
+    // Imports of the program itself are relative to it
+    dir: PathBuf,
+    cache: HashMap<PathBuf, Rc<Module>>,
+    // Files that are running, innermost last. Importing one of them again is circular
+    loading: Vec<PathBuf>,
+    // Defined from Rust, every file has them next to the natives
+    globals: HashMap<String, LiteralValue>,
+}
+
+impl Modules {
+    pub fn define(&mut self, name: &str, value: LiteralValue) {
+        self.globals.insert(name.to_string(), value);
+    }
+
+    // The globals an imported file starts with
+    pub fn globals(&self) -> Rc<RefCell<HashMap<String, LiteralValue>>> {
+        let globals = get_globals();
+        globals.borrow_mut().extend(self.globals.clone());
+        globals
+    }
+
+    pub fn set_main_file(&mut self, path: &Path) {
+        self.dir = path.parent().map(Path::to_path_buf).unwrap_or_default();
+        if let Ok(file) = path.canonicalize() {
+            self.loading = vec![file];
+        }
+    }
+
+    // Relative to the file doing the import
+    fn find(&self, path: &str, span: Span) -> Result<PathBuf, ValkyrieError> {
+        let dir = match self.loading.last().and_then(|file| file.parent()) {
+            Some(dir) => dir,
+            None => &self.dir,
+        };
+        let file = dir.join(path);
+        let candidates = if file.extension().is_some() {
+            vec![file]
+        } else {
+            EXTENSIONS
+                .iter()
+                .map(|extension| file.with_extension(extension))
+                .collect()
+        };
+
+        for candidate in &candidates {
+            if let Ok(file) = candidate.canonicalize() {
+                return Ok(file);
+            }
+        }
+        Err(ValkyrieError::runtime(
+            ErrorCode::ImportFailed,
+            format!("Could not find module {}", path),
+            span,
+        )
+        .with_note(format!(
+            "Looked for {}",
+            candidates
+                .iter()
+                .map(|candidate| candidate.display().to_string())
+                .collect::<Vec<String>>()
+                .join(" and ")
+        )))
+    }
+
+    fn check_cycle(&self, file: &Path, span: Span) -> Result<(), ValkyrieError> {
+        let start = match self.loading.iter().position(|loading| loading == file) {
+            Some(start) => start,
+            None => return Ok(()),
+        };
+        let chain = self.loading[start..]
+            .iter()
+            .chain([&file.to_path_buf()])
+            .map(|file| file_name(file))
+            .collect::<Vec<String>>();
+        Err(ValkyrieError::runtime(
+            ErrorCode::CircularImport,
+            format!("Circular import of {}", file_name(file)),
+            span,
+        )
+        .with_note(format!("Import chain: {}", chain.join(" -> "))))
+    }
+}
+
+// Runs the file at path the first time it is imported, with `run` given its resolved statements
+// and returning the globals it declared. Later imports get the same module. The sandbox is checked
+// before anything is read
+pub fn import(
+    modules: &RefCell<Modules>,
+    sandbox: &Sandbox,
+    path: &str,
+    span: Span,
+    run: impl FnOnce(&[Stmt], Locals) -> Result<HashMap<String, LiteralValue>, ValkyrieError>,
+) -> Result<Rc<Module>, ValkyrieError> {
+    let file = modules.borrow().find(path, span)?;
+    sandbox.check_file(&file, span)?;
+    if let Some(module) = modules.borrow().cache.get(&file) {
+        return Ok(module.clone());
+    }
+    modules.borrow().check_cycle(&file, span)?;
+
+    let (stmts, locals) = load(&file).map_err(|errs| import_failed(&file, &errs, span))?;
+    modules.borrow_mut().loading.push(file.clone());
+    let result = run(&stmts, locals);
+    modules.borrow_mut().loading.pop();
+    let globals = result.map_err(|err| import_failed(&file, &[err], span))?;
+
+    // The natives are there in every module, they are only exported when the module replaced them
+    let natives = modules.borrow().globals();
+    let exports = globals
+        .into_iter()
+        .filter(|(name, value)| natives.borrow().get(name) != Some(value))
+        .collect();
+    let module = Rc::new(Module {
+        name: file
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().to_string())
+            .unwrap_or_default(),
+        exports,
+    });
+    modules.borrow_mut().cache.insert(file, module.clone());
+    Ok(module)
+}
+
+fn load(file: &Path) -> Result<(Vec<Stmt>, Locals), Vec<ValkyrieError>> {
+    let contents = fs::read_to_string(file).map_err(|err| {
+        vec![ValkyrieError::runtime(
+            ErrorCode::ImportFailed,
+            format!("Could not read {}: {}", file.display(), err),
+            Span::default(),
+        )]
+    })?;
+    let source = add_source(file_name(file), &contents);
+    let tokens = Scanner::new(&contents).with_file(source).scan_tokens()?;
+    let stmts = Parser::new(tokens).parse()?;
+    let locals = Resolver::new()
+        .resolve(&stmts.iter().collect())
+        .map_err(|err| vec![err])?;
+    Ok((stmts, locals))
+}
+
+// The errors of another file cannot be shown against the source of the importing one, so they
+// become notes of an error at the import statement. Fatal errors stop the importing file too, so
+// they keep their code and cannot be caught there either
+fn import_failed(file: &Path, errs: &[ValkyrieError], span: Span) -> ValkyrieError {
+    let (code, message) = match errs {
+        [err] if err.code().is_fatal() => (err.code(), err.message().to_string()),
+        _ => (
+            ErrorCode::ImportFailed,
+            format!("Could not import {}", file_name(file)),
+        ),
+    };
+    let mut error = ValkyrieError::runtime(code, message, span);
+    for err in errs {
+        error = error.with_note(format!(
+            "{}, {}: {} error [{}]: {}",
+            file_name(file),
+            err.span(),
+            err.kind(),
+            err.code(),
+            err.message()
+        ));
+        for note in &err.diagnostic().notes {
+            error = error.with_note(note.clone());
+        }
+    }
+    error
+}
+
+fn file_name(file: &Path) -> String {
+    file.file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default()
+}
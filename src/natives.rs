@@ -0,0 +1,322 @@
+use crate::diagnostic::Span;
+use crate::error::{ErrorCode, ValkyrieError};
+use crate::expr::{map_get, CallableImpl, LiteralValue, NativeFunctionImpl};
+use crate::limits::reserve;
+use crate::stdlib::define_stdlib;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+// The span of these errors is the one of the call, filled in by the caller
+pub fn native_error(code: ErrorCode, message: String) -> ValkyrieError {
+    ValkyrieError::runtime(code, message, Span::default())
+}
+
+// Gives the arguments as an array, to be destructured. The caller already checked their number
+// against the arity, the error is for natives called some other way
+pub fn arguments<'a, const N: usize>(
+    name: &str,
+    args: &'a [LiteralValue],
+) -> Result<&'a [LiteralValue; N], ValkyrieError> {
+    args.try_into().map_err(|_| {
+        native_error(
+            ErrorCode::ArityMismatch,
+            format!(
+                "Callable {} expected {} arguments but got {}",
+                name,
+                N,
+                args.len()
+            ),
+        )
+    })
+}
+
+pub fn expected(name: &str, what: &str, value: &LiteralValue) -> ValkyrieError {
+    native_error(
+        ErrorCode::TypeMismatch,
+        format!("{} expected {} but got {}", name, what, value.to_type()),
+    )
+}
+
+fn array<'a>(
+    name: &str,
+    value: &'a LiteralValue,
+) -> Result<&'a RefCell<Vec<LiteralValue>>, ValkyrieError> {
+    match value {
+        LiteralValue::Array(xs) => Ok(xs),
+        other => Err(expected(name, "an Array", other)),
+    }
+}
+
+fn map<'a>(
+    name: &str,
+    value: &'a LiteralValue,
+) -> Result<&'a RefCell<Vec<(LiteralValue, LiteralValue)>>, ValkyrieError> {
+    match value {
+        LiteralValue::Map(entries) => Ok(entries),
+        other => Err(expected(name, "a Map", other)),
+    }
+}
+
+fn clock_impl(_args: &[LiteralValue]) -> Result<LiteralValue, ValkyrieError> {
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::SystemTime::UNIX_EPOCH)
+        .map(|time| time.as_millis())
+        .unwrap_or_default();
+
+    Ok(LiteralValue::Number(now as f64 / 1000.0))
+}
+
+fn len_impl(args: &[LiteralValue]) -> Result<LiteralValue, ValkyrieError> {
+    let [value] = arguments("len", args)?;
+    match value {
+        LiteralValue::Array(xs) => Ok(LiteralValue::Number(xs.borrow().len() as f64)),
+        LiteralValue::Map(entries) => Ok(LiteralValue::Number(entries.borrow().len() as f64)),
+        LiteralValue::StringValue(s) => Ok(LiteralValue::Number(s.chars().count() as f64)),
+        other => Err(expected("len", "an Array, a Map or a String", other)),
+    }
+}
+
+fn push_impl(args: &[LiteralValue]) -> Result<LiteralValue, ValkyrieError> {
+    let [xs, value] = arguments("push", args)?;
+    array("push", xs)?.borrow_mut().push(value.clone());
+    Ok(LiteralValue::Null)
+}
+
+// Returns null when the array is empty
+fn pop_impl(args: &[LiteralValue]) -> Result<LiteralValue, ValkyrieError> {
+    let [xs] = arguments("pop", args)?;
+    Ok(array("pop", xs)?
+        .borrow_mut()
+        .pop()
+        .unwrap_or(LiteralValue::Null))
+}
+
+// slice(xs, start, end) copies xs[start..end], both bounds are clamped to the array
+fn slice_impl(args: &[LiteralValue]) -> Result<LiteralValue, ValkyrieError> {
+    let [xs, start, end] = arguments("slice", args)?;
+    let xs = array("slice", xs)?.borrow();
+    match (start, end) {
+        (LiteralValue::Number(start), LiteralValue::Number(end)) => {
+            let end = (end.max(0.0) as usize).min(xs.len());
+            let start = (start.max(0.0) as usize).min(end);
+            Ok(LiteralValue::new_array(xs[start..end].to_vec()))
+        }
+        (LiteralValue::Number(_), other) | (other, _) => Err(expected("slice", "a Number", other)),
+    }
+}
+
+// concat(xs, ys, ...) copies the elements of all its arrays into a new one
+fn concat_impl(args: &[LiteralValue]) -> Result<LiteralValue, ValkyrieError> {
+    let mut size = 0usize;
+    for xs in args {
+        size = size.saturating_add(array("concat", xs)?.borrow().len());
+    }
+    reserve("Array", size, "elements")?;
+    let mut result = vec![];
+    for xs in args {
+        result.extend(array("concat", xs)?.borrow().iter().cloned());
+    }
+    Ok(LiteralValue::new_array(result))
+}
+
+fn has_impl(args: &[LiteralValue]) -> Result<LiteralValue, ValkyrieError> {
+    let [entries, key] = arguments("has", args)?;
+    let entries = map("has", entries)?.borrow();
+    Ok(LiteralValue::from_bool(map_get(&entries, key).is_some()))
+}
+
+// Deletes a key from a map and returns its value, or null if it was not there
+fn remove_impl(args: &[LiteralValue]) -> Result<LiteralValue, ValkyrieError> {
+    let [entries, key] = arguments("remove", args)?;
+    let mut entries = map("remove", entries)?.borrow_mut();
+    Ok(match entries.iter().position(|(k, _)| k == key) {
+        Some(i) => entries.remove(i).1,
+        None => LiteralValue::Null,
+    })
+}
+
+fn keys_impl(args: &[LiteralValue]) -> Result<LiteralValue, ValkyrieError> {
+    let [entries] = arguments("keys", args)?;
+    Ok(LiteralValue::new_array(
+        map("keys", entries)?
+            .borrow()
+            .iter()
+            .map(|(key, _)| key.clone())
+            .collect(),
+    ))
+}
+
+fn values_impl(args: &[LiteralValue]) -> Result<LiteralValue, ValkyrieError> {
+    let [entries] = arguments("values", args)?;
+    Ok(LiteralValue::new_array(
+        map("values", entries)?
+            .borrow()
+            .iter()
+            .map(|(_, value)| value.clone())
+            .collect(),
+    ))
+}
+
+// Error(message) makes an error value like the ones the interpreter raises, to be thrown
+fn error_impl(args: &[LiteralValue]) -> Result<LiteralValue, ValkyrieError> {
+    let [message] = arguments("Error", args)?;
+    Ok(match message {
+        LiteralValue::StringValue(message) => {
+            LiteralValue::new_error(message.clone(), "Error".to_string())
+        }
+        other => LiteralValue::new_error(other.to_string(), "Error".to_string()),
+    })
+}
+
+fn insert_native(
+    env: &mut HashMap<String, LiteralValue>,
+    name: &str,
+    arity: usize,
+    variadic: bool,
+    fun: impl Fn(&[LiteralValue]) -> Result<LiteralValue, ValkyrieError> + 'static,
+) {
+    let fun_impl = NativeFunctionImpl {
+        name: name.to_string(),
+        arity,
+        variadic,
+        fun: Rc::new(fun),
+    };
+    let callable_impl = CallableImpl::NativeFunction(fun_impl);
+    env.insert(name.to_string(), LiteralValue::Callable(callable_impl));
+}
+
+pub fn define_native(
+    env: &mut HashMap<String, LiteralValue>,
+    name: &str,
+    arity: usize,
+    fun: impl Fn(&[LiteralValue]) -> Result<LiteralValue, ValkyrieError> + 'static,
+) {
+    insert_native(env, name, arity, false, fun);
+}
+
+// For natives that take at least min_arity arguments
+pub fn define_variadic(
+    env: &mut HashMap<String, LiteralValue>,
+    name: &str,
+    min_arity: usize,
+    fun: impl Fn(&[LiteralValue]) -> Result<LiteralValue, ValkyrieError> + 'static,
+) {
+    insert_native(env, name, min_arity, true, fun);
+}
+
+pub fn get_globals() -> Rc<RefCell<HashMap<String, LiteralValue>>> {
+    let mut env = HashMap::new();
+    define_native(&mut env, "clock", 0, clock_impl);
+    define_native(&mut env, "len", 1, len_impl);
+    define_native(&mut env, "push", 2, push_impl);
+    define_native(&mut env, "pop", 1, pop_impl);
+    define_native(&mut env, "slice", 3, slice_impl);
+    define_variadic(&mut env, "concat", 2, concat_impl);
+    define_native(&mut env, "has", 2, has_impl);
+    define_native(&mut env, "remove", 2, remove_impl);
+    define_native(&mut env, "keys", 1, keys_impl);
+    define_native(&mut env, "values", 1, values_impl);
+    define_native(&mut env, "Error", 1, error_impl);
+    define_stdlib(&mut env);
+
+    Rc::new(RefCell::new(env))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn numbers(xs: &[f64]) -> LiteralValue {
+        LiteralValue::new_array(xs.iter().map(|x| LiteralValue::Number(*x)).collect())
+    }
+
+    #[test]
+    fn push_and_pop_share_storage() {
+        let xs = numbers(&[1.0]);
+        push_impl(&[xs.clone(), LiteralValue::Number(2.0)]).unwrap();
+        assert_eq!(
+            len_impl(std::slice::from_ref(&xs)).unwrap(),
+            LiteralValue::Number(2.0)
+        );
+        assert_eq!(
+            pop_impl(std::slice::from_ref(&xs)).unwrap(),
+            LiteralValue::Number(2.0)
+        );
+        assert_eq!(xs, numbers(&[1.0]));
+    }
+
+    #[test]
+    fn slice_clamps_bounds() {
+        let xs = numbers(&[1.0, 2.0, 3.0]);
+        let args = [
+            xs.clone(),
+            LiteralValue::Number(1.0),
+            LiteralValue::Number(10.0),
+        ];
+        assert_eq!(slice_impl(&args).unwrap(), numbers(&[2.0, 3.0]));
+        let args = [xs, LiteralValue::Number(2.0), LiteralValue::Number(1.0)];
+        assert_eq!(slice_impl(&args).unwrap(), numbers(&[]));
+    }
+
+    #[test]
+    fn concat_copies() {
+        let xs = numbers(&[1.0]);
+        let ys = numbers(&[2.0]);
+        let zs = concat_impl(&[xs.clone(), ys]).unwrap();
+        push_impl(&[zs.clone(), LiteralValue::Number(3.0)]).unwrap();
+        assert_eq!(zs, numbers(&[1.0, 2.0, 3.0]));
+        assert_eq!(xs, numbers(&[1.0]));
+    }
+
+    #[test]
+    fn remove_keeps_insertion_order() {
+        let string = |s: &str| LiteralValue::StringValue(s.to_string());
+        let map = LiteralValue::new_map(vec![
+            (string("a"), LiteralValue::Number(1.0)),
+            (string("b"), LiteralValue::Number(2.0)),
+            (string("c"), LiteralValue::Number(3.0)),
+        ]);
+
+        assert_eq!(
+            remove_impl(&[map.clone(), string("b")]).unwrap(),
+            LiteralValue::Number(2.0)
+        );
+        assert_eq!(
+            remove_impl(&[map.clone(), string("b")]).unwrap(),
+            LiteralValue::Null
+        );
+        assert_eq!(
+            has_impl(&[map.clone(), string("b")]).unwrap(),
+            LiteralValue::False
+        );
+        assert_eq!(
+            keys_impl(&[map]).unwrap(),
+            LiteralValue::new_array(vec![string("a"), string("c")])
+        );
+    }
+
+    fn native(value: &LiteralValue) -> &NativeFunctionImpl {
+        match value {
+            LiteralValue::Callable(CallableImpl::NativeFunction(native)) => native,
+            other => panic!("{} is not a native", other.to_string()),
+        }
+    }
+
+    #[test]
+    fn variadic_natives_take_more_arguments() {
+        let globals = get_globals();
+        let globals = globals.borrow();
+        let concat = native(&globals["concat"]);
+        assert!(concat.check_arity(3, Span::default()).is_ok());
+        let err = concat.check_arity(1, Span::default()).unwrap_err();
+        assert_eq!(err.code(), ErrorCode::ArityMismatch);
+        assert_eq!(
+            err.message(),
+            "Callable concat expected at least 2 arguments but got 1"
+        );
+        assert!(native(&globals["len"])
+            .check_arity(2, Span::default())
+            .is_err());
+    }
+}
@@ -0,0 +1,43 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+// Where print writes, shared by everything that runs in one interpreter, imported files included.
+// Closures taking the printed line are outputs too
+pub trait Output {
+    // A line without its newline
+    fn print(&mut self, line: &str);
+}
+
+impl<F: FnMut(&str)> Output for F {
+    fn print(&mut self, line: &str) {
+        self(line)
+    }
+}
+
+pub type SharedOutput = Rc<RefCell<Box<dyn Output>>>;
+
+pub fn stdout() -> SharedOutput {
+    Rc::new(RefCell::new(Box::new(|line: &str| println!("{}", line))))
+}
+
+// Keeps what is printed in memory. Clones share the text, so one can be given to the interpreter
+// and another kept to read it
+#[derive(Clone, Debug, Default)]
+pub struct Capture {
+    text: Rc<RefCell<String>>,
+}
+
+impl Capture {
+    // The text printed since the last take, every line ends with a newline
+    pub fn take(&self) -> String {
+        self.text.take()
+    }
+}
+
+impl Output for Capture {
+    fn print(&mut self, line: &str) {
+        let mut text = self.text.borrow_mut();
+        text.push_str(line);
+        text.push('\n');
+    }
+}
@@ -1,11 +1,20 @@
+use crate::command::split_words;
+use crate::diagnostic::Span;
+use crate::error::{ErrorCode, ValkyrieError};
 use crate::expr::{Expr, Expr::*, LiteralValue};
+use crate::scanner;
 use crate::scanner::{Token, TokenType, TokenType::*};
 use crate::stmt::Stmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// Ids are unique across parses, so that the addresses the resolver gave to the lines the prompt
+// already ran stay valid for the functions declared on them
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
 
 pub struct Parser {
     tokens: Vec<Token>,
     current: usize,
-    next_id: usize,
+    errors: Vec<ValkyrieError>,
 }
 
 #[derive(Debug)]
@@ -19,58 +28,93 @@ impl Parser {
         Self {
             tokens,
             current: 0,
-            next_id: 0,
+            errors: vec![],
         }
     }
 
     fn get_id(&mut self) -> usize {
-        let id = self.next_id;
-        self.next_id += 1;
-
-        id
+        NEXT_ID.fetch_add(1, Ordering::Relaxed)
     }
 
-    pub fn parse(&mut self) -> Result<Vec<Stmt>, String> {
+    pub fn parse(&mut self) -> Result<Vec<Stmt>, Vec<ValkyrieError>> {
         let mut stmts = vec![];
-        let mut errs = vec![];
 
         while !self.is_at_end() {
-            let stmt = self.declaration();
-            match stmt {
-                Ok(s) => stmts.push(s),
-                Err(msg) => {
-                    errs.push(msg);
-                    self.synchronize();
-                }
+            if let Some(stmt) = self.declaration_or_recover() {
+                stmts.push(stmt);
+            } else if self.check(RightBrace) {
+                // A stray '}' has nothing to close at the top level, skip it
+                self.advance();
             }
         }
 
-        if errs.len() == 0 {
+        if self.errors.is_empty() {
             Ok(stmts)
         } else {
-            Err(errs.join("\n"))
+            Err(std::mem::take(&mut self.errors))
+        }
+    }
+
+    // Parses a declaration, on a syntax error the error is recorded and the parser skips ahead
+    // to the next statement boundary so that the errors after it are reported as well
+    fn declaration_or_recover(&mut self) -> Option<Stmt> {
+        match self.declaration() {
+            Ok(stmt) => Some(stmt),
+            Err(err) => {
+                self.errors.push(err);
+                self.synchronize();
+                None
+            }
         }
     }
 
-    fn declaration(&mut self) -> Result<Stmt, String> {
+    fn declaration(&mut self) -> Result<Stmt, ValkyrieError> {
         if self.match_token(Var) {
             self.var_declaration()
         } else if self.match_token(Fun) {
             self.function(FunctionKind::Function)
         } else if self.match_token(Class) {
             self.class_declaration()
+        } else if self.match_token(Import) {
+            self.import_declaration()
         } else {
             self.statement()
         }
     }
 
-    fn class_declaration(&mut self) -> Result<Stmt, String> {
+    // import "path"; or import name from "path";
+    fn import_declaration(&mut self) -> Result<Stmt, ValkyrieError> {
+        let keyword = self.previous();
+        let name = if self.match_token(Identifier) {
+            let name = self.previous();
+            self.consume(From, "Expected 'from' after the name of the module.")?;
+            Some(name)
+        } else {
+            None
+        };
+        let path = self.consume(StringLit, "Expected the path of the module as a string.")?;
+        self.consume(Semicolon, "Expected ';' after import.")?;
+
+        let path = match path.literal {
+            Some(scanner::LiteralValue::StringValue(path)) => path,
+            _ => panic!("A string token had no string value"),
+        };
+        Ok(Stmt::Import {
+            span: self.span_from(keyword.span),
+            path,
+            name,
+        })
+    }
+
+    fn class_declaration(&mut self) -> Result<Stmt, ValkyrieError> {
+        let keyword = self.previous();
         let name = self.consume(Identifier, "Expected name after 'class' keyword.")?;
         let superclass = if self.match_token(TokenType::Less) {
-            self.consume(Identifier, "Expected superclass name after '<'.")?;
+            let super_name = self.consume(Identifier, "Expected superclass name after '<'.")?;
             Some(Expr::Variable {
                 id: self.get_id(),
-                name: self.previous(),
+                span: super_name.span,
+                name: super_name,
             })
         } else {
             None
@@ -87,22 +131,36 @@ impl Parser {
         self.consume(RightBrace, "Expected '}' after class body.")?;
 
         Ok(Stmt::Class {
+            span: self.span_from(keyword.span),
             name,
             methods,
             superclass,
         })
     }
 
-    fn function(&mut self, kind: FunctionKind) -> Result<Stmt, String> {
+    fn function(&mut self, kind: FunctionKind) -> Result<Stmt, ValkyrieError> {
+        // Methods have no 'fun' keyword in front of them
+        let start = match kind {
+            FunctionKind::Function => self.previous().span,
+            FunctionKind::Method => self.peek().span,
+        };
         let name = self.consume(Identifier, &format!("Expected {kind:?} name"))?;
 
         if self.match_token(Gets) {
-            let cmd_body = self.consume(StringLit, "Expected command body")?; 
+            let cmd_body = self.consume(StringLit, "Expected command body")?;
             self.consume(Semicolon, "Expected ';' after command body")?;
 
+            let cmd = match &cmd_body.literal {
+                Some(scanner::LiteralValue::StringValue(cmd)) => split_words(cmd),
+                _ => panic!("A string token had no string value"),
+            }
+            .map_err(|message| {
+                ValkyrieError::parse(ErrorCode::InvalidCommand, message, cmd_body.span)
+            })?;
             return Ok(Stmt::CmdFunction {
+                span: self.span_from(start),
                 name,
-                cmd: cmd_body.lexeme,
+                cmd,
             });
         }
 
@@ -112,9 +170,10 @@ impl Parser {
         if !self.check(RightParen) {
             loop {
                 if parameters.len() >= 255 {
-                    let location = self.peek().line_number;
-                    return Err(format!(
-                        "Line {location}: Cant have more than 255 arguments"
+                    return Err(ValkyrieError::parse(
+                        ErrorCode::TooManyArguments,
+                        "Cant have more than 255 arguments".to_string(),
+                        self.peek().span,
                     ));
                 }
 
@@ -130,18 +189,23 @@ impl Parser {
 
         self.consume(LeftBrace, &format!("Expected '{{' before {kind:?} body."))?;
         let body = match self.block_statement()? {
-            Stmt::Block { statements } => statements,
+            Stmt::Block {
+                span: _,
+                statements,
+            } => statements.into_iter().map(|stmt| *stmt).collect(),
             _ => panic!("Block statement parsed something that was not a block"),
         };
 
         Ok(Stmt::Function {
+            span: self.span_from(start),
             name,
-            params: parameters,
+            params: parameters.into(),
             body,
         })
     }
 
-    fn var_declaration(&mut self) -> Result<Stmt, String> {
+    fn var_declaration(&mut self) -> Result<Stmt, ValkyrieError> {
+        let keyword = self.previous();
         let token = self.consume(Identifier, "Expected variable name")?;
 
         let initializer;
@@ -150,6 +214,7 @@ impl Parser {
         } else {
             initializer = Literal {
                 id: self.get_id(),
+                span: token.span,
                 value: LiteralValue::Null,
             };
         }
@@ -157,15 +222,16 @@ impl Parser {
         self.consume(Semicolon, "Expected ';' after variable declaration")?;
 
         Ok(Stmt::Var {
+            span: self.span_from(keyword.span),
             name: token,
             initializer,
         })
     }
 
-    fn statement(&mut self) -> Result<Stmt, String> {
+    fn statement(&mut self) -> Result<Stmt, ValkyrieError> {
         if self.match_token(Print) {
             self.print_statement()
-        } else if self.match_token(LeftBrace) {
+        } else if !self.starts_map_literal() && self.match_token(LeftBrace) {
             self.block_statement()
         } else if self.match_token(If) {
             self.if_statement()
@@ -175,12 +241,20 @@ impl Parser {
             self.for_statement()
         } else if self.match_token(Return) {
             self.return_statement()
+        } else if self.match_token(Break) {
+            self.break_statement()
+        } else if self.match_token(Continue) {
+            self.continue_statement()
+        } else if self.match_token(Throw) {
+            self.throw_statement()
+        } else if self.match_token(Try) {
+            self.try_statement()
         } else {
             self.expression_statement()
         }
     }
 
-    fn return_statement(&mut self) -> Result<Stmt, String> {
+    fn return_statement(&mut self) -> Result<Stmt, ValkyrieError> {
         let keyword = self.previous();
         let value;
         if !self.check(Semicolon) {
@@ -191,12 +265,17 @@ impl Parser {
         }
         self.consume(Semicolon, "Expected ';' after return value;")?;
 
-        Ok(Stmt::ReturnStmt { keyword, value })
+        Ok(Stmt::ReturnStmt {
+            span: self.span_from(keyword.span),
+            keyword,
+            value,
+        })
     }
 
-    fn for_statement(&mut self) -> Result<Stmt, String> {
+    fn for_statement(&mut self) -> Result<Stmt, ValkyrieError> {
         // for v
         //       ( SMTH ; SMTH ; SMTH )
+        let keyword = self.previous();
         self.consume(LeftParen, "Expected '(' after 'for'.")?;
 
         // Consumes "SMTH ;"
@@ -231,33 +310,31 @@ impl Parser {
         self.consume(RightParen, "Expected ')' after for clauses.")?;
 
         let mut body = self.statement()?;
-
-        if let Some(incr) = increment {
-            body = Stmt::Block {
-                statements: vec![
-                    Box::new(body),
-                    Box::new(Stmt::Expression { expression: incr }),
-                ],
-            };
-        }
+        // The desugared statements all point back at the whole loop
+        let span = self.span_from(keyword.span);
 
         let cond;
         match condition {
             None => {
                 cond = Expr::Literal {
                     id: self.get_id(),
+                    span: keyword.span,
                     value: LiteralValue::True,
                 }
             }
             Some(c) => cond = c,
         }
+        // The increment stays out of the body so that a continue does not skip it
         body = Stmt::WhileStmt {
+            span,
             condition: cond,
             body: Box::new(body),
+            increment,
         };
 
         if let Some(init) = initializer {
             body = Stmt::Block {
+                span,
                 statements: vec![Box::new(init), Box::new(body)],
             };
         }
@@ -265,19 +342,90 @@ impl Parser {
         Ok(body)
     }
 
-    fn while_statement(&mut self) -> Result<Stmt, String> {
+    fn while_statement(&mut self) -> Result<Stmt, ValkyrieError> {
+        let keyword = self.previous();
         self.consume(LeftParen, "Expected '(' after 'while'")?;
         let condition = self.expression()?;
         self.consume(RightParen, "Expected ')' after condition.")?;
         let body = self.statement()?;
 
         Ok(Stmt::WhileStmt {
+            span: self.span_from(keyword.span),
             condition,
             body: Box::new(body),
+            increment: None,
         })
     }
 
-    fn if_statement(&mut self) -> Result<Stmt, String> {
+    fn break_statement(&mut self) -> Result<Stmt, ValkyrieError> {
+        let keyword = self.previous();
+        self.consume(Semicolon, "Expected ';' after 'break'.")?;
+        Ok(Stmt::Break {
+            span: self.span_from(keyword.span),
+            keyword,
+        })
+    }
+
+    fn continue_statement(&mut self) -> Result<Stmt, ValkyrieError> {
+        let keyword = self.previous();
+        self.consume(Semicolon, "Expected ';' after 'continue'.")?;
+        Ok(Stmt::Continue {
+            span: self.span_from(keyword.span),
+            keyword,
+        })
+    }
+
+    fn throw_statement(&mut self) -> Result<Stmt, ValkyrieError> {
+        let keyword = self.previous();
+        let value = self.expression()?;
+        self.consume(Semicolon, "Expected ';' after thrown value.")?;
+        Ok(Stmt::Throw {
+            span: self.span_from(keyword.span),
+            keyword,
+            value,
+        })
+    }
+
+    fn try_statement(&mut self) -> Result<Stmt, ValkyrieError> {
+        let keyword = self.previous();
+        self.consume(LeftBrace, "Expected '{' after 'try'.")?;
+        let body = Box::new(self.block_statement()?);
+
+        let catch = if self.match_token(Catch) {
+            self.consume(LeftParen, "Expected '(' after 'catch'.")?;
+            let name = self.consume(Identifier, "Expected a name for the caught value.")?;
+            self.consume(RightParen, "Expected ')' after the caught value.")?;
+            self.consume(LeftBrace, "Expected '{' after catch clause.")?;
+            Some((name, Box::new(self.block_statement()?)))
+        } else {
+            None
+        };
+
+        let finally = if self.match_token(Finally) {
+            self.consume(LeftBrace, "Expected '{' after 'finally'.")?;
+            Some(Box::new(self.block_statement()?))
+        } else {
+            None
+        };
+
+        if catch.is_none() && finally.is_none() {
+            return Err(ValkyrieError::parse(
+                ErrorCode::ExpectedToken,
+                "Expected 'catch' or 'finally' after try block.".to_string(),
+                self.peek().span,
+            ));
+        }
+
+        Ok(Stmt::Try {
+            span: self.span_from(keyword.span),
+            body,
+            catch,
+            finally,
+        })
+    }
+
+    fn if_statement(&mut self) -> Result<Stmt, ValkyrieError> {
+        let keyword = self.previous();
         self.consume(LeftParen, "Expected '(' after 'if'")?;
         let predicate = self.expression()?;
         self.consume(RightParen, "Expected ')' after if-predicate")?;
@@ -291,49 +439,64 @@ impl Parser {
         };
 
         Ok(Stmt::IfStmt {
+            span: self.span_from(keyword.span),
             predicate,
             then,
             els,
         })
     }
 
-    fn block_statement(&mut self) -> Result<Stmt, String> {
+    fn block_statement(&mut self) -> Result<Stmt, ValkyrieError> {
+        let brace = self.previous();
         let mut statements = vec![];
 
         while !self.check(RightBrace) && !self.is_at_end() {
-            let decl = self.declaration()?;
-            statements.push(Box::new(decl));
+            if let Some(decl) = self.declaration_or_recover() {
+                statements.push(Box::new(decl));
+            }
         }
 
         self.consume(RightBrace, "Expected '}' after a block")?;
-        Ok(Stmt::Block { statements })
+        Ok(Stmt::Block {
+            span: self.span_from(brace.span),
+            statements,
+        })
     }
 
-    fn print_statement(&mut self) -> Result<Stmt, String> {
+    fn print_statement(&mut self) -> Result<Stmt, ValkyrieError> {
+        let keyword = self.previous();
         let value = self.expression()?;
         self.consume(Semicolon, "Expected ';' after value.")?;
-        Ok(Stmt::Print { expression: value })
+        Ok(Stmt::Print {
+            span: self.span_from(keyword.span),
+            expression: value,
+        })
     }
 
-    fn expression_statement(&mut self) -> Result<Stmt, String> {
+    fn expression_statement(&mut self) -> Result<Stmt, ValkyrieError> {
         let expr = self.expression()?;
         self.consume(Semicolon, "Expected ';' after expression.")?;
-        Ok(Stmt::Expression { expression: expr })
+        Ok(Stmt::Expression {
+            span: self.span_from(expr.get_span()),
+            expression: expr,
+        })
     }
 
-    fn expression(&mut self) -> Result<Expr, String> {
+    fn expression(&mut self) -> Result<Expr, ValkyrieError> {
         self.assignment()
     }
 
-    fn function_expression(&mut self) -> Result<Expr, String> {
+    fn function_expression(&mut self) -> Result<Expr, ValkyrieError> {
+        let keyword = self.previous();
         let paren = self.consume(LeftParen, "Expected '(' after anonymous function")?;
         let mut parameters = vec![];
         if !self.check(RightParen) {
             loop {
                 if parameters.len() >= 255 {
-                    let location = self.peek().line_number;
-                    return Err(format!(
-                        "Line {location}: Cant have more than 255 arguments"
+                    return Err(ValkyrieError::parse(
+                        ErrorCode::TooManyArguments,
+                        "Cant have more than 255 arguments".to_string(),
+                        self.peek().span,
                     ));
                 }
 
@@ -356,49 +519,77 @@ impl Parser {
         )?;
 
         let body = match self.block_statement()? {
-            Stmt::Block { statements } => statements,
+            Stmt::Block {
+                span: _,
+                statements,
+            } => statements.into_iter().map(|stmt| *stmt).collect(),
             _ => panic!("Block statement parsed something that was not a block"),
         };
 
         Ok(Expr::AnonFunction {
             id: self.get_id(),
+            span: self.span_from(keyword.span),
             paren,
-            arguments: parameters,
+            arguments: parameters.into(),
             body,
         })
     }
 
-    fn assignment(&mut self) -> Result<Expr, String> {
+    fn assignment(&mut self) -> Result<Expr, ValkyrieError> {
         // a = 2; NOT var a = 2;
         let expr = self.pipe()?; // a |> f = 2;
 
         if self.match_token(Equal) {
             let value = self.expression()?;
+            let span = expr.get_span().to(value.get_span());
 
             match expr {
-                Variable { id: _, name } => Ok(Assign {
+                Variable {
+                    id: _,
+                    span: _,
+                    name,
+                } => Ok(Assign {
                     id: self.get_id(),
+                    span,
                     name,
                     value: Box::from(value),
                 }),
                 Get {
                     id: _,
+                    span: _,
                     object,
                     name,
                 } => Ok(Set {
                     id: self.get_id(),
+                    span,
                     object,
                     name,
                     value: Box::new(value),
                 }),
-                _ => Err("Invalid assignment target.".to_string()),
+                Index {
+                    id: _,
+                    span: _,
+                    object,
+                    index,
+                } => Ok(IndexSet {
+                    id: self.get_id(),
+                    span,
+                    object,
+                    index,
+                    value: Box::new(value),
+                }),
+                _ => Err(ValkyrieError::parse(
+                    ErrorCode::InvalidAssignmentTarget,
+                    "Invalid assignment target.".to_string(),
+                    expr.get_span(),
+                )),
             }
         } else {
             Ok(expr)
         }
     }
 
-    fn pipe(&mut self) -> Result<Expr, String> {
+    fn pipe(&mut self) -> Result<Expr, ValkyrieError> {
         // expr |> f
         // expr |> f1 |> f2
         // expr |> (f1 |> f2)
@@ -414,6 +605,7 @@ impl Parser {
 
             expr = Call {
                 id: self.get_id(),
+                span: expr.get_span().to(function.get_span()),
                 callee: Box::new(function),
                 paren: pipe,
                 arguments: vec![expr],
@@ -422,7 +614,7 @@ impl Parser {
         Ok(expr)
     }
 
-    fn or(&mut self) -> Result<Expr, String> {
+    fn or(&mut self) -> Result<Expr, ValkyrieError> {
         let mut expr = self.and()?;
 
         while self.match_token(Or) {
@@ -431,6 +623,7 @@ impl Parser {
 
             expr = Logical {
                 id: self.get_id(),
+                span: expr.get_span().to(right.get_span()),
                 left: Box::new(expr),
                 operator,
                 right: Box::new(right),
@@ -440,7 +633,7 @@ impl Parser {
         Ok(expr)
     }
 
-    fn and(&mut self) -> Result<Expr, String> {
+    fn and(&mut self) -> Result<Expr, ValkyrieError> {
         let mut expr = self.equality()?;
 
         while self.match_token(And) {
@@ -448,6 +641,7 @@ impl Parser {
             let right = self.equality()?;
             expr = Logical {
                 id: self.get_id(),
+                span: expr.get_span().to(right.get_span()),
                 left: Box::new(expr),
                 operator,
                 right: Box::new(right),
@@ -457,13 +651,14 @@ impl Parser {
         Ok(expr)
     }
 
-    fn equality(&mut self) -> Result<Expr, String> {
+    fn equality(&mut self) -> Result<Expr, ValkyrieError> {
         let mut expr = self.comparison()?;
         while self.match_tokens(&[BangEqual, EqualEqual]) {
             let operator = self.previous();
             let rhs = self.comparison()?;
             expr = Binary {
                 id: self.get_id(),
+                span: expr.get_span().to(rhs.get_span()),
                 left: Box::from(expr),
                 operator,
                 right: Box::from(rhs),
@@ -473,7 +668,7 @@ impl Parser {
         Ok(expr)
     }
 
-    fn comparison(&mut self) -> Result<Expr, String> {
+    fn comparison(&mut self) -> Result<Expr, ValkyrieError> {
         let mut expr = self.term()?;
 
         while self.match_tokens(&[Greater, GreaterEqual, Less, LessEqual]) {
@@ -481,6 +676,7 @@ impl Parser {
             let rhs = self.term()?;
             expr = Binary {
                 id: self.get_id(),
+                span: expr.get_span().to(rhs.get_span()),
                 left: Box::from(expr),
                 operator: op,
                 right: Box::from(rhs),
@@ -490,7 +686,7 @@ impl Parser {
         Ok(expr)
     }
 
-    fn term(&mut self) -> Result<Expr, String> {
+    fn term(&mut self) -> Result<Expr, ValkyrieError> {
         let mut expr = self.factor()?;
 
         while self.match_tokens(&[Minus, Plus]) {
@@ -498,6 +694,7 @@ impl Parser {
             let rhs = self.factor()?;
             expr = Binary {
                 id: self.get_id(),
+                span: expr.get_span().to(rhs.get_span()),
                 left: Box::from(expr),
                 operator: op,
                 right: Box::from(rhs),
@@ -507,13 +704,14 @@ impl Parser {
         Ok(expr)
     }
 
-    fn factor(&mut self) -> Result<Expr, String> {
+    fn factor(&mut self) -> Result<Expr, ValkyrieError> {
         let mut expr = self.unary()?;
         while self.match_tokens(&[Slash, Star]) {
             let op = self.previous();
             let rhs = self.unary()?;
             expr = Binary {
                 id: self.get_id(),
+                span: expr.get_span().to(rhs.get_span()),
                 left: Box::from(expr),
                 operator: op,
                 right: Box::from(rhs),
@@ -523,12 +721,13 @@ impl Parser {
         Ok(expr)
     }
 
-    fn unary(&mut self) -> Result<Expr, String> {
+    fn unary(&mut self) -> Result<Expr, ValkyrieError> {
         if self.match_tokens(&[Bang, Minus]) {
             let op = self.previous();
             let rhs = self.unary()?;
             Ok(Unary {
                 id: self.get_id(),
+                span: op.span.to(rhs.get_span()),
                 operator: op,
                 right: Box::from(rhs),
             })
@@ -537,7 +736,7 @@ impl Parser {
         }
     }
 
-    fn call(&mut self) -> Result<Expr, String> {
+    fn call(&mut self) -> Result<Expr, ValkyrieError> {
         let mut expr = self.primary()?;
 
         loop {
@@ -547,9 +746,19 @@ impl Parser {
                 let name = self.consume(Identifier, "Expected token after dot-accessor")?;
                 expr = Get {
                     id: self.get_id(),
+                    span: expr.get_span().to(name.span),
                     object: Box::new(expr),
                     name,
                 };
+            } else if self.match_token(LeftBracket) {
+                let index = self.expression()?;
+                let bracket = self.consume(RightBracket, "Expected ']' after index")?;
+                expr = Index {
+                    id: self.get_id(),
+                    span: expr.get_span().to(bracket.span),
+                    object: Box::new(expr),
+                    index: Box::new(index),
+                };
             } else {
                 break;
             }
@@ -558,7 +767,7 @@ impl Parser {
         Ok(expr)
     }
 
-    fn finish_call(&mut self, callee: Expr) -> Result<Expr, String> {
+    fn finish_call(&mut self, callee: Expr) -> Result<Expr, ValkyrieError> {
         let mut arguments = vec![];
 
         if !self.check(RightParen) {
@@ -566,9 +775,10 @@ impl Parser {
                 let arg = self.expression()?;
                 arguments.push(arg);
                 if arguments.len() >= 255 {
-                    let location = self.peek().line_number;
-                    return Err(format!(
-                        "Line {location}: Cant have more than 255 arguments"
+                    return Err(ValkyrieError::parse(
+                        ErrorCode::TooManyArguments,
+                        "Cant have more than 255 arguments".to_string(),
+                        self.peek().span,
                     ));
                 }
 
@@ -581,22 +791,24 @@ impl Parser {
 
         Ok(Call {
             id: self.get_id(),
+            span: callee.get_span().to(paren.span),
             callee: Box::new(callee),
             paren,
             arguments,
         })
     }
 
-    fn primary(&mut self) -> Result<Expr, String> {
+    fn primary(&mut self) -> Result<Expr, ValkyrieError> {
         let token = self.peek();
         let result;
         match token.token_type {
             LeftParen => {
                 self.advance();
                 let expr = self.expression()?;
-                self.consume(RightParen, "Expected ')'")?;
+                let paren = self.consume(RightParen, "Expected ')'")?;
                 result = Grouping {
                     id: self.get_id(),
+                    span: token.span.to(paren.span),
                     expression: Box::from(expr),
                 };
             }
@@ -604,6 +816,7 @@ impl Parser {
                 self.advance();
                 result = Literal {
                     id: self.get_id(),
+                    span: token.span,
                     value: LiteralValue::from_token(token),
                 }
             }
@@ -611,6 +824,7 @@ impl Parser {
                 self.advance();
                 result = Variable {
                     id: self.get_id(),
+                    span: token.span,
                     name: self.previous(),
                 };
             }
@@ -618,6 +832,7 @@ impl Parser {
                 self.advance();
                 result = Expr::This {
                     id: self.get_id(),
+                    span: token.span,
                     keyword: token,
                 };
             }
@@ -629,6 +844,7 @@ impl Parser {
                     self.consume(TokenType::Identifier, "Expected superclass method name.")?;
                 result = Expr::Super {
                     id: self.get_id(),
+                    span: token.span.to(method.span),
                     keyword: token,
                     method,
                 };
@@ -637,23 +853,89 @@ impl Parser {
                 self.advance();
                 result = self.function_expression()?;
             }
-            _ => return Err("Expected expression".to_string()),
+            LeftBrace => {
+                self.advance();
+                let mut entries = vec![];
+                if !self.check(RightBrace) {
+                    loop {
+                        let key = self.expression()?;
+                        self.consume(Colon, "Expected ':' after map key")?;
+                        let value = self.expression()?;
+                        entries.push((key, value));
+                        if !self.match_token(Comma) {
+                            break;
+                        }
+                    }
+                }
+                let brace = self.consume(RightBrace, "Expected '}' after map entries")?;
+                result = Map {
+                    id: self.get_id(),
+                    span: token.span.to(brace.span),
+                    entries,
+                };
+            }
+            LeftBracket => {
+                self.advance();
+                let mut elements = vec![];
+                if !self.check(RightBracket) {
+                    loop {
+                        elements.push(self.expression()?);
+                        if !self.match_token(Comma) {
+                            break;
+                        }
+                    }
+                }
+                let bracket = self.consume(RightBracket, "Expected ']' after array elements")?;
+                result = Array {
+                    id: self.get_id(),
+                    span: token.span.to(bracket.span),
+                    elements,
+                };
+            }
+            _ => {
+                return Err(ValkyrieError::parse(
+                    ErrorCode::ExpectedExpression,
+                    "Expected expression".to_string(),
+                    token.span,
+                ))
+            }
         }
 
         Ok(result)
     }
 
-    fn consume(&mut self, token_type: TokenType, msg: &str) -> Result<Token, String> {
+    fn consume(&mut self, token_type: TokenType, msg: &str) -> Result<Token, ValkyrieError> {
         let token = self.peek();
         if token.token_type == token_type {
             self.advance();
             let token = self.previous();
             Ok(token)
         } else {
-            Err(format!("Line {}: {}", token.line_number, msg))
+            Err(ValkyrieError::parse(
+                ErrorCode::ExpectedToken,
+                msg.to_string(),
+                token.span,
+            ))
         }
     }
 
+    // From `start` to the end of the last consumed token
+    fn span_from(&mut self, start: Span) -> Span {
+        start.to(self.previous().span)
+    }
+
+    // A '{' starts a block where a statement is expected, unless it is followed by a simple key
+    // and a ':', as in `{"a": 1}["a"];`
+    fn starts_map_literal(&mut self) -> bool {
+        if !self.check(LeftBrace) || self.current + 2 >= self.tokens.len() {
+            return false;
+        }
+
+        let key = &self.tokens[self.current + 1].token_type;
+        let colon = &self.tokens[self.current + 2].token_type;
+        matches!(key, StringLit | Number | Identifier | True | False | Null) && *colon == Colon
+    }
+
     fn check(&mut self, typ: TokenType) -> bool {
         self.peek().token_type == typ
     }
@@ -701,20 +983,21 @@ impl Parser {
         self.peek().token_type == Eof
     }
 
+    // Skips to just after the next ';' or to the next token that starts a statement. A '}' is
+    // left in place so that the enclosing block can still be closed
     fn synchronize(&mut self) {
-        self.advance();
-
         while !self.is_at_end() {
-            if self.previous().token_type == Semicolon {
-                return;
-            }
-
             match self.peek().token_type {
-                Class | Fun | Var | For | If | While | Print | Return => return,
-                _ => (),
+                Semicolon => {
+                    self.advance();
+                    return;
+                }
+                RightBrace | Class | Fun | Var | For | If | While | Print | Return | Break
+                | Continue | Throw | Try | Import => return,
+                _ => {
+                    self.advance();
+                }
             }
-
-            self.advance();
         }
     }
 }
@@ -730,31 +1013,31 @@ mod tests {
             token_type: Number,
             lexeme: "1".to_string(),
             literal: Some(FValue(1.0)),
-            line_number: 0,
+            span: Span::default(),
         };
         let plus = Token {
             token_type: Plus,
             lexeme: "+".to_string(),
             literal: None,
-            line_number: 0,
+            span: Span::default(),
         };
         let two = Token {
             token_type: Number,
             lexeme: "2".to_string(),
             literal: Some(FValue(2.0)),
-            line_number: 0,
+            span: Span::default(),
         };
         let semicol = Token {
             token_type: Semicolon,
             lexeme: ";".to_string(),
             literal: None,
-            line_number: 0,
+            span: Span::default(),
         };
         let eof = Token {
             token_type: Eof,
             lexeme: "".to_string(),
             literal: None,
-            line_number: 0,
+            span: Span::default(),
         };
 
         let tokens = vec![one, plus, two, semicol, eof];
@@ -789,4 +1072,108 @@ mod tests {
 
         assert_eq!(string_expr, "(== 1 (group (+ 2 2)))");
     }
+
+    #[test]
+    fn test_spans() {
+        let source = "var a = 1;\nprint a.b(2,\n  3);";
+        let mut scanner = Scanner::new(source);
+        let tokens = scanner.scan_tokens().unwrap();
+        let mut parser = Parser::new(tokens);
+        let parsed = parser.parse().unwrap();
+
+        assert_eq!(parsed[0].get_span(), Span::new(1, 1, 1, 10));
+        assert_eq!(parsed[1].get_span(), Span::new(2, 1, 3, 5));
+        match &parsed[1] {
+            Stmt::Print { expression, .. } => {
+                assert_eq!(expression.get_span(), Span::new(2, 7, 3, 4))
+            }
+            _ => panic!("Expected a print statement"),
+        }
+    }
+
+    #[test]
+    fn test_error_span() {
+        let source = "var a = ;";
+        let mut scanner = Scanner::new(source);
+        let tokens = scanner.scan_tokens().unwrap();
+        let mut parser = Parser::new(tokens);
+        let errs = parser.parse().unwrap_err();
+
+        assert_eq!(errs.len(), 1);
+        assert_eq!(errs[0].code(), ErrorCode::ExpectedExpression);
+        assert_eq!(errs[0].message(), "Expected expression");
+        assert_eq!(errs[0].span(), Span::new(1, 9, 1, 9));
+    }
+
+    #[test]
+    fn test_reports_all_errors() {
+        let source = "var a = ;\nprint 1 +;\nvar b = 2;\nprint b b;";
+        let mut scanner = Scanner::new(source);
+        let tokens = scanner.scan_tokens().unwrap();
+        let mut parser = Parser::new(tokens);
+        let errs = parser.parse().unwrap_err();
+
+        let lines: Vec<usize> = errs.iter().map(|err| err.span().start_line).collect();
+        assert_eq!(lines, vec![1, 2, 4]);
+    }
+
+    #[test]
+    fn test_recovers_inside_blocks() {
+        let source = "fun f() {\n  var a = ;\n  print a;\n}\nvar b = 1 var c = 2;";
+        let mut scanner = Scanner::new(source);
+        let tokens = scanner.scan_tokens().unwrap();
+        let mut parser = Parser::new(tokens);
+        let errs = parser.parse().unwrap_err();
+
+        // No cascade from the closing brace of the function or from the second 'var'
+        assert_eq!(errs.len(), 2);
+        assert_eq!(errs[0].span(), Span::new(2, 11, 2, 11));
+        assert_eq!(errs[1].code(), ErrorCode::ExpectedToken);
+        assert_eq!(errs[1].span(), Span::new(5, 11, 5, 13));
+    }
+
+    #[test]
+    fn test_recovers_at_statement_keywords() {
+        let source = "var a = 1\ntry {\n  print a;\n} catch (e) {\n  print e;\n}\nprint 2 2;";
+        let mut scanner = Scanner::new(source);
+        let tokens = scanner.scan_tokens().unwrap();
+        let mut parser = Parser::new(tokens);
+        let errs = parser.parse().unwrap_err();
+
+        // Parsing starts again at 'try' instead of skipping into its block
+        let lines: Vec<usize> = errs.iter().map(|err| err.span().start_line).collect();
+        assert_eq!(lines, vec![2, 7]);
+    }
+
+    #[test]
+    fn test_stray_closing_brace() {
+        let source = "print 1;\n}\nprint 2 2;";
+        let mut scanner = Scanner::new(source);
+        let tokens = scanner.scan_tokens().unwrap();
+        let mut parser = Parser::new(tokens);
+        let errs = parser.parse().unwrap_err();
+
+        assert_eq!(errs.len(), 2);
+        assert_eq!(errs[0].code(), ErrorCode::ExpectedExpression);
+        assert_eq!(errs[1].span().start_line, 3);
+    }
+
+    #[test]
+    fn test_map_literal_or_block() {
+        let source = "{\"a\": 1}[\"a\"];\n{ a; }\nvar m = {};";
+        let mut scanner = Scanner::new(source);
+        let tokens = scanner.scan_tokens().unwrap();
+        let mut parser = Parser::new(tokens);
+        let parsed = parser.parse().unwrap();
+
+        assert_eq!(parsed[0].to_string(), "(index (map (\"a\" 1)) \"a\")");
+        assert!(matches!(parsed[1], Stmt::Block { .. }));
+        assert!(matches!(
+            &parsed[2],
+            Stmt::Var {
+                initializer: Expr::Map { .. },
+                ..
+            }
+        ));
+    }
 }
@@ -1,3 +1,4 @@
+use crate::error::{ErrorCode, ValkyrieError};
 use crate::expr::Expr;
 use crate::scanner::Token;
 use crate::stmt::Stmt;
@@ -10,11 +11,21 @@ enum FunctionType {
     Method,
 }
 
+// A local variable, its slot is its position among the declarations of its scope, which is also
+// the order in which the interpreter defines them at runtime
+struct Local {
+    defined: bool,
+    slot: usize,
+}
+
 #[allow(dead_code)]
 pub struct Resolver {
-    scopes: Vec<HashMap<String, bool>>,
+    scopes: Vec<HashMap<String, Local>>,
     current_function: FunctionType,
-    locals: HashMap<usize, usize>,
+    // Number of loops around the current statement, inside the current function
+    loop_depth: usize,
+    // Expression id -> (scopes to walk up, slot in that scope)
+    locals: HashMap<usize, (usize, usize)>,
 }
 
 impl Resolver {
@@ -22,18 +33,24 @@ impl Resolver {
         Self {
             scopes: vec![],
             current_function: FunctionType::None,
+            loop_depth: 0,
             locals: HashMap::new(),
         }
     }
 
-    fn resolve_internal(&mut self, stmt: &Stmt) -> Result<(), String> {
+    fn resolve_internal(&mut self, stmt: &Stmt) -> Result<(), ValkyrieError> {
         match stmt {
-            Stmt::Block { statements: _ } => self.resolve_block(stmt)?,
+            Stmt::Block {
+                span: _,
+                statements: _,
+            } => self.resolve_block(stmt)?,
             Stmt::Var {
+                span: _,
                 name: _,
                 initializer: _,
             } => self.resolve_var(stmt)?,
             Stmt::Class {
+                span: _,
                 name,
                 methods,
                 superclass,
@@ -42,35 +59,46 @@ impl Resolver {
                 if let Some(super_expr) = superclass {
                     if let Expr::Variable {
                         id: _,
+                        span: _,
                         name: super_name,
                     } = super_expr
                     {
-                        if super_name.lexeme == name.lexeme {
-                            return Err("A class cannot inherit from itself".to_string());
+                        if super_name.name() == name.name() {
+                            return Err(ValkyrieError::resolve(
+                                ErrorCode::InheritFromSelf,
+                                "A class cannot inherit from itself".to_string(),
+                                super_name.span,
+                            ));
                         }
                     }
 
                     self.resolve_expr(super_expr)?;
-                    self.begin_scope();
-                    self.scopes
-                        .last_mut()
-                        .unwrap()
-                        .insert("super".to_string(), true);
                 }
 
-                // Resolving class
+                // Resolving class, in the scope it is declared in
                 self.declare(name)?;
                 self.define(name);
 
-                // Resolving methods
+                if superclass.is_some() {
+                    self.begin_scope();
+                    self.add_defined("super");
+                }
+
+                // Resolving methods, their names are not variables
                 self.begin_scope();
-                self.scopes
-                    .last_mut()
-                    .unwrap()
-                    .insert("this".to_string(), true);
+                self.add_defined("this");
                 for method in methods {
-                    let declaration = FunctionType::Method;
-                    self.resolve_function(method, declaration)?;
+                    if let Stmt::Function {
+                        span: _,
+                        name: _,
+                        params,
+                        body,
+                    } = method.as_ref()
+                    {
+                        self.resolve_function_helper(params, body, FunctionType::Method)?;
+                    } else {
+                        panic!("Something that was not a function was in the methods of a class");
+                    }
                 }
                 self.end_scope();
 
@@ -79,36 +107,117 @@ impl Resolver {
                 }
             }
             Stmt::Function {
+                span: _,
                 name: _,
                 params: _,
                 body: _,
             } => self.resolve_function(stmt, FunctionType::Function)?,
-            Stmt::CmdFunction { name: _, cmd: _ } => self.resolve_var(stmt)?,
-            Stmt::Expression { expression } => self.resolve_expr(expression)?,
+            Stmt::CmdFunction {
+                span: _,
+                name: _,
+                cmd: _,
+            } => self.resolve_var(stmt)?,
+            Stmt::Expression {
+                span: _,
+                expression,
+            } => self.resolve_expr(expression)?,
             Stmt::IfStmt {
+                span: _,
                 predicate: _,
                 then: _,
                 els: _,
             } => self.resolve_if_stmt(stmt)?,
-            Stmt::Print { expression } => self.resolve_expr(expression)?,
-            Stmt::ReturnStmt { keyword: _, value } => {
+            Stmt::Print {
+                span: _,
+                expression,
+            } => self.resolve_expr(expression)?,
+            Stmt::ReturnStmt {
+                span: _,
+                keyword,
+                value,
+            } => {
                 if self.current_function == FunctionType::None {
-                    return Err("Return statement is not allowed outside of a function".to_string());
+                    return Err(ValkyrieError::resolve(
+                        ErrorCode::ReturnOutsideFunction,
+                        "Return statement is not allowed outside of a function".to_string(),
+                        keyword.span,
+                    ));
                 }
 
                 if let Some(value) = value {
                     self.resolve_expr(value)?;
                 }
             }
-            Stmt::WhileStmt { condition, body } => {
+            Stmt::WhileStmt {
+                span: _,
+                condition,
+                body,
+                increment,
+            } => {
                 self.resolve_expr(condition)?;
+                self.loop_depth += 1;
                 self.resolve_internal(body.as_ref())?;
+                self.loop_depth -= 1;
+                if let Some(increment) = increment {
+                    self.resolve_expr(increment)?;
+                }
+            }
+            Stmt::Break { span: _, keyword } | Stmt::Continue { span: _, keyword } => {
+                if self.loop_depth == 0 {
+                    return Err(ValkyrieError::resolve(
+                        ErrorCode::OutsideLoop,
+                        format!("'{}' is not allowed outside of a loop", keyword.lexeme),
+                        keyword.span,
+                    ));
+                }
+            }
+            Stmt::Throw {
+                span: _,
+                keyword: _,
+                value,
+            } => self.resolve_expr(value)?,
+            Stmt::Try {
+                span: _,
+                body,
+                catch,
+                finally,
+            } => {
+                self.resolve_internal(body)?;
+                // The caught value lives in a scope of its own around the catch block
+                if let Some((name, catch_body)) = catch {
+                    self.begin_scope();
+                    self.declare(name)?;
+                    self.define(name);
+                    self.resolve_internal(catch_body)?;
+                    self.end_scope();
+                }
+                if let Some(finally) = finally {
+                    self.resolve_internal(finally)?;
+                }
+            }
+            Stmt::Import {
+                span,
+                path: _,
+                name,
+            } => {
+                // A module is run once, so its globals can only be declared once as well
+                if !self.scopes.is_empty() {
+                    return Err(ValkyrieError::resolve(
+                        ErrorCode::ImportOutsideTopLevel,
+                        "Imports can only appear at the top level".to_string(),
+                        *span,
+                    ));
+                }
+                if let Some(name) = name {
+                    self.declare(name)?;
+                    self.define(name);
+                }
             }
         }
         Ok(())
     }
 
-    fn resolve_many(&mut self, stmts: &Vec<&Stmt>) -> Result<(), String> {
+    fn resolve_many(&mut self, stmts: &Vec<&Stmt>) -> Result<(), ValkyrieError> {
         for stmt in stmts {
             self.resolve_internal(stmt)?;
         }
@@ -116,14 +225,20 @@ impl Resolver {
         Ok(())
     }
 
-    pub fn resolve(mut self, stmts: &Vec<&Stmt>) -> Result<HashMap<usize, usize>, String> {
+    pub fn resolve(
+        mut self,
+        stmts: &Vec<&Stmt>,
+    ) -> Result<HashMap<usize, (usize, usize)>, ValkyrieError> {
         self.resolve_many(stmts)?;
         Ok(self.locals)
     }
 
-    fn resolve_block(&mut self, stmt: &Stmt) -> Result<(), String> {
+    fn resolve_block(&mut self, stmt: &Stmt) -> Result<(), ValkyrieError> {
         match stmt {
-            Stmt::Block { statements } => {
+            Stmt::Block {
+                span: _,
+                statements,
+            } => {
                 self.begin_scope();
                 self.resolve_many(&statements.iter().map(|b| b.as_ref()).collect())?;
                 self.end_scope();
@@ -134,12 +249,22 @@ impl Resolver {
         Ok(())
     }
 
-    fn resolve_var(&mut self, stmt: &Stmt) -> Result<(), String> {
-        if let Stmt::Var { name, initializer } = stmt {
+    fn resolve_var(&mut self, stmt: &Stmt) -> Result<(), ValkyrieError> {
+        if let Stmt::Var {
+            span: _,
+            name,
+            initializer,
+        } = stmt
+        {
             self.declare(name)?;
             self.resolve_expr(initializer)?;
             self.define(name);
-        } else if let Stmt::CmdFunction {name, cmd: _} = stmt {
+        } else if let Stmt::CmdFunction {
+            span: _,
+            name,
+            cmd: _,
+        } = stmt
+        {
             self.declare(name)?;
             self.define(name);
         } else {
@@ -149,23 +274,30 @@ impl Resolver {
         Ok(())
     }
 
-    fn resolve_function(&mut self, stmt: &Stmt, fn_type: FunctionType) -> Result<(), String> {
-        if let Stmt::Function { name, params, body } = stmt {
+    fn resolve_function(
+        &mut self,
+        stmt: &Stmt,
+        fn_type: FunctionType,
+    ) -> Result<(), ValkyrieError> {
+        if let Stmt::Function {
+            span: _,
+            name,
+            params,
+            body,
+        } = stmt
+        {
             self.declare(name)?;
             self.define(name);
 
-            self.resolve_function_helper(
-                params,
-                &body.iter().map(|b| b.as_ref()).collect(),
-                fn_type,
-            )
+            self.resolve_function_helper(params, body, fn_type)
         } else {
             panic!("Wrong type in resolve function");
         }
     }
 
-    fn resolve_if_stmt(&mut self, stmt: &Stmt) -> Result<(), String> {
+    fn resolve_if_stmt(&mut self, stmt: &Stmt) -> Result<(), ValkyrieError> {
         if let Stmt::IfStmt {
+            span: _,
             predicate,
             then,
             els,
@@ -185,20 +317,26 @@ impl Resolver {
 
     fn resolve_function_helper(
         &mut self,
-        params: &Vec<Token>,
-        body: &Vec<&Stmt>,
+        params: &[Token],
+        body: &[Stmt],
         resolving_function: FunctionType,
-    ) -> Result<(), String> {
+    ) -> Result<(), ValkyrieError> {
         let enclosing_function = self.current_function;
+        let enclosing_loop_depth = self.loop_depth;
         self.current_function = resolving_function;
+        // A loop around a function declaration cannot be broken out of from its body
+        self.loop_depth = 0;
         self.begin_scope();
         for param in params {
             self.declare(param)?;
             self.define(param);
         }
-        self.resolve_many(body)?;
+        for stmt in body {
+            self.resolve_internal(stmt)?;
+        }
         self.end_scope();
         self.current_function = enclosing_function;
+        self.loop_depth = enclosing_loop_depth;
         Ok(())
     }
 
@@ -210,28 +348,54 @@ impl Resolver {
         self.scopes.pop().expect("Stack underflow");
     }
 
-    fn declare(&mut self, name: &Token) -> Result<(), String> {
+    fn declare(&mut self, name: &Token) -> Result<(), ValkyrieError> {
         let size = self.scopes.len();
         if self.scopes.is_empty() {
             return Ok(());
         }
 
-        if self.scopes[size - 1].contains_key(&name.lexeme.clone()) {
-            return Err("A variable with this name is already in scope".to_string());
+        if self.scopes[size - 1].contains_key(name.name()) {
+            return Err(ValkyrieError::resolve(
+                ErrorCode::AlreadyDeclared,
+                "A variable with this name is already in scope".to_string(),
+                name.span,
+            ));
         }
 
-        self.scopes[size - 1].insert(name.lexeme.clone(), false);
+        let slot = self.scopes[size - 1].len();
+        self.scopes[size - 1].insert(
+            name.name().to_string(),
+            Local {
+                defined: false,
+                slot,
+            },
+        );
 
         Ok(())
     }
 
+    // Declares and defines a variable the interpreter binds by itself, like this and super
+    fn add_defined(&mut self, name: &str) {
+        let scope = self.scopes.last_mut().unwrap();
+        let slot = scope.len();
+        scope.insert(
+            name.to_string(),
+            Local {
+                defined: true,
+                slot,
+            },
+        );
+    }
+
     fn define(&mut self, name: &Token) {
         if self.scopes.is_empty() {
             return;
         }
 
         let size = self.scopes.len();
-        self.scopes[size - 1].insert(name.lexeme.clone(), true);
+        if let Some(local) = self.scopes[size - 1].get_mut(name.name()) {
+            local.defined = true;
+        }
     }
 
     // (i > j) may require different resolution distances
@@ -240,16 +404,22 @@ impl Resolver {
     // (i > 3) ->
     //         -> i -> try to resolve
     //         -> 3 -> try to resolve (trivial)
-    fn resolve_expr(&mut self, expr: &Expr) -> Result<(), String> {
+    fn resolve_expr(&mut self, expr: &Expr) -> Result<(), ValkyrieError> {
         match expr {
-            Expr::Variable { id: _, name: _ } => self.resolve_expr_var(expr, expr.get_id()),
+            Expr::Variable {
+                id: _,
+                span: _,
+                name: _,
+            } => self.resolve_expr_var(expr, expr.get_id()),
             Expr::Assign {
                 id: _,
+                span: _,
                 name: _,
                 value: _,
             } => self.resolve_expr_assign(expr, expr.get_id()),
             Expr::Binary {
                 id: _,
+                span: _,
                 left,
                 operator: _,
                 right,
@@ -259,6 +429,7 @@ impl Resolver {
             }
             Expr::Call {
                 id: _,
+                span: _,
                 callee,
                 paren: _,
                 arguments,
@@ -272,13 +443,66 @@ impl Resolver {
             }
             Expr::Get {
                 id: _,
+                span: _,
                 object,
                 name: _,
             } => self.resolve_expr(object),
-            Expr::Grouping { id: _, expression } => self.resolve_expr(expression),
-            Expr::Literal { id: _, value: _ } => Ok(()),
+            Expr::Grouping {
+                id: _,
+                span: _,
+                expression,
+            } => self.resolve_expr(expression),
+            Expr::Array {
+                id: _,
+                span: _,
+                elements,
+            } => {
+                for element in elements {
+                    self.resolve_expr(element)?;
+                }
+
+                Ok(())
+            }
+            Expr::Map {
+                id: _,
+                span: _,
+                entries,
+            } => {
+                for (key, value) in entries {
+                    self.resolve_expr(key)?;
+                    self.resolve_expr(value)?;
+                }
+
+                Ok(())
+            }
+            Expr::Index {
+                id: _,
+                span: _,
+                object,
+                index,
+            } => {
+                self.resolve_expr(object)?;
+                self.resolve_expr(index)
+            }
+            Expr::IndexSet {
+                id: _,
+                span: _,
+                object,
+                index,
+                value,
+            } => {
+                self.resolve_expr(value)?;
+                self.resolve_expr(object)?;
+                self.resolve_expr(index)
+            }
+            Expr::Literal {
+                id: _,
+                span: _,
+                value: _,
+            } => Ok(()),
             Expr::Logical {
                 id: _,
+                span: _,
                 left,
                 operator: _,
                 right,
@@ -288,6 +512,7 @@ impl Resolver {
             }
             Expr::Set {
                 id: _,
+                span: _,
                 object,
                 name: _,
                 value,
@@ -295,49 +520,76 @@ impl Resolver {
                 self.resolve_expr(value)?;
                 self.resolve_expr(object)
             }
-            Expr::This { id: _, keyword } => {
+            Expr::This {
+                id: _,
+                span: _,
+                keyword,
+            } => {
                 if self.current_function != FunctionType::Method {
-                    return Err("Cannot use 'this' keyword outside of a class".to_string());
+                    return Err(ValkyrieError::resolve(
+                        ErrorCode::ThisOutsideClass,
+                        "Cannot use 'this' keyword outside of a class".to_string(),
+                        keyword.span,
+                    ));
                 }
                 self.resolve_local(keyword, expr.get_id())
             }
             Expr::Super {
                 id: _,
+                span: _,
                 keyword,
                 method: _,
             } => {
                 if self.current_function != FunctionType::Method {
-                    return Err("Cannot use 'super' keyword outside of a class".to_string());
+                    return Err(ValkyrieError::resolve(
+                        ErrorCode::SuperOutsideClass,
+                        "Cannot use 'super' keyword outside of a class".to_string(),
+                        keyword.span,
+                    ));
                 }
-                if self.scopes.len() < 3 || !self.scopes[self.scopes.len() - 3].contains_key("super") {
-                    return Err("Class has no superclass".to_string());
+                if self.scopes.len() < 3
+                    || !self.scopes[self.scopes.len() - 3].contains_key("super")
+                {
+                    return Err(ValkyrieError::resolve(
+                        ErrorCode::NoSuperclass,
+                        "Class has no superclass".to_string(),
+                        keyword.span,
+                    ));
                 }
                 self.resolve_local(keyword, expr.get_id())
             }
             Expr::Unary {
                 id: _,
+                span: _,
                 operator: _,
                 right,
             } => self.resolve_expr(right),
             Expr::AnonFunction {
                 id: _,
+                span: _,
                 paren: _,
                 arguments,
                 body,
-            } => self.resolve_function_helper(
-                arguments,
-                &body.iter().map(|b| b.as_ref()).collect(),
-                FunctionType::Function,
-            ),
+            } => self.resolve_function_helper(arguments, body, FunctionType::Function),
         }
     }
 
-    fn resolve_expr_var(&mut self, expr: &Expr, resolve_id: usize) -> Result<(), String> {
+    fn resolve_expr_var(&mut self, expr: &Expr, resolve_id: usize) -> Result<(), ValkyrieError> {
         match expr {
-            Expr::Variable { id: _, name } => {
+            Expr::Variable {
+                id: _,
+                span: _,
+                name,
+            } => {
                 if !self.scopes.is_empty() {
-                    if let Some(false) = self.scopes[self.scopes.len() - 1].get(&name.lexeme) {
-                        return Err("Can't read local variable in its own initializer".to_string());
+                    if let Some(Local { defined: false, .. }) =
+                        self.scopes[self.scopes.len() - 1].get(name.name())
+                    {
+                        return Err(ValkyrieError::resolve(
+                            ErrorCode::ReadInOwnInitializer,
+                            "Can't read local variable in its own initializer".to_string(),
+                            name.span,
+                        ));
                     }
                 }
 
@@ -345,27 +597,31 @@ impl Resolver {
             }
             Expr::Call {
                 id: _,
+                span: _,
                 callee,
                 paren: _,
                 arguments: _,
             } => match callee.as_ref() {
-                Expr::Variable { id: _, name } => self.resolve_local(&name, resolve_id),
+                Expr::Variable {
+                    id: _,
+                    span: _,
+                    name,
+                } => self.resolve_local(name, resolve_id),
                 _ => panic!("Wrong type in resolve_expr_var"),
             },
             _ => panic!("Wrong type in resolve_expr_var"),
         }
     }
 
-    fn resolve_local(&mut self, name: &Token, resolve_id: usize) -> Result<(), String> {
+    fn resolve_local(&mut self, name: &Token, resolve_id: usize) -> Result<(), ValkyrieError> {
         let size = self.scopes.len();
         if size == 0 {
             return Ok(());
         }
 
         for i in (0..=(size - 1)).rev() {
-            let scope = &self.scopes[i];
-            if scope.contains_key(&name.lexeme) {
-                self.locals.insert(resolve_id, size - 1 - i);
+            if let Some(local) = self.scopes[i].get(name.name()) {
+                self.locals.insert(resolve_id, (size - 1 - i, local.slot));
                 return Ok(());
             }
         }
@@ -374,8 +630,14 @@ impl Resolver {
         Ok(())
     }
 
-    fn resolve_expr_assign(&mut self, expr: &Expr, resolve_id: usize) -> Result<(), String> {
-        if let Expr::Assign { id: _, name, value } = expr {
+    fn resolve_expr_assign(&mut self, expr: &Expr, resolve_id: usize) -> Result<(), ValkyrieError> {
+        if let Expr::Assign {
+            id: _,
+            span: _,
+            name,
+            value,
+        } = expr
+        {
             self.resolve_expr(value.as_ref())?;
             self.resolve_local(name, resolve_id)?;
         } else {
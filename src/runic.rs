@@ -0,0 +1,291 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+// Keyword symbols, as typed with the Valkyrie-Keymap app
+pub const KEYWORD_RUNES: [(&str, &str); 24] = [
+    ("var", "𖤍"),
+    ("fun", "♅"),
+    ("if", "↟↟"),
+    ("else", "↟↡"),
+    ("while", "↟↠"),
+    ("for", "𒌐"),
+    ("return", "↡"),
+    ("and", "↠↠"),
+    ("class", "🕈"),
+    ("false", "☽"),
+    ("null", "☽𖤍"),
+    ("or", "↞↞"),
+    ("print", "♅♅"),
+    ("super", "🕈↟"),
+    ("this", "🕈↡"),
+    ("true", "𖤓"),
+    ("break", "↟↞"),
+    ("continue", "↞↠"),
+    ("throw", "↡↡"),
+    ("try", "↠↟"),
+    ("catch", "↠↡"),
+    ("finally", "↠↞"),
+    ("import", "↞↟"),
+    ("from", "↞↡"),
+];
+
+const LOWERCASE_RUNES: [(char, char); 26] = [
+    ('a', 'ᚨ'),
+    ('b', 'ᛒ'),
+    ('c', 'ᚲ'),
+    ('d', 'ᚦ'),
+    ('e', 'ᛅ'),
+    ('f', 'ᚠ'),
+    ('g', 'ᛞ'),
+    ('h', 'ᚺ'),
+    ('i', 'ᛁ'),
+    ('j', 'ᚴ'),
+    ('k', 'ᛘ'),
+    ('l', 'ᛐ'),
+    ('m', 'ᛖ'),
+    ('n', 'ᚾ'),
+    ('o', 'ᛜ'),
+    ('p', 'ᛩ'),
+    ('q', 'ᛶ'),
+    ('r', 'ᛃ'),
+    ('s', 'ᛋ'),
+    ('t', 'ᛄ'),
+    ('u', 'ᚢ'),
+    ('v', 'ᛡ'),
+    ('w', 'ᚳ'),
+    ('x', '×'),
+    ('y', 'ᛣ'),
+    ('z', 'ᛇ'),
+];
+
+const UPPERCASE_RUNES: [(char, char); 26] = [
+    ('A', 'ᚪ'),
+    ('B', 'ᛔ'),
+    ('C', 'ᛈ'),
+    ('D', 'ᚣ'),
+    ('E', 'ᚯ'),
+    ('F', 'ᚡ'),
+    ('G', 'ᛥ'),
+    ('H', 'ᚻ'),
+    ('I', 'ᛂ'),
+    ('J', 'ᚵ'),
+    ('K', 'ᛯ'),
+    ('L', 'ᛚ'),
+    ('M', 'ᛗ'),
+    ('N', 'ᚬ'),
+    ('O', 'ᛟ'),
+    ('P', 'ᚹ'),
+    ('Q', 'ᚿ'),
+    ('R', 'ᚱ'),
+    ('S', 'ᛊ'),
+    ('T', 'ᛏ'),
+    ('U', 'ᚤ'),
+    ('V', 'ᛤ'),
+    ('W', 'ᛠ'),
+    ('X', 'ᚷ'),
+    ('Y', 'ᛉ'),
+    ('Z', 'ᛢ'),
+];
+
+fn get_runes_hashmap() -> HashMap<char, char> {
+    LOWERCASE_RUNES
+        .iter()
+        .chain(UPPERCASE_RUNES.iter())
+        .cloned()
+        .collect()
+}
+
+pub fn get_latin_hashmap() -> HashMap<char, char> {
+    LOWERCASE_RUNES
+        .iter()
+        .chain(UPPERCASE_RUNES.iter())
+        .map(|(latin, rune)| (*rune, *latin))
+        .collect()
+}
+
+fn is_identifier_char(ch: char) -> bool {
+    ch.is_ascii_alphanumeric() || ch == '_'
+}
+
+// Copies a string literal or a comment starting at `start` verbatim into `result` and returns
+// the byte index right after it, or None if `start` does not begin one of them. Comments are the
+// ones the scanner skips: `//` and `##` to the end of the line and `/* */` blocks
+fn copy_verbatim(source: &str, start: usize, result: &mut String) -> Option<usize> {
+    let rest = &source[start..];
+    let end = if let Some(literal) = rest.strip_prefix('"') {
+        match literal.find('"') {
+            Some(idx) => start + idx + 2,
+            None => source.len(),
+        }
+    } else if rest.starts_with("//") || rest.starts_with("##") {
+        match rest.find('\n') {
+            Some(idx) => start + idx,
+            None => source.len(),
+        }
+    } else {
+        let block = rest.strip_prefix("/*")?;
+        match block.find("*/") {
+            Some(idx) => start + idx + 4,
+            None => source.len(),
+        }
+    };
+
+    result.push_str(&source[start..end]);
+    Some(end)
+}
+
+// Runic -> Latin, string literals and comments are left untouched
+pub fn to_latin(source: &str) -> String {
+    let latin = get_latin_hashmap();
+
+    // Longest symbols first so that "♅♅" is not read as two "♅"
+    let mut keywords = KEYWORD_RUNES.to_vec();
+    keywords.sort_by_key(|(_, symbol)| std::cmp::Reverse(symbol.len()));
+
+    let mut result = String::with_capacity(source.len());
+    let mut current = 0;
+    while current < source.len() {
+        if let Some(end) = copy_verbatim(source, current, &mut result) {
+            current = end;
+            continue;
+        }
+
+        let rest = &source[current..];
+        if let Some((keyword, symbol)) = keywords.iter().find(|(_, s)| rest.starts_with(s)) {
+            result.push_str(keyword);
+            current += symbol.len();
+            continue;
+        }
+
+        let c = rest.chars().next().unwrap();
+        result.push(*latin.get(&c).unwrap_or(&c));
+        current += c.len_utf8();
+    }
+
+    result
+}
+
+// Latin -> runic, string literals and comments are left untouched
+pub fn to_runic(source: &str) -> String {
+    let runes = get_runes_hashmap();
+    let keywords: HashMap<&str, &str> = KEYWORD_RUNES.iter().cloned().collect();
+
+    let mut result = String::with_capacity(source.len());
+    let mut current = 0;
+    while current < source.len() {
+        if let Some(end) = copy_verbatim(source, current, &mut result) {
+            current = end;
+            continue;
+        }
+
+        let rest = &source[current..];
+        let c = rest.chars().next().unwrap();
+        if c.is_ascii_alphabetic() || c == '_' {
+            // Read the whole word, keywords are only replaced when they stand on their own
+            let len = rest
+                .find(|ch| !is_identifier_char(ch))
+                .unwrap_or(rest.len());
+            let word = &rest[..len];
+            match keywords.get(word) {
+                Some(symbol) => result.push_str(symbol),
+                None => result.extend(word.chars().map(|ch| *runes.get(&ch).unwrap_or(&ch))),
+            }
+            current += len;
+            continue;
+        }
+
+        result.push(c);
+        current += c.len_utf8();
+    }
+
+    result
+}
+
+// Writes the translation of a .runic file to a .valkyrie file next to it (or the other way
+// around) and returns the path of the new file
+pub fn translate_file(path: &str) -> Result<String, String> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(msg) => return Err(format!("Could not read {}: {}", path, msg)),
+    };
+
+    // Only the extension changes, directories may have one too
+    let file = Path::new(path);
+    let (new_path, converted) = match file.extension().and_then(|extension| extension.to_str()) {
+        Some("runic") => (file.with_extension("valkyrie"), to_latin(&contents)),
+        Some("valkyrie") => (file.with_extension("runic"), to_runic(&contents)),
+        _ => return Err("Invalid file extension for conversion".to_string()),
+    };
+    let new_path = new_path.display().to_string();
+
+    match fs::write(&new_path, converted) {
+        Ok(_) => Ok(new_path),
+        Err(msg) => Err(format!("Could not write {}: {}", new_path, msg)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keywords_to_latin() {
+        let source = "𖤍 a = ☽𖤍;\n♅♅ a;\n↟↟ (𖤓) ♅♅ 1; ↟↡ ♅♅ 2;";
+        assert_eq!(
+            to_latin(source),
+            "var a = null;\nprint a;\nif (true) print 1; else print 2;"
+        );
+    }
+
+    #[test]
+    fn letters_to_latin() {
+        assert_eq!(to_latin("𖤍 ᛗᛣᛡᚨᛃ = 34;"), "var Myvar = 34;");
+        assert_eq!(to_latin("🕈↡.ᛩᛃᛜᛩ"), "this.prop");
+    }
+
+    #[test]
+    fn strings_and_comments_untouched() {
+        let source = "♅♅ \"ᚠ ♅♅ ᚠ\"; // ↟↟ ᚨ\n♅♅ ᚨ;";
+        assert_eq!(to_latin(source), "print \"ᚠ ♅♅ ᚠ\"; // ↟↟ ᚨ\nprint a;");
+    }
+
+    #[test]
+    fn hash_comments_untouched() {
+        let source = "♅♅ ᚨ; ## ↟↟ ᚨ\n♅♅ ᚨ;";
+        assert_eq!(to_latin(source), "print a; ## ↟↟ ᚨ\nprint a;");
+        assert_eq!(to_runic("print a; ## if a"), "♅♅ ᚨ; ## if a");
+    }
+
+    #[test]
+    fn block_comments_untouched() {
+        let source = "/* ♅♅ ᚨ\n↟↟ */ ♅♅ ᚨ; /* ᚨ";
+        assert_eq!(to_latin(source), "/* ♅♅ ᚨ\n↟↟ */ print a; /* ᚨ");
+        assert_eq!(to_runic("/* var\nif */ var a;"), "/* var\nif */ 𖤍 ᚨ;");
+    }
+
+    #[test]
+    fn latin_to_runic() {
+        let source = "var this_is = \"var\"; // var\nprint this.x;";
+        assert_eq!(to_runic(source), "𖤍 ᛄᚺᛁᛋ_ᛁᛋ = \"var\"; // var\n♅♅ 🕈↡.×;");
+    }
+
+    #[test]
+    fn round_trip() {
+        let source = "class Greeter < Base {\n  greet(name) {\n    return \"Hi \" + name;\n  }\n}";
+        assert_eq!(to_latin(&to_runic(source)), source);
+    }
+
+    #[test]
+    fn translate_file_changes_only_the_extension() {
+        let dir = std::env::temp_dir().join(format!("valkyrie-{}.runic", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("x.runic");
+        fs::write(&file, "♅♅ ᚨ;").unwrap();
+
+        let new_path = translate_file(&file.display().to_string()).unwrap();
+        assert_eq!(new_path, dir.join("x.valkyrie").display().to_string());
+        assert_eq!(fs::read_to_string(&new_path).unwrap(), "print a;");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
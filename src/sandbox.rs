@@ -0,0 +1,131 @@
+use crate::diagnostic::Span;
+use crate::error::{ErrorCode, ValkyrieError};
+use std::path::{Path, PathBuf};
+
+// What a sandboxed program may reach outside of the interpreter
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum Permission {
+    #[default]
+    All,
+    Nothing,
+    // The programs command functions may run, or the directories whose files may be read
+    Only(Vec<String>),
+}
+
+// Limits what command functions and imports can do, so that untrusted scripts can be run. By
+// default everything is allowed
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Sandbox {
+    pub commands: Permission,
+    pub files: Permission,
+}
+
+impl Sandbox {
+    // Denies every command and file, the allowlists can then be added to
+    pub fn strict() -> Self {
+        Self {
+            commands: Permission::Nothing,
+            files: Permission::Nothing,
+        }
+    }
+
+    pub fn allow_command(&mut self, program: &str) {
+        allow(&mut self.commands, program);
+    }
+
+    pub fn allow_dir(&mut self, dir: &str) {
+        allow(&mut self.files, dir);
+    }
+
+    // Programs are compared with the first word of the command as it is written, so that allowing
+    // echo does not allow ./echo
+    pub fn check_command(&self, program: &str) -> Result<(), ValkyrieError> {
+        match &self.commands {
+            Permission::All => Ok(()),
+            Permission::Only(programs) if programs.iter().any(|allowed| allowed == program) => {
+                Ok(())
+            }
+            Permission::Nothing => Err(denied(format!(
+                "Sandbox policy denies command functions, cannot run {}",
+                program
+            ))),
+            Permission::Only(programs) => {
+                Err(denied(format!("Sandbox policy denies running {}", program))
+                    .with_note(format!("Allowed commands: {}", programs.join(", "))))
+            }
+        }
+    }
+
+    // Files are allowed when they are inside one of the directories, after following links
+    pub fn check_file(&self, file: &Path, span: Span) -> Result<(), ValkyrieError> {
+        let name = file
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let dirs = match &self.files {
+            Permission::All => return Ok(()),
+            Permission::Nothing => {
+                return Err(denied(format!(
+                    "Sandbox policy denies file access, cannot read {}",
+                    name
+                ))
+                .with_span(span))
+            }
+            Permission::Only(dirs) => dirs,
+        };
+        let inside = dirs
+            .iter()
+            .filter_map(|dir| PathBuf::from(dir).canonicalize().ok())
+            .any(|dir| file.starts_with(dir));
+        if inside {
+            return Ok(());
+        }
+        Err(denied(format!("Sandbox policy denies reading {}", name))
+            .with_span(span)
+            .with_note(format!("Allowed directories: {}", dirs.join(", "))))
+    }
+}
+
+fn allow(permission: &mut Permission, name: &str) {
+    match permission {
+        Permission::All => {}
+        Permission::Nothing => *permission = Permission::Only(vec![name.to_string()]),
+        Permission::Only(names) => names.push(name.to_string()),
+    }
+}
+
+// The span is the one of the call, filled in by the caller when it is a native
+fn denied(message: String) -> ValkyrieError {
+    ValkyrieError::runtime(ErrorCode::SandboxDenied, message, Span::default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn allowlists_start_from_nothing() {
+        let mut sandbox = Sandbox::strict();
+        assert!(sandbox.check_command("echo").is_err());
+        sandbox.allow_command("echo");
+        assert!(sandbox.check_command("echo").is_ok());
+        let err = sandbox.check_command("/bin/echo").unwrap_err();
+        assert_eq!(err.code(), ErrorCode::SandboxDenied);
+        assert_eq!(err.message(), "Sandbox policy denies running /bin/echo");
+
+        assert!(Sandbox::default().check_command("rm").is_ok());
+    }
+
+    #[test]
+    fn files_must_be_inside_allowed_dirs() {
+        let dir = std::env::current_dir().unwrap();
+        let file = dir.join("src").join("main.rs");
+        let mut sandbox = Sandbox::strict();
+        assert!(sandbox.check_file(&file, Span::default()).is_err());
+        sandbox.allow_dir("src");
+        assert!(sandbox.check_file(&file, Span::default()).is_ok());
+        assert!(sandbox
+            .check_file(&dir.join("Cargo.toml"), Span::default())
+            .is_err());
+    }
+}
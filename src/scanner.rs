@@ -1,126 +1,116 @@
+use crate::diagnostic::Span;
+use crate::error::{ErrorCode, ValkyrieError};
+use crate::runic::{get_latin_hashmap, KEYWORD_RUNES};
 use std::collections::HashMap;
+use std::iter::Peekable;
+use std::str::CharIndices;
 use std::string::String;
 
 fn is_digit(ch: char) -> bool {
-    ch as u8 >= '0' as u8 && ch as u8 <= '9' as u8
+    ch.is_ascii_digit()
 }
 
 fn is_alpha(ch: char) -> bool {
-    let uch = ch as u8;
-    (uch >= 'a' as u8 && uch <= 'z' as u8) || (uch >= 'A' as u8 && uch <= 'Z' as u8) || (ch == '_')
+    ch.is_ascii_alphabetic() || ch == '_'
 }
 
-// fn get_latin_char(ch: char) -> Option<char> {
-//     let runes: HashMap<char, char> = HashMap::from([
-//         ('ᚨ', 'a'),
-//         ('ᛒ', 'b'),
-//         ('ᚲ', 'c'),
-//         ('ᛞ', 'd'),
-//         ('ᚯ', 'e'),
-//         ('ᚡ', 'f'),
-//         ('ᚷ', 'g'),
-//         ('ᚻ', 'h'),
-//         ('ᛂ', 'i'),
-//         ('ᛃ', 'j'),
-//         ('ᛘ', 'k'),
-//         ('ᛚ', 'l'),
-//         ('ᛖ', 'm'),
-//         ('ᚾ', 'n'),
-//         ('ᛜ', 'o'),
-//         ('ᛩ', 'p'),
-//         ('ᛶ', 'q'),
-//         ('ᛄ', 'r'),
-//         ('ᛋ', 's'),
-//         ('ᛅ', 't'),
-//         ('ᚦ', 'u'),
-//         ('ᚤ', 'v'),
-//         ('ᚳ', 'w'),
-//         ('ᛯ', 'x'),
-//         ('ᚴ', 'y'),
-//         ('ᛇ', 'z'),
-//         ('ᚪ', 'A'),
-//         ('ᛔ', 'B'),
-//         ('ᛈ', 'C'),
-//         ('ᚺ', 'D'),
-//         ('ᛑ', 'E'),
-//         ('ᛣ', 'F'),
-//         ('ᛇ', 'G'),
-//         ('ᛡ', 'H'),
-//         ('ᛥ', 'I'),
-//         ('ᚠ', 'J'),
-//         ('ᚵ', 'K'),
-//         ('ᛚ', 'L'),
-//         ('ᛗ', 'M'),
-//         ('ᚬ', 'N'),
-//         ('ᛟ', 'O'),
-//         ('ᚹ', 'P'),
-//         ('ᚿ', 'Q'),
-//         ('ᚱ', 'R'),
-//         ('ᛊ', 'S'),
-//         ('ᛏ', 'T'),
-//         ('ᚮ', 'U'),
-//         ('ᚢ', 'V'),
-//         ('ᛠ', 'W'),
-//         ('ᛤ', 'X'),
-//         ('ᛉ', 'Y'),
-//         ('ᛢ', 'Z'),
-//     ]);
-
-//     runes.get(&ch).cloned()
-// }
-
 fn is_alpha_numeric(ch: char) -> bool {
     is_alpha(ch) || is_digit(ch)
 }
 
-// TODO: Add runic keywords
 fn get_keywords_hashmap() -> HashMap<&'static str, TokenType> {
     HashMap::from([
         ("and", And),
+        ("break", Break),
+        ("catch", Catch),
         ("class", Class),
+        ("continue", Continue),
         ("else", Else),
         ("false", False),
+        ("finally", Finally),
         ("for", For),
+        ("from", From),
         ("fun", Fun),
         ("if", If),
+        ("import", Import),
         ("null", Null),
         ("or", Or),
         ("print", Print),
         ("return", Return),
         ("super", Super),
         ("this", This),
+        ("throw", Throw),
         ("true", True),
+        ("try", Try),
         ("var", Var),
         ("while", While),
     ])
 }
 
-pub struct Scanner {
-    source: String,
+// Runic keyword symbols paired with their token type, longest symbols first so that "♅♅" is
+// not scanned as two "♅"
+fn get_runic_keywords() -> Vec<(&'static str, TokenType)> {
+    let keywords = get_keywords_hashmap();
+    let mut runic_keywords: Vec<(&'static str, TokenType)> = KEYWORD_RUNES
+        .iter()
+        .map(|(keyword, symbol)| (*symbol, keywords[keyword]))
+        .collect();
+    runic_keywords.sort_by_key(|(symbol, _)| std::cmp::Reverse(symbol.chars().count()));
+
+    runic_keywords
+}
+
+pub struct Scanner<'a> {
+    source: &'a str,
+    chars: Peekable<CharIndices<'a>>,
     tokens: Vec<Token>,
+    // Byte offsets into source
     start: usize,
     current: usize,
+    // Position of the first char of the current lexeme and of the last char consumed
+    start_line: usize,
+    start_column: usize,
     line: usize,
+    column: usize,
+    // Marks the spans, for the files that are imported
+    file: usize,
 
     keywords: HashMap<&'static str, TokenType>,
+    runic_keywords: Vec<(&'static str, TokenType)>,
+    latin: HashMap<char, char>,
 }
 
-impl Scanner {
-    pub fn new(source: &str) -> Self {
+impl<'a> Scanner<'a> {
+    pub fn new(source: &'a str) -> Self {
         Self {
-            source: source.to_string(),
+            source,
+            chars: source.char_indices().peekable(),
             tokens: vec![],
             start: 0,
             current: 0,
+            start_line: 1,
+            start_column: 1,
             line: 1,
+            column: 0,
+            file: 0,
             keywords: get_keywords_hashmap(),
+            runic_keywords: get_runic_keywords(),
+            latin: get_latin_hashmap(),
         }
     }
 
-    pub fn scan_tokens(self: &mut Self) -> Result<Vec<Token>, String> {
+    // Scans an imported file, see diagnostic::add_source
+    pub fn with_file(mut self, file: usize) -> Self {
+        self.file = file;
+        self
+    }
+
+    pub fn scan_tokens(&mut self) -> Result<Vec<Token>, Vec<ValkyrieError>> {
         let mut errors = vec![];
         while !self.is_at_end() {
             self.start = self.current;
+            self.start_line = self.line;
+            self.start_column = self.column + 1;
             match self.scan_token() {
                 Ok(_) => (),
                 Err(msg) => errors.push(msg),
@@ -131,28 +121,22 @@ impl Scanner {
             token_type: Eof,
             lexeme: "".to_string(),
             literal: None,
-            line_number: self.line,
+            span: Span::new(self.line, self.column + 1, self.line, self.column + 1)
+                .in_file(self.file),
         });
 
-        if errors.len() > 0 {
-            let mut joined = "".to_string();
-            for error in errors {
-                joined.push_str(&error);
-                joined.push_str("\n");
-            }
-            return Err(joined);
+        if !errors.is_empty() {
+            return Err(errors);
         }
 
         Ok(self.tokens.clone())
     }
 
-    // let test = 0.01;
-
-    fn is_at_end(self: &Self) -> bool {
-        self.current >= self.source.len()
+    fn is_at_end(&mut self) -> bool {
+        self.chars.peek().is_none()
     }
 
-    fn scan_token(self: &mut Self) -> Result<(), String> {
+    fn scan_token(&mut self) -> Result<(), ValkyrieError> {
         let c = self.advance();
 
         match c {
@@ -160,6 +144,9 @@ impl Scanner {
             ')' => self.add_token(RightParen),
             '{' => self.add_token(LeftBrace),
             '}' => self.add_token(RightBrace),
+            '[' => self.add_token(LeftBracket),
+            ']' => self.add_token(RightBracket),
+            ':' => self.add_token(Colon),
             ',' => self.add_token(Comma),
             '.' => self.add_token(Dot),
             '-' => self.add_token(Minus),
@@ -205,10 +192,7 @@ impl Scanner {
             }
             '#' => {
                 if self.char_match('#') {
-                    loop {
-                        if self.peek() == '\n' || self.is_at_end() {
-                            break;
-                        }
+                    while self.peek() != '\n' && !self.is_at_end() {
                         self.advance();
                     }
                 } else {
@@ -224,9 +208,6 @@ impl Scanner {
                 } else if self.char_match('*') {
                     // Comentario de múltiples líneas
                     while !(self.peek() == '*' && self.peek_next() == '/') && !self.is_at_end() {
-                        if self.peek() == '\n' {
-                            self.line += 1;
-                        }
                         self.advance();
                     }
                     // Consumir el '*/'
@@ -242,26 +223,27 @@ impl Scanner {
                 if self.char_match('>') {
                     self.add_token(Pipe);
                 } else {
-                    return Err(format!("Expected '>' at line {}", self.line));
+                    return Err(self.error(
+                        ErrorCode::UnexpectedCharacter,
+                        "Expected '>' after '|'".to_string(),
+                    ));
                 }
             }
-            ' ' | '\r' | '\t' => {}
-            '\n' => self.line += 1,
+            ' ' | '\r' | '\t' | '\n' => {}
             '"' => self.string()?,
 
             c => {
                 if is_digit(c) {
                     self.number()?;
-                } else if is_alpha(c) {
+                } else if let Some(token_type) = self.runic_keyword(c) {
+                    self.add_token(token_type);
+                } else if is_alpha(c) || self.is_rune(c) {
                     self.identifier();
-                // } else if let Some(latin_char) = get_latin_char(c) {
-                //     // Simulate that the character is the Latin counterpart
-                //     self.start = self.current - 1; // Reset to start scanning from this rune
-                //     self.source
-                //         .replace_range(self.start..self.current, &latin_char.to_string());
-                //     self.identifier(); // Process as if it was a normal Latin character
                 } else {
-                    return Err(format!("Unrecognized char at line {}: {}", self.line, c));
+                    return Err(self.error(
+                        ErrorCode::UnexpectedCharacter,
+                        format!("Unrecognized char: {}", c),
+                    ));
                 }
             }
         }
@@ -269,20 +251,47 @@ impl Scanner {
         Ok(())
     }
 
+    fn is_rune(&self, ch: char) -> bool {
+        self.latin.contains_key(&ch)
+    }
+
+    fn runic_keyword(&mut self, first: char) -> Option<TokenType> {
+        let rest = &self.source[self.current..];
+        let (symbol, token_type) = self.runic_keywords.iter().find(|(symbol, _)| {
+            symbol.starts_with(first) && rest.starts_with(&symbol[first.len_utf8()..])
+        })?;
+
+        let (remaining, token_type) = (symbol.chars().count() - 1, *token_type);
+        for _ in 0..remaining {
+            self.advance();
+        }
+
+        Some(token_type)
+    }
+
     fn identifier(&mut self) {
-        while is_alpha_numeric(self.peek()) {
+        loop {
+            let c = self.peek();
+            if !is_alpha_numeric(c) && !self.is_rune(c) {
+                break;
+            }
             self.advance();
         }
 
-        let substring = &self.source[self.start..self.current];
-        if let Some(&t_type) = self.keywords.get(substring) {
+        // Runes are read as their Latin letters so that "ᚨ" and "a" name the same variable, the
+        // lexeme keeps the spelling in the source
+        let name: String = self.source[self.start..self.current]
+            .chars()
+            .map(|ch| *self.latin.get(&ch).unwrap_or(&ch))
+            .collect();
+        if let Some(&t_type) = self.keywords.get(name.as_str()) {
             self.add_token(t_type);
         } else {
-            self.add_token(Identifier);
+            self.add_token_lit(Identifier, Some(Name(name)));
         }
     }
 
-    fn number(self: &mut Self) -> Result<(), String> {
+    fn number(&mut self) -> Result<(), ValkyrieError> {
         while is_digit(self.peek()) {
             self.advance();
         }
@@ -298,34 +307,36 @@ impl Scanner {
         let value = substring.parse::<f64>();
         match value {
             Ok(value) => self.add_token_lit(Number, Some(FValue(value))),
-            Err(_) => return Err(format!("Could not parse number: {}", substring)),
+            Err(_) => {
+                let msg = format!("Could not parse number: {}", substring);
+                return Err(self.error(ErrorCode::InvalidNumber, msg));
+            }
         }
 
         Ok(())
     }
 
-    fn peek_next(self: &Self) -> char {
-        if self.current + 1 >= self.source.len() {
-            return '\0';
-        }
-
-        self.source.chars().nth(self.current + 1).unwrap()
+    fn peek_next(&self) -> char {
+        let mut chars = self.chars.clone();
+        chars.next();
+        chars.next().map(|(_, c)| c).unwrap_or('\0')
     }
 
-    fn string(self: &mut Self) -> Result<(), String> {
+    fn string(&mut self) -> Result<(), ValkyrieError> {
         while self.peek() != '"' && !self.is_at_end() {
-            if self.peek() == '\n' {
-                self.line += 1;
-            }
             self.advance();
         }
 
         if self.is_at_end() {
-            return Err("Unterminated string".to_string());
+            return Err(self.error(
+                ErrorCode::UnterminatedString,
+                "Unterminated string".to_string(),
+            ));
         }
 
         self.advance();
 
+        // The quotes are one byte each
         let value = &self.source[self.start + 1..self.current - 1];
 
         self.add_token_lit(StringLit, Some(StringValue(value.to_string())));
@@ -333,44 +344,56 @@ impl Scanner {
         Ok(())
     }
 
-    fn peek(self: &Self) -> char {
-        if self.is_at_end() {
-            return '\0';
-        }
-        self.source.chars().nth(self.current).unwrap()
+    fn peek(&mut self) -> char {
+        self.chars.peek().map(|(_, c)| *c).unwrap_or('\0')
     }
 
-    fn char_match(self: &mut Self, ch: char) -> bool {
-        if self.is_at_end() {
+    fn char_match(&mut self, ch: char) -> bool {
+        if self.peek() != ch || self.is_at_end() {
             return false;
         }
-        if self.source.chars().nth(self.current).unwrap() != ch {
-            return false;
+
+        self.advance();
+        true
+    }
+
+    fn advance(&mut self) -> char {
+        let (idx, c) = self
+            .chars
+            .next()
+            .expect("Advanced past the end of the source");
+        self.current = idx + c.len_utf8();
+        if c == '\n' {
+            self.line += 1;
+            self.column = 0;
         } else {
-            self.current += 1;
-            return true;
+            self.column += 1;
         }
+
+        c
     }
 
-    fn advance(self: &mut Self) -> char {
-        let c = self.source.chars().nth(self.current).unwrap();
-        self.current += 1;
+    // Span of the current lexeme
+    fn span(&self) -> Span {
+        Span::new(self.start_line, self.start_column, self.line, self.column).in_file(self.file)
+    }
 
-        c
+    fn error(&self, code: ErrorCode, msg: String) -> ValkyrieError {
+        ValkyrieError::lex(code, msg, self.span())
     }
 
-    fn add_token(self: &mut Self, token_type: TokenType) {
+    fn add_token(&mut self, token_type: TokenType) {
         self.add_token_lit(token_type, None);
     }
 
-    fn add_token_lit(self: &mut Self, token_type: TokenType, literal: Option<LiteralValue>) {
+    fn add_token_lit(&mut self, token_type: TokenType, literal: Option<LiteralValue>) {
         let text = self.source[self.start..self.current].to_string();
 
         self.tokens.push(Token {
-            token_type: token_type,
+            token_type,
             lexeme: text,
-            literal: literal,
-            line_number: self.line,
+            literal,
+            span: self.span(),
         });
     }
 }
@@ -382,6 +405,9 @@ pub enum TokenType {
     RightParen,
     LeftBrace,
     RightBrace,
+    LeftBracket,
+    RightBracket,
+    Colon,
     Comma,
     Dot,
     Minus,
@@ -409,19 +435,27 @@ pub enum TokenType {
 
     // Keywords
     And,
+    Break,
+    Catch,
     Class,
+    Continue,
     Else,
     False,
+    Finally,
     Fun,
     For,
+    From,
     If,
+    Import,
     Null,
     Or,
     Print,
     Return,
     Super,
     This,
+    Throw,
     True,
+    Try,
     Var,
     While,
 
@@ -439,6 +473,8 @@ impl std::fmt::Display for TokenType {
 pub enum LiteralValue {
     FValue(f64),
     StringValue(String),
+    // The name of an identifier
+    Name(String),
 }
 use LiteralValue::*;
 
@@ -447,10 +483,18 @@ pub struct Token {
     pub token_type: TokenType,
     pub lexeme: String,
     pub literal: Option<LiteralValue>,
-    pub line_number: usize,
+    pub span: Span,
 }
 
 impl Token {
+    // What an identifier is looked up by, its lexeme with the runes read as Latin letters
+    pub fn name(&self) -> &str {
+        match &self.literal {
+            Some(Name(name)) => name,
+            _ => &self.lexeme,
+        }
+    }
+
     pub fn to_string(self: &Self) -> String {
         format!("{} {} {:?}", self.token_type, self.lexeme, self.literal)
     }
@@ -604,4 +648,96 @@ mod tests {
         assert_eq!(scanner.tokens[4].token_type, Semicolon);
         assert_eq!(scanner.tokens[5].token_type, Eof);
     }
+
+    #[test]
+    fn runic_keywords() {
+        let source = "𖤍 ♅ ♅♅ ↟↟ ↟↡ ↟↠ 𒌐 ↡ ↠↠ ↞↞ 🕈 🕈↟ 🕈↡ ☽ ☽𖤍 𖤓 ↟↞ ↞↠ ↡↡ ↠↟ ↠↡ ↠↞ ↞↟ ↞↡";
+        let mut scanner = Scanner::new(source);
+        scanner.scan_tokens().unwrap();
+
+        let expected = [
+            Var, Fun, Print, If, Else, While, For, Return, And, Or, Class, Super, This, False,
+            Null, True, Break, Continue, Throw, Try, Catch, Finally, Import, From, Eof,
+        ];
+        assert_eq!(scanner.tokens.len(), expected.len());
+        for (token, token_type) in scanner.tokens.iter().zip(expected.iter()) {
+            assert_eq!(token.token_type, *token_type);
+        }
+        assert_eq!(scanner.tokens[2].lexeme, "♅♅");
+        assert_eq!(scanner.tokens[14].lexeme, "☽𖤍");
+    }
+
+    #[test]
+    fn runic_keywords_without_spaces() {
+        let source = "♅♅🕈↡.ᚨ;";
+        let mut scanner = Scanner::new(source);
+        scanner.scan_tokens().unwrap();
+
+        assert_eq!(scanner.tokens.len(), 6);
+        assert_eq!(scanner.tokens[0].token_type, Print);
+        assert_eq!(scanner.tokens[1].token_type, This);
+        assert_eq!(scanner.tokens[2].token_type, Dot);
+        assert_eq!(scanner.tokens[3].token_type, Identifier);
+        assert_eq!(scanner.tokens[4].token_type, Semicolon);
+        assert_eq!(scanner.tokens[5].token_type, Eof);
+    }
+
+    #[test]
+    fn rune_identifiers() {
+        let source = "𖤍 ᛗᛣ_ᛡᚨᛃ2 = 34;\nᚲᛐᛜᚲᛘ();";
+        let mut scanner = Scanner::new(source);
+        scanner.scan_tokens().unwrap();
+
+        assert_eq!(scanner.tokens.len(), 10);
+        assert_eq!(scanner.tokens[0].token_type, Var);
+        assert_eq!(scanner.tokens[1].token_type, Identifier);
+        assert_eq!(scanner.tokens[1].lexeme, "ᛗᛣ_ᛡᚨᛃ2");
+        assert_eq!(scanner.tokens[1].name(), "My_var2");
+        assert_eq!(scanner.tokens[2].token_type, Equal);
+        assert_eq!(scanner.tokens[3].token_type, Number);
+        assert_eq!(scanner.tokens[4].token_type, Semicolon);
+        assert_eq!(scanner.tokens[5].token_type, Identifier);
+        assert_eq!(scanner.tokens[5].lexeme, "ᚲᛐᛜᚲᛘ");
+        assert_eq!(scanner.tokens[5].name(), "clock");
+        assert_eq!(scanner.tokens[5].span, Span::new(2, 1, 2, 5));
+    }
+
+    #[test]
+    fn handle_unicode_string_lit() {
+        let source = "print \"ᚠ runes ᚠ\"; // ᚨ comment\n\"é\" + \"\";";
+        let mut scanner = Scanner::new(source);
+        scanner.scan_tokens().unwrap();
+
+        assert_eq!(scanner.tokens.len(), 8);
+        assert_eq!(scanner.tokens[1].token_type, StringLit);
+        assert_eq!(scanner.tokens[1].lexeme, "\"ᚠ runes ᚠ\"");
+        match scanner.tokens[1].literal.as_ref().unwrap() {
+            StringValue(val) => assert_eq!(val, "ᚠ runes ᚠ"),
+            _ => panic!("Incorrect literal type"),
+        }
+        match scanner.tokens[3].literal.as_ref().unwrap() {
+            StringValue(val) => assert_eq!(val, "é"),
+            _ => panic!("Incorrect literal type"),
+        }
+        assert_eq!(scanner.tokens[3].span.start_line, 2);
+        match scanner.tokens[5].literal.as_ref().unwrap() {
+            StringValue(val) => assert_eq!(val, ""),
+            _ => panic!("Incorrect literal type"),
+        }
+    }
+
+    #[test]
+    fn unrecognized_multibyte_char() {
+        let source = "var a = 1 € 2;";
+        let mut scanner = Scanner::new(source);
+        match scanner.scan_tokens() {
+            Err(errors) => {
+                assert_eq!(errors.len(), 1);
+                assert_eq!(errors[0].code(), ErrorCode::UnexpectedCharacter);
+                assert!(errors[0].message().contains("€"));
+                assert_eq!(errors[0].span(), Span::new(1, 11, 1, 11));
+            }
+            _ => panic!("Should have failed"),
+        }
+    }
 }
@@ -0,0 +1,356 @@
+use crate::error::{ErrorCode, ValkyrieError};
+use crate::expr::LiteralValue;
+use crate::limits::reserve;
+use crate::natives::{arguments, define_native, define_variadic, expected, native_error};
+use std::cell::Cell;
+use std::collections::HashMap;
+
+fn string<'a>(name: &str, value: &'a LiteralValue) -> Result<&'a str, ValkyrieError> {
+    match value {
+        LiteralValue::StringValue(s) => Ok(s),
+        other => Err(expected(name, "a String", other)),
+    }
+}
+
+fn number(name: &str, value: &LiteralValue) -> Result<f64, ValkyrieError> {
+    match value {
+        LiteralValue::Number(x) => Ok(*x),
+        other => Err(expected(name, "a Number", other)),
+    }
+}
+
+// Positions count chars like len does, they are clamped to the string
+fn position(x: f64, len: usize) -> usize {
+    (x.max(0.0) as usize).min(len)
+}
+
+// substr(s, start, end) copies the chars from start up to end, like slice does for arrays
+fn substr_impl(args: &[LiteralValue]) -> Result<LiteralValue, ValkyrieError> {
+    let [s, start, end] = arguments("substr", args)?;
+    let s = string("substr", s)?;
+    let len = s.chars().count();
+    let end = position(number("substr", end)?, len);
+    let start = position(number("substr", start)?, len).min(end);
+    Ok(LiteralValue::StringValue(
+        s.chars().skip(start).take(end - start).collect(),
+    ))
+}
+
+fn upper_impl(args: &[LiteralValue]) -> Result<LiteralValue, ValkyrieError> {
+    let [s] = arguments("upper", args)?;
+    Ok(LiteralValue::StringValue(
+        string("upper", s)?.to_uppercase(),
+    ))
+}
+
+fn lower_impl(args: &[LiteralValue]) -> Result<LiteralValue, ValkyrieError> {
+    let [s] = arguments("lower", args)?;
+    Ok(LiteralValue::StringValue(
+        string("lower", s)?.to_lowercase(),
+    ))
+}
+
+fn trim_impl(args: &[LiteralValue]) -> Result<LiteralValue, ValkyrieError> {
+    let [s] = arguments("trim", args)?;
+    Ok(LiteralValue::StringValue(
+        string("trim", s)?.trim().to_string(),
+    ))
+}
+
+// An empty separator splits the string into its chars
+fn split_impl(args: &[LiteralValue]) -> Result<LiteralValue, ValkyrieError> {
+    let [s, separator] = arguments("split", args)?;
+    let s = string("split", s)?;
+    let separator = string("split", separator)?;
+    let parts = if separator.is_empty() {
+        s.chars()
+            .map(|c| LiteralValue::StringValue(c.to_string()))
+            .collect()
+    } else {
+        s.split(separator)
+            .map(|part| LiteralValue::StringValue(part.to_string()))
+            .collect()
+    };
+    Ok(LiteralValue::new_array(parts))
+}
+
+// Strings are joined as they are, other values the way print shows them
+fn join_impl(args: &[LiteralValue]) -> Result<LiteralValue, ValkyrieError> {
+    let [xs, separator] = arguments("join", args)?;
+    let separator = string("join", separator)?;
+    match xs {
+        LiteralValue::Array(xs) => {
+            let parts: Vec<String> = xs.borrow().iter().map(to_str).collect();
+            let separators = separator
+                .len()
+                .saturating_mul(parts.len().saturating_sub(1));
+            let size = parts
+                .iter()
+                .fold(separators, |size, part| size.saturating_add(part.len()));
+            reserve("String", size, "bytes")?;
+            Ok(LiteralValue::StringValue(parts.join(separator)))
+        }
+        other => Err(expected("join", "an Array", other)),
+    }
+}
+
+// The char position of the first match, or -1
+fn find_impl(args: &[LiteralValue]) -> Result<LiteralValue, ValkyrieError> {
+    let [s, needle] = arguments("find", args)?;
+    let s = string("find", s)?;
+    let needle = string("find", needle)?;
+    let index = match s.find(needle) {
+        Some(byte) => s[..byte].chars().count() as f64,
+        None => -1.0,
+    };
+    Ok(LiteralValue::Number(index))
+}
+
+fn replace_impl(args: &[LiteralValue]) -> Result<LiteralValue, ValkyrieError> {
+    let [s, from, to] = arguments("replace", args)?;
+    let s = string("replace", s)?;
+    let from = string("replace", from)?;
+    if from.is_empty() {
+        return Err(native_error(
+            ErrorCode::InvalidArgument,
+            "replace cannot replace an empty string".to_string(),
+        ));
+    }
+    let to = string("replace", to)?;
+    // Checked before replacing, the result can be much larger than the string
+    let matches = s.matches(from).count();
+    let size = (s.len() - matches * from.len()).saturating_add(matches.saturating_mul(to.len()));
+    reserve("String", size, "bytes")?;
+    Ok(LiteralValue::StringValue(s.replace(from, to)))
+}
+
+fn math(
+    name: &'static str,
+    f: fn(f64) -> f64,
+) -> impl Fn(&[LiteralValue]) -> Result<LiteralValue, ValkyrieError> {
+    move |args| {
+        let [x] = arguments(name, args)?;
+        Ok(LiteralValue::Number(f(number(name, x)?)))
+    }
+}
+
+fn sqrt_impl(args: &[LiteralValue]) -> Result<LiteralValue, ValkyrieError> {
+    let [x] = arguments("sqrt", args)?;
+    let x = number("sqrt", x)?;
+    if x < 0.0 {
+        return Err(native_error(
+            ErrorCode::InvalidArgument,
+            format!("sqrt of the negative number {}", x),
+        ));
+    }
+    Ok(LiteralValue::Number(x.sqrt()))
+}
+
+fn pow_impl(args: &[LiteralValue]) -> Result<LiteralValue, ValkyrieError> {
+    let [base, exponent] = arguments("pow", args)?;
+    Ok(LiteralValue::Number(
+        number("pow", base)?.powf(number("pow", exponent)?),
+    ))
+}
+
+// min and max take one or more numbers
+fn extreme(
+    name: &'static str,
+    pick: fn(f64, f64) -> f64,
+) -> impl Fn(&[LiteralValue]) -> Result<LiteralValue, ValkyrieError> {
+    move |args| {
+        let mut result = None;
+        for x in args {
+            let x = number(name, x)?;
+            result = Some(result.map_or(x, |result| pick(result, x)));
+        }
+        Ok(result.map_or(LiteralValue::Null, LiteralValue::Number))
+    }
+}
+
+thread_local! {
+    // State of the xorshift generator behind random, 0 until it is first seeded
+    static RANDOM_STATE: Cell<u64> = const { Cell::new(0) };
+}
+
+// Spreads the bits of the seed so that close seeds give unrelated sequences, and never gives the
+// 0 state xorshift cannot leave
+fn mix_seed(seed: u64) -> u64 {
+    let mut z = seed.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    (z ^ (z >> 31)) | 1
+}
+
+// seed(n) makes the numbers random returns from then on the same for every run
+fn seed_impl(args: &[LiteralValue]) -> Result<LiteralValue, ValkyrieError> {
+    let [seed] = arguments("seed", args)?;
+    let seed = number("seed", seed)?;
+    RANDOM_STATE.with(|state| state.set(mix_seed(seed.to_bits())));
+    Ok(LiteralValue::Null)
+}
+
+// A number in [0, 1), seeded from the clock unless seed was called
+fn random_impl(args: &[LiteralValue]) -> Result<LiteralValue, ValkyrieError> {
+    let [] = arguments("random", args)?;
+    let value = RANDOM_STATE.with(|state| {
+        let mut x = state.get();
+        if x == 0 {
+            let now = std::time::SystemTime::now()
+                .duration_since(std::time::SystemTime::UNIX_EPOCH)
+                .map(|time| time.as_nanos() as u64)
+                .unwrap_or_default();
+            x = mix_seed(now);
+        }
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        state.set(x);
+        x
+    });
+    Ok(LiteralValue::Number(
+        (value >> 11) as f64 / (1u64 << 53) as f64,
+    ))
+}
+
+fn type_of_impl(args: &[LiteralValue]) -> Result<LiteralValue, ValkyrieError> {
+    let [value] = arguments("type_of", args)?;
+    Ok(LiteralValue::StringValue(value.to_type().to_string()))
+}
+
+pub fn to_str(value: &LiteralValue) -> String {
+    match value {
+        LiteralValue::StringValue(s) => s.clone(),
+        other => other.to_string(),
+    }
+}
+
+fn str_impl(args: &[LiteralValue]) -> Result<LiteralValue, ValkyrieError> {
+    let [value] = arguments("str", args)?;
+    Ok(LiteralValue::StringValue(to_str(value)))
+}
+
+// Surrounding whitespace is ignored, anything else that is not a number is an error
+fn num_impl(args: &[LiteralValue]) -> Result<LiteralValue, ValkyrieError> {
+    let [value] = arguments("num", args)?;
+    match value {
+        LiteralValue::Number(x) => Ok(LiteralValue::Number(*x)),
+        LiteralValue::StringValue(s) => match s.trim().parse::<f64>() {
+            Ok(x) if x.is_finite() => Ok(LiteralValue::Number(x)),
+            _ => Err(native_error(
+                ErrorCode::InvalidArgument,
+                format!("Cannot convert \"{}\" to a number", s),
+            )),
+        },
+        other => Err(expected("num", "a String or a Number", other)),
+    }
+}
+
+pub fn define_stdlib(env: &mut HashMap<String, LiteralValue>) {
+    define_native(env, "substr", 3, substr_impl);
+    define_native(env, "upper", 1, upper_impl);
+    define_native(env, "lower", 1, lower_impl);
+    define_native(env, "trim", 1, trim_impl);
+    define_native(env, "split", 2, split_impl);
+    define_native(env, "join", 2, join_impl);
+    define_native(env, "find", 2, find_impl);
+    define_native(env, "replace", 3, replace_impl);
+
+    define_native(env, "sqrt", 1, sqrt_impl);
+    define_native(env, "pow", 2, pow_impl);
+    define_native(env, "floor", 1, math("floor", f64::floor));
+    define_native(env, "ceil", 1, math("ceil", f64::ceil));
+    define_native(env, "abs", 1, math("abs", f64::abs));
+    define_variadic(env, "min", 1, extreme("min", f64::min));
+    define_variadic(env, "max", 1, extreme("max", f64::max));
+    define_native(env, "seed", 1, seed_impl);
+    define_native(env, "random", 0, random_impl);
+
+    define_native(env, "type_of", 1, type_of_impl);
+    define_native(env, "str", 1, str_impl);
+    define_native(env, "num", 1, num_impl);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::limits::{Budget, Limits};
+
+    fn string(s: &str) -> LiteralValue {
+        LiteralValue::StringValue(s.to_string())
+    }
+
+    #[test]
+    fn strings_count_chars() {
+        let runes = string("ᚠᚢᚦᚨᚱ");
+        let args = [
+            runes.clone(),
+            LiteralValue::Number(1.0),
+            LiteralValue::Number(3.0),
+        ];
+        assert_eq!(substr_impl(&args).unwrap(), string("ᚢᚦ"));
+        assert_eq!(
+            find_impl(&[runes, string("ᚨ")]).unwrap(),
+            LiteralValue::Number(3.0)
+        );
+    }
+
+    #[test]
+    fn results_over_the_size_limit_are_not_built() {
+        let budget = Budget::default();
+        budget.set_limits(Limits {
+            max_size: Some(50),
+            ..Limits::default()
+        });
+        let s = string(&"a".repeat(1000));
+        let err = budget
+            .call_native(|| replace_impl(&[s.clone(), string("a"), s.clone()]))
+            .unwrap_err();
+        assert_eq!(err.code(), ErrorCode::SizeLimitExceeded);
+        assert_eq!(
+            err.message(),
+            "Size limit exceeded, the String has 1000000 bytes, more than the limit of 50"
+        );
+
+        let parts = LiteralValue::new_array(vec![string(&"a".repeat(20)); 3]);
+        let err = budget
+            .call_native(|| join_impl(&[parts.clone(), string("--")]))
+            .unwrap_err();
+        assert_eq!(
+            err.message(),
+            "Size limit exceeded, the String has 64 bytes, more than the limit of 50"
+        );
+        assert!(join_impl(&[parts, string("--")]).is_ok());
+    }
+
+    #[test]
+    fn wrong_arguments_are_errors() {
+        let err = upper_impl(&[LiteralValue::Number(1.0)]).unwrap_err();
+        assert_eq!(err.code(), ErrorCode::TypeMismatch);
+        assert_eq!(err.message(), "upper expected a String but got Number");
+
+        let err = pow_impl(&[LiteralValue::Number(1.0)]).unwrap_err();
+        assert_eq!(err.code(), ErrorCode::ArityMismatch);
+
+        let err = num_impl(&[string("12abc")]).unwrap_err();
+        assert_eq!(err.code(), ErrorCode::InvalidArgument);
+    }
+
+    #[test]
+    fn seeded_random_repeats() {
+        let draw = || {
+            seed_impl(&[LiteralValue::Number(42.0)]).unwrap();
+            (0..3)
+                .map(|_| random_impl(&[]).unwrap())
+                .collect::<Vec<LiteralValue>>()
+        };
+        let first = draw();
+        assert_eq!(first, draw());
+        for value in first {
+            match value {
+                LiteralValue::Number(x) => assert!((0.0..1.0).contains(&x)),
+                other => panic!("random returned {:?}", other),
+            }
+        }
+    }
+}
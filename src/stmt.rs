@@ -1,63 +1,137 @@
+use crate::diagnostic::Span;
 use crate::expr::Expr;
 use crate::scanner::Token;
+use std::rc::Rc;
 
 #[allow(dead_code)]
 #[derive(Debug, Clone)]
 pub enum Stmt {
     Expression {
+        span: Span,
         expression: Expr,
     },
     Print {
+        span: Span,
         expression: Expr,
     },
     Var {
+        span: Span,
         name: Token,
         initializer: Expr,
     },
     Block {
+        span: Span,
         statements: Vec<Box<Stmt>>,
     },
     Class {
+        span: Span,
         name: Token,
         methods: Vec<Box<Stmt>>,
         superclass: Option<Expr>,
     },
     IfStmt {
+        span: Span,
         predicate: Expr,
         then: Box<Stmt>,
         els: Option<Box<Stmt>>,
     },
     WhileStmt {
+        span: Span,
         condition: Expr,
         body: Box<Stmt>,
+        // The increment of a desugared for loop, it also runs after a continue
+        increment: Option<Expr>,
     },
+    Break {
+        span: Span,
+        keyword: Token,
+    },
+    Continue {
+        span: Span,
+        keyword: Token,
+    },
+    // The parameters and body are shared with every function value created from it
     Function {
+        span: Span,
         name: Token,
-        params: Vec<Token>,
-        body: Vec<Box<Stmt>>,
+        params: Rc<[Token]>,
+        body: Rc<[Stmt]>,
     },
     CmdFunction {
+        span: Span,
         name: Token,
-        cmd: String,
+        // The command line split into words
+        cmd: Vec<String>,
     },
     ReturnStmt {
+        span: Span,
         keyword: Token,
         value: Option<Expr>,
     },
+    Throw {
+        span: Span,
+        keyword: Token,
+        value: Expr,
+    },
+    // At least one of catch and finally is there, the catch clause names the caught value
+    Try {
+        span: Span,
+        body: Box<Stmt>,
+        catch: Option<(Token, Box<Stmt>)>,
+        finally: Option<Box<Stmt>>,
+    },
+    // Binds the module to the name if there is one, else declares its globals here
+    Import {
+        span: Span,
+        path: String,
+        name: Option<Token>,
+    },
 }
 
 impl Stmt {
+    #[allow(dead_code)]
+    pub fn get_span(&self) -> Span {
+        use Stmt::*;
+        match self {
+            Expression { span, .. } => *span,
+            Print { span, .. } => *span,
+            Var { span, .. } => *span,
+            Block { span, .. } => *span,
+            Class { span, .. } => *span,
+            IfStmt { span, .. } => *span,
+            WhileStmt { span, .. } => *span,
+            Break { span, .. } => *span,
+            Continue { span, .. } => *span,
+            Function { span, .. } => *span,
+            CmdFunction { span, .. } => *span,
+            ReturnStmt { span, .. } => *span,
+            Throw { span, .. } => *span,
+            Try { span, .. } => *span,
+            Import { span, .. } => *span,
+        }
+    }
+
     #[allow(dead_code)]
     pub fn to_string(&self) -> String {
         use Stmt::*;
         match self {
-            Expression { expression } => expression.to_string(),
-            Print { expression } => format!("(print {})", expression.to_string()),
+            Expression {
+                span: _,
+                expression,
+            } => expression.to_string(),
+            Print {
+                span: _,
+                expression,
+            } => format!("(print {})", expression.to_string()),
             Var {
+                span: _,
                 name,
                 initializer: _,
             } => format!("(var {})", name.lexeme),
-            Block { statements } => format!(
+            Block {
+                span: _,
+                statements,
+            } => format!(
                 "(block {})",
                 statements
                     .into_iter()
@@ -65,21 +139,27 @@ impl Stmt {
                     .collect::<String>()
             ),
             IfStmt {
+                span: _,
                 predicate: _,
                 then: _,
                 els: _,
             } => todo!(),
             WhileStmt {
+                span: _,
                 condition: _,
                 body: _,
+                increment: _,
             } => todo!(),
+            Break { .. } => "(break)".to_string(),
+            Continue { .. } => "(continue)".to_string(),
             Function {
+                span: _,
                 name: _,
                 params: _,
                 body: _,
             } => todo!(),
-            CmdFunction { name: _, cmd: _ } => todo!(),
-            ReturnStmt { keyword: _, value: _ } => todo!(),
+            CmdFunction { .. } => todo!(),
+            ReturnStmt { .. } => todo!(),
             _ => todo!(),
         }
     }
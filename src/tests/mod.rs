@@ -1,11 +1,11 @@
 #[cfg(test)]
 mod tests {
+    use crate::{render_all, Options, Valkyrie};
     use std::fs::{read_dir, read_to_string, DirEntry};
-    use std::process::Command;
 
     #[test]
     fn execute_tests() {
-        let cases = read_dir(r"X:\Programacion\Valkyrie\valkyrie-interpreter\src\tests\cases")
+        let cases = read_dir(concat!(env!("CARGO_MANIFEST_DIR"), "/src/tests/cases"))
             .expect("Failed to read test cases directory");
 
         let mut errors = vec![];
@@ -17,15 +17,19 @@ mod tests {
                 continue;
             }
 
-            println!("Running test: {}", name);
-
-            match run_test(case) {
-                Ok(_) => {
-                    msgs.push(format!("Running {name:.<85}...ok"));
-                },
-                Err(msg) => {
-                    errors.push(msg);
-                    msgs.push(format!("Running {name:.<85}...failed"));
+            // Every case runs on both backends, they must print the same thing
+            for (backend, flags) in [("", vec![]), (" (vm)", vec!["--vm"])] {
+                println!("Running test: {}{}", name, backend);
+
+                let name = format!("{}{}", name, backend);
+                match run_test(&case, &flags) {
+                    Ok(_) => {
+                        msgs.push(format!("Running {name:.<85}...ok"));
+                    }
+                    Err(msg) => {
+                        errors.push(msg);
+                        msgs.push(format!("Running {name:.<85}...failed"));
+                    }
                 }
             }
         }
@@ -41,16 +45,19 @@ mod tests {
         }
     }
 
-    fn run_test(file: DirEntry) -> Result<(), String> {
+    fn run_test(file: &DirEntry, flags: &[&str]) -> Result<(), String> {
         // Parse input and expected
         let contents = read_to_string(file.path()).unwrap();
         let lines = contents.split("\n").collect::<Vec<&str>>();
 
         let mut test_code = vec![];
+        // Options the case runs with, written after the test marker
+        let mut options = vec![];
 
         let mut idx = None;
         for (i, line) in lines.iter().enumerate() {
-            if line.starts_with("## --- Test") {
+            if let Some(rest) = line.strip_prefix("## --- Test") {
+                options.extend(rest.split_whitespace());
                 continue;
             }
             if line.starts_with("## --- Expected") {
@@ -80,17 +87,9 @@ mod tests {
 
         println!("Expected output:\n{}", expected_output.join("\n"));
 
-        let output = Command::new("cargo")
-            .arg("run")
-            .arg("--")
-            .arg("--run_test")
-            .arg(input)
-            .output()
-            .unwrap();
-        let lines = std::str::from_utf8(output.stdout.as_slice())
-            .unwrap()
-            .split("\n")
-            .collect::<Vec<&str>>();
+        let (status, output) = run_case(flags.iter().chain(&options), input)
+            .map_err(|msg| format!("{:#?}: {}", file.file_name(), msg))?;
+        let lines = output.split("\n").collect::<Vec<&str>>();
 
         println!("Output from interpreter:\n{}", lines.join("\n"));
 
@@ -104,8 +103,29 @@ mod tests {
             ));
         }
 
+        // Errors in the source exit with 65, errors while running it with 70. A program can print
+        // before its runtime error
+        let expected_status = if !expected_output.iter().any(|line| line == "ERROR:") {
+            0
+        } else if expected_output
+            .iter()
+            .any(|line| line.starts_with("Runtime error"))
+        {
+            70
+        } else {
+            65
+        };
+        if status != expected_status {
+            return Err(format!(
+                "{:#?}: exit status {} != {}",
+                file.file_name(),
+                status,
+                expected_status
+            ));
+        }
+
         for (i, expected) in expected_output.iter().enumerate() {
-            if lines[i] != (*expected).trim() {
+            if lines[i] != expected.trim_end() {
                 return Err(format!(
                     "{:#?}: {} != {}\nFull output:\n{}",
                     file.file_name(),
@@ -118,4 +138,31 @@ mod tests {
 
         Ok(())
     }
+
+    // Runs the case like --run_test does, with the output captured, and returns its exit status
+    // and output. It runs on a thread with the stack the interpreter gives programs
+    fn run_case<'a>(
+        flags: impl Iterator<Item = &'a &'a str>,
+        input: String,
+    ) -> Result<(i32, String), String> {
+        let mut flags = flags.map(|flag| flag.to_string()).collect();
+        let options = Options::from_flags(&mut flags)?;
+        let case = options
+            .spawn(move |options| {
+                let mut engine = Valkyrie::new(&options);
+                let capture = engine.capture_output();
+                let result = engine.eval(&input);
+                let mut output = capture.take();
+                match result {
+                    Ok(()) => (0, output),
+                    Err(errs) => {
+                        output.push_str(&format!("ERROR:\n{}\n", render_all(&errs, &input)));
+                        (errs[0].exit_code(), output)
+                    }
+                }
+            })
+            .map_err(|err| err.to_string())?;
+        case.join()
+            .map_err(|_| "the interpreter panicked".to_string())
+    }
 }
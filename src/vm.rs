@@ -0,0 +1,965 @@
+use crate::chunk::{Function, OpCode};
+use crate::compiler::Compiler;
+use crate::diagnostic::{CallFrame, Span};
+use crate::error::{ErrorCode, ValkyrieError};
+use crate::expr::{
+    binary_op, caught_value, check_key, check_native_sizes, error_property, index_get, index_set,
+    map_insert, module_property, stack_overflow, thrown_error, unary_op, CallableImpl,
+    LiteralValue, MAX_DEPTH,
+};
+use crate::limits::{Budget, Limits};
+use crate::module::{import, Modules};
+use crate::natives::get_globals;
+use crate::output::{stdout, Output, SharedOutput};
+use crate::sandbox::Sandbox;
+use crate::scanner::TokenType;
+use crate::stmt::Stmt;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::path::Path;
+use std::rc::Rc;
+
+pub struct Closure {
+    pub function: Rc<Function>,
+    pub upvalues: Vec<Rc<RefCell<Upvalue>>>,
+    // The globals of the file the closure was declared in
+    pub globals: Globals,
+}
+
+type Globals = Rc<RefCell<HashMap<String, LiteralValue>>>;
+
+// A captured variable lives in its stack slot until the scope that declared it ends, then it is
+// moved into the upvalue so that every closure that captured it keeps sharing it
+pub enum Upvalue {
+    Open(usize),
+    Closed(LiteralValue),
+}
+
+pub struct BoundMethod {
+    pub receiver: LiteralValue,
+    pub method: Rc<Closure>,
+}
+
+pub struct Class {
+    pub name: String,
+    pub methods: RefCell<HashMap<String, Rc<Closure>>>,
+    pub superclass: Option<Rc<Class>>,
+}
+
+impl Class {
+    pub fn find_method(&self, name: &str) -> Option<Rc<Closure>> {
+        if let Some(method) = self.methods.borrow().get(name) {
+            return Some(method.clone());
+        }
+        match &self.superclass {
+            Some(superclass) => superclass.find_method(name),
+            None => None,
+        }
+    }
+}
+
+struct Frame {
+    closure: Rc<Closure>,
+    ip: usize,
+    // Stack index of slot 0, which holds the callee or the receiver of a method
+    base: usize,
+    call_span: Span,
+    // Calls to a class return the new instance, whatever init returns
+    constructor: bool,
+    // The frame a chain of tail calls replaced first, kept for tracebacks
+    tail_caller: Option<CallFrame>,
+}
+
+impl Frame {
+    fn call_frame(&self) -> CallFrame {
+        CallFrame {
+            function: self.closure.function.name.clone(),
+            class: self.closure.function.class_name.clone(),
+            call_span: self.call_span,
+        }
+    }
+}
+
+// Where an error goes when a try statement catches it
+struct Handler {
+    frames: usize,
+    stack_len: usize,
+    ip: usize,
+    finally: bool,
+    pending: usize,
+}
+
+pub struct Vm {
+    stack: Vec<LiteralValue>,
+    frames: Vec<Frame>,
+    handlers: Vec<Handler>,
+    // Errors waiting for their finally blocks to finish before they are raised again
+    pending: Vec<ValkyrieError>,
+    globals: Globals,
+    modules: Rc<RefCell<Modules>>,
+    open_upvalues: Vec<Rc<RefCell<Upvalue>>>,
+    max_depth: usize,
+    sandbox: Rc<RefCell<Sandbox>>,
+    budget: Budget,
+    output: SharedOutput,
+}
+
+impl Vm {
+    pub fn new() -> Self {
+        Self {
+            stack: vec![],
+            frames: vec![],
+            handlers: vec![],
+            pending: vec![],
+            globals: get_globals(),
+            modules: Rc::new(RefCell::new(Modules::default())),
+            open_upvalues: vec![],
+            max_depth: MAX_DEPTH,
+            sandbox: Rc::new(RefCell::new(Sandbox::default())),
+            budget: Budget::default(),
+            output: stdout(),
+        }
+    }
+
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.budget.set_limits(limits);
+    }
+
+    pub fn set_max_depth(&mut self, max_depth: usize) {
+        self.max_depth = max_depth;
+    }
+
+    pub fn set_sandbox(&mut self, sandbox: Sandbox) {
+        *self.sandbox.borrow_mut() = sandbox;
+    }
+
+    pub fn set_output(&mut self, output: impl Output + 'static) {
+        *self.output.borrow_mut() = Box::new(output);
+    }
+
+    // The globals of the program, imported files have their own
+    pub fn global_scope(&self) -> Globals {
+        self.globals.clone()
+    }
+
+    // Globals defined from Rust are also there in the files the program imports
+    pub fn define_global(&mut self, name: &str, value: LiteralValue) {
+        self.modules.borrow_mut().define(name, value.clone());
+        self.globals.borrow_mut().insert(name.to_string(), value);
+    }
+
+    // Imports are resolved relative to the file being run
+    pub fn set_main_file(&mut self, path: &Path) {
+        self.modules.borrow_mut().set_main_file(path);
+    }
+
+    // Globals are kept between calls, so that the prompt can run one line at a time. Each call has
+    // the full budget of the limits
+    pub fn interpret(&mut self, stmts: Vec<&Stmt>) -> Result<(), ValkyrieError> {
+        self.budget.start();
+        let function = Compiler::compile(&stmts, &self.sandbox)?;
+        let closure = Rc::new(Closure {
+            function,
+            upvalues: vec![],
+            globals: self.globals.clone(),
+        });
+        self.stack
+            .push(LiteralValue::Callable(CallableImpl::Closure(
+                closure.clone(),
+            )));
+        self.frames.push(Frame {
+            closure,
+            ip: 0,
+            base: 0,
+            call_span: Span::default(),
+            constructor: false,
+            tail_caller: None,
+        });
+
+        let result = self.run(0);
+        if result.is_err() {
+            self.stack.clear();
+            self.frames.clear();
+            self.handlers.clear();
+            self.pending.clear();
+            self.open_upvalues.clear();
+        }
+        result
+    }
+
+    // Runs until the frames above depth have returned, the handlers of the frames below it are
+    // left to the run that pushed them
+    fn run(&mut self, depth: usize) -> Result<(), ValkyrieError> {
+        loop {
+            match self.execute(depth) {
+                Err(err)
+                    if err.is_catchable()
+                        && self
+                            .handlers
+                            .last()
+                            .is_some_and(|handler| handler.frames > depth) =>
+                {
+                    self.unwind(err)
+                }
+                result => return result,
+            }
+        }
+    }
+
+    // An imported file runs on the same stack as a call would, with globals of its own
+    fn run_module(
+        &mut self,
+        stmts: &[Stmt],
+    ) -> Result<HashMap<String, LiteralValue>, ValkyrieError> {
+        let function = Compiler::compile(&stmts.iter().collect(), &self.sandbox)?;
+        let globals = self.modules.borrow().globals();
+        let closure = Rc::new(Closure {
+            function,
+            upvalues: vec![],
+            globals: globals.clone(),
+        });
+        let depth = self.frames.len();
+        let base = self.stack.len();
+        self.stack
+            .push(LiteralValue::Callable(CallableImpl::Closure(
+                closure.clone(),
+            )));
+        self.frames.push(Frame {
+            closure,
+            ip: 0,
+            base,
+            call_span: Span::default(),
+            constructor: false,
+            tail_caller: None,
+        });
+
+        if let Err(err) = self.run(depth) {
+            self.frames.truncate(depth);
+            self.close_upvalues(base);
+            self.stack.truncate(base);
+            return Err(err);
+        }
+        let globals = globals.borrow().clone();
+        Ok(globals)
+    }
+
+    // Drops the frames and values pushed since the innermost try statement started, and jumps to
+    // its catch or finally block
+    fn unwind(&mut self, err: ValkyrieError) {
+        let handler = self.handlers.pop().unwrap();
+        self.frames.truncate(handler.frames);
+        self.close_upvalues(handler.stack_len);
+        self.stack.truncate(handler.stack_len);
+        self.frame_mut().ip = handler.ip;
+        self.pending.truncate(handler.pending);
+        if handler.finally {
+            self.pending.push(err);
+        } else {
+            self.stack.push(caught_value(err));
+        }
+    }
+
+    fn execute(&mut self, depth: usize) -> Result<(), ValkyrieError> {
+        loop {
+            let frame = self.frames.last_mut().unwrap();
+            let op = frame.closure.function.chunk.code[frame.ip];
+            frame.ip += 1;
+
+            match op {
+                OpCode::Constant(index) => {
+                    let value =
+                        self.frame().closure.function.chunk.constants[index as usize].clone();
+                    self.stack.push(value);
+                }
+                OpCode::Null => self.stack.push(LiteralValue::Null),
+                OpCode::True => self.stack.push(LiteralValue::True),
+                OpCode::False => self.stack.push(LiteralValue::False),
+                OpCode::Pop => {
+                    self.pop();
+                }
+                OpCode::GetLocal(slot) => {
+                    let value = self.stack[self.frame().base + slot as usize].clone();
+                    self.stack.push(value);
+                }
+                OpCode::SetLocal(slot) => {
+                    let index = self.frame().base + slot as usize;
+                    self.stack[index] = self.peek(0).clone();
+                }
+                OpCode::GetUpvalue(index) => {
+                    let value = match &*self.frame().closure.upvalues[index as usize].borrow() {
+                        Upvalue::Open(slot) => self.stack[*slot].clone(),
+                        Upvalue::Closed(value) => value.clone(),
+                    };
+                    self.stack.push(value);
+                }
+                OpCode::SetUpvalue(index) => {
+                    let value = self.peek(0).clone();
+                    let upvalue = self.frame().closure.upvalues[index as usize].clone();
+                    let mut upvalue = upvalue.borrow_mut();
+                    match &mut *upvalue {
+                        Upvalue::Open(slot) => self.stack[*slot] = value,
+                        Upvalue::Closed(closed) => *closed = value,
+                    }
+                }
+                OpCode::GetGlobal(index) => {
+                    let name = self.name(index);
+                    let value = self.frame().closure.globals.borrow().get(name).cloned();
+                    match value {
+                        Some(value) => self.stack.push(value),
+                        None => {
+                            return Err(self.error(
+                                ErrorCode::UndefinedVariable,
+                                format!(
+                                    "Variable '{}' has not been declared at distance None",
+                                    name
+                                ),
+                            ))
+                        }
+                    }
+                }
+                OpCode::DefineGlobal(index) => {
+                    let name = self.name(index).to_string();
+                    let value = self.pop();
+                    self.frame()
+                        .closure
+                        .globals
+                        .borrow_mut()
+                        .insert(name, value);
+                }
+                OpCode::SetGlobal(index) => {
+                    let name = self.name(index);
+                    if !self.frame().closure.globals.borrow().contains_key(name) {
+                        return Err(self.error(
+                            ErrorCode::UndefinedVariable,
+                            format!("Variable {} has not been declared", name),
+                        ));
+                    }
+                    let name = name.to_string();
+                    let value = self.peek(0).clone();
+                    self.frame()
+                        .closure
+                        .globals
+                        .borrow_mut()
+                        .insert(name, value);
+                }
+                OpCode::CloseUpvalue => {
+                    self.close_upvalues(self.stack.len() - 1);
+                    self.pop();
+                }
+                OpCode::GetProperty(index) => {
+                    let object = self.pop();
+                    let value = self.get_property(&object, index)?;
+                    self.stack.push(value);
+                }
+                OpCode::SetProperty(index) => {
+                    let value = self.pop();
+                    let object = self.pop();
+                    match object {
+                        LiteralValue::LoxInstance { class: _, fields } => {
+                            let name = self.name(index);
+                            let mut fields = fields.borrow_mut();
+                            match fields.iter_mut().find(|(field, _)| field == name) {
+                                Some(field) => field.1 = value,
+                                None => fields.push((name.to_string(), value)),
+                            }
+                        }
+                        other => {
+                            return Err(self.error(
+                                ErrorCode::TypeMismatch,
+                                format!("Cannot set property on type {}", other.to_type()),
+                            ))
+                        }
+                    }
+                    self.stack.push(LiteralValue::Null);
+                }
+                OpCode::GetSuper(index) => {
+                    let superclass = self.pop();
+                    let receiver = self.pop();
+                    // Like the tree-walker, only the methods of the direct superclass are looked at
+                    let method = match &superclass {
+                        LiteralValue::VmClass(class) => {
+                            class.methods.borrow().get(self.name(index)).cloned()
+                        }
+                        other => {
+                            return Err(self.error(
+                                ErrorCode::InvalidSuperclass,
+                                format!("Superclass must be a class, not {}", other.to_type()),
+                            ))
+                        }
+                    };
+                    match method {
+                        Some(method) => {
+                            self.stack
+                                .push(LiteralValue::Callable(CallableImpl::BoundMethod(Rc::new(
+                                    BoundMethod { receiver, method },
+                                ))))
+                        }
+                        None => {
+                            return Err(self.error(
+                                ErrorCode::UndefinedProperty,
+                                format!(
+                                    "No method named {} on superclass {}",
+                                    self.name(index),
+                                    superclass.to_type()
+                                ),
+                            ))
+                        }
+                    }
+                }
+                OpCode::Index => {
+                    let index = self.pop();
+                    let object = self.pop();
+                    let value = index_get(&object, &index, self.span())
+                        .map_err(|err| err.with_trace(self.trace()))?;
+                    self.stack.push(value);
+                }
+                OpCode::IndexSet => {
+                    let value = self.pop();
+                    let index = self.pop();
+                    let object = self.pop();
+                    let value = index_set(&object, index, value, self.span())
+                        .map_err(|err| err.with_trace(self.trace()))?;
+                    self.check_size(&object)?;
+                    self.stack.push(value);
+                }
+                OpCode::CheckKey => check_key(self.peek(0), self.span())
+                    .map_err(|err| err.with_trace(self.trace()))?,
+                OpCode::Array(count) => {
+                    let elements = self.stack.split_off(self.stack.len() - count as usize);
+                    let array = LiteralValue::new_array(elements);
+                    self.check_size(&array)?;
+                    self.stack.push(array);
+                }
+                OpCode::Map(count) => {
+                    let values = self.stack.split_off(self.stack.len() - 2 * count as usize);
+                    let mut entries = vec![];
+                    let mut values = values.into_iter();
+                    while let (Some(key), Some(value)) = (values.next(), values.next()) {
+                        map_insert(&mut entries, key, value);
+                    }
+                    let map = LiteralValue::new_map(entries);
+                    self.check_size(&map)?;
+                    self.stack.push(map);
+                }
+                OpCode::Equal => self.binary(TokenType::EqualEqual)?,
+                OpCode::NotEqual => self.binary(TokenType::BangEqual)?,
+                OpCode::Greater => self.binary(TokenType::Greater)?,
+                OpCode::GreaterEqual => self.binary(TokenType::GreaterEqual)?,
+                OpCode::Less => self.binary(TokenType::Less)?,
+                OpCode::LessEqual => self.binary(TokenType::LessEqual)?,
+                OpCode::Add => self.binary(TokenType::Plus)?,
+                OpCode::Subtract => self.binary(TokenType::Minus)?,
+                OpCode::Multiply => self.binary(TokenType::Star)?,
+                OpCode::Divide => self.binary(TokenType::Slash)?,
+                OpCode::Not => self.unary(TokenType::Bang)?,
+                OpCode::Negate => self.unary(TokenType::Minus)?,
+                OpCode::Print => {
+                    let value = self.pop();
+                    self.output.borrow_mut().print(&value.to_string());
+                }
+                OpCode::Jump(offset) => self.frame_mut().ip += offset as usize,
+                OpCode::JumpIfFalse(offset) => {
+                    if self.peek(0).is_truthy() != LiteralValue::True {
+                        self.frame_mut().ip += offset as usize;
+                    }
+                }
+                OpCode::Loop(offset) => {
+                    self.step()?;
+                    self.frame_mut().ip -= offset as usize;
+                }
+                OpCode::Call(count) => self.call_value(count as usize)?,
+                OpCode::TailCall(count) => self.tail_call(count as usize)?,
+                OpCode::Closure(index) => {
+                    let function =
+                        self.frame().closure.function.chunk.functions[index as usize].clone();
+                    let mut upvalues = vec![];
+                    for upvalue in &function.upvalues {
+                        let index = upvalue.index as usize;
+                        if upvalue.is_local {
+                            let slot = self.frame().base + index;
+                            upvalues.push(self.capture_upvalue(slot));
+                        } else {
+                            upvalues.push(self.frame().closure.upvalues[index].clone());
+                        }
+                    }
+                    let closure = Closure {
+                        function,
+                        upvalues,
+                        globals: self.frame().closure.globals.clone(),
+                    };
+                    self.stack
+                        .push(LiteralValue::Callable(CallableImpl::Closure(Rc::new(
+                            closure,
+                        ))));
+                }
+                OpCode::Return => {
+                    let mut result = self.pop();
+                    let frame = self.frames.pop().unwrap();
+                    self.close_upvalues(frame.base);
+                    if frame.constructor {
+                        result = self.stack[frame.base].clone();
+                    }
+                    self.stack.truncate(frame.base);
+                    if self.frames.len() == depth {
+                        return Ok(());
+                    }
+                    self.stack.push(result);
+                }
+                OpCode::PushHandler(offset, finally) => {
+                    let ip = self.frame().ip + offset as usize;
+                    self.handlers.push(Handler {
+                        frames: self.frames.len(),
+                        stack_len: self.stack.len(),
+                        ip,
+                        finally,
+                        pending: self.pending.len(),
+                    });
+                }
+                OpCode::PopHandler => {
+                    self.handlers.pop();
+                }
+                OpCode::Throw => {
+                    let value = self.pop();
+                    let err = thrown_error(value, self.span());
+                    return Err(if err.trace().is_empty() {
+                        err.with_trace(self.trace())
+                    } else {
+                        err
+                    });
+                }
+                OpCode::Rethrow => return Err(self.pending.pop().unwrap()),
+                OpCode::Import(index) => {
+                    let path = self.name(index).to_string();
+                    let span = self.span();
+                    let modules = self.modules.clone();
+                    let sandbox = self.sandbox.clone();
+                    let module = import(&modules, &sandbox.borrow(), &path, span, |stmts, _| {
+                        self.run_module(stmts)
+                    })
+                    .map_err(|err| err.with_trace(self.trace()))?;
+                    self.stack.push(LiteralValue::Module(module));
+                }
+                OpCode::ImportAll => {
+                    if let LiteralValue::Module(module) = self.pop() {
+                        let mut globals = self.frame().closure.globals.borrow_mut();
+                        for (name, value) in &module.exports {
+                            globals.insert(name.clone(), value.clone());
+                        }
+                    }
+                }
+                OpCode::Class(index, inherits) => {
+                    let superclass = if inherits {
+                        match self.peek(0) {
+                            LiteralValue::VmClass(superclass) => Some(superclass.clone()),
+                            other => {
+                                return Err(self.error(
+                                    ErrorCode::InvalidSuperclass,
+                                    format!("Superclass must be a class, not {}", other.to_type()),
+                                ))
+                            }
+                        }
+                    } else {
+                        None
+                    };
+                    let class = Class {
+                        name: self.name(index).to_string(),
+                        methods: RefCell::new(HashMap::new()),
+                        superclass,
+                    };
+                    self.stack.push(LiteralValue::VmClass(Rc::new(class)));
+                }
+                OpCode::Method(index) => {
+                    let method = match self.pop() {
+                        LiteralValue::Callable(CallableImpl::Closure(method)) => method,
+                        _ => {
+                            return Err(self.error(
+                                ErrorCode::TypeMismatch,
+                                "Methods of a class must be functions".to_string(),
+                            ))
+                        }
+                    };
+                    if let LiteralValue::VmClass(class) = self.peek(0) {
+                        class
+                            .methods
+                            .borrow_mut()
+                            .insert(self.name(index).to_string(), method);
+                    }
+                }
+                OpCode::AssignClass(index) => {
+                    let name = self.name(index);
+                    if !self.frame().closure.globals.borrow().contains_key(name) {
+                        return Err(self
+                            .error(
+                                ErrorCode::UndefinedVariable,
+                                format!("Class definition failed for {}", name),
+                            )
+                            .with_note(
+                                "Classes can only be declared at the top level".to_string(),
+                            ));
+                    }
+                    let name = name.to_string();
+                    let class = self.pop();
+                    self.frame()
+                        .closure
+                        .globals
+                        .borrow_mut()
+                        .insert(name, class);
+                }
+            }
+        }
+    }
+
+    fn frame(&self) -> &Frame {
+        self.frames.last().unwrap()
+    }
+
+    fn frame_mut(&mut self) -> &mut Frame {
+        self.frames.last_mut().unwrap()
+    }
+
+    fn name(&self, index: u16) -> &str {
+        &self.frame().closure.function.chunk.names[index as usize]
+    }
+
+    // The span of the instruction that is running
+    fn span(&self) -> Span {
+        let frame = self.frame();
+        frame.closure.function.chunk.spans[frame.ip - 1]
+    }
+
+    fn pop(&mut self) -> LiteralValue {
+        self.stack.pop().expect("Stack underflow")
+    }
+
+    fn peek(&self, distance: usize) -> &LiteralValue {
+        &self.stack[self.stack.len() - 1 - distance]
+    }
+
+    // The first frame runs the script itself and is not a call
+    fn trace(&self) -> Vec<CallFrame> {
+        let mut trace = vec![];
+        for frame in &self.frames[1..] {
+            if let Some(tail_caller) = &frame.tail_caller {
+                trace.push(tail_caller.clone());
+            }
+            trace.push(frame.call_frame());
+        }
+        trace
+    }
+
+    // A loop iteration or a call, counted against the limits
+    fn step(&self) -> Result<(), ValkyrieError> {
+        self.budget
+            .step(self.span())
+            .map_err(|err| err.with_trace(self.trace()))
+    }
+
+    fn check_size(&self, value: &LiteralValue) -> Result<(), ValkyrieError> {
+        self.budget
+            .check_size(value, self.span())
+            .map_err(|err| err.with_trace(self.trace()))
+    }
+
+    fn error(&self, code: ErrorCode, message: String) -> ValkyrieError {
+        ValkyrieError::runtime(code, message, self.span()).with_trace(self.trace())
+    }
+
+    fn binary(&mut self, operator: TokenType) -> Result<(), ValkyrieError> {
+        let right = self.pop();
+        let left = self.pop();
+        let value = binary_op(&left, operator, &right, self.span())
+            .map_err(|err| err.with_trace(self.trace()))?;
+        self.check_size(&value)?;
+        self.stack.push(value);
+        Ok(())
+    }
+
+    fn unary(&mut self, operator: TokenType) -> Result<(), ValkyrieError> {
+        let right = self.pop();
+        let value =
+            unary_op(operator, &right, self.span()).map_err(|err| err.with_trace(self.trace()))?;
+        self.stack.push(value);
+        Ok(())
+    }
+
+    fn get_property(
+        &self,
+        object: &LiteralValue,
+        index: u16,
+    ) -> Result<LiteralValue, ValkyrieError> {
+        let name = self.name(index);
+        match object {
+            LiteralValue::LoxInstance { class, fields } => {
+                if let Some((_, value)) = fields.borrow().iter().find(|(field, _)| field == name) {
+                    return Ok(value.clone());
+                }
+                let method = match class.as_ref() {
+                    LiteralValue::VmClass(class) => class.find_method(name),
+                    other => {
+                        return Err(self.error(
+                            ErrorCode::TypeMismatch,
+                            format!("Instances must belong to a class, not {}", other.to_type()),
+                        ))
+                    }
+                };
+                match method {
+                    Some(method) => Ok(LiteralValue::Callable(CallableImpl::BoundMethod(Rc::new(
+                        BoundMethod {
+                            receiver: object.clone(),
+                            method,
+                        },
+                    )))),
+                    None => Err(self.error(
+                        ErrorCode::UndefinedProperty,
+                        format!("No field named {} on this instance", name),
+                    )),
+                }
+            }
+            LiteralValue::Error(error) => {
+                error_property(error, name, self.span()).map_err(|err| err.with_trace(self.trace()))
+            }
+            LiteralValue::Module(module) => module_property(module, name, self.span())
+                .map_err(|err| err.with_trace(self.trace())),
+            other => Err(self.error(
+                ErrorCode::TypeMismatch,
+                format!("Cannot access property on type {}", other.to_type()),
+            )),
+        }
+    }
+
+    fn call_value(&mut self, count: usize) -> Result<(), ValkyrieError> {
+        let slot = self.stack.len() - 1 - count;
+        let callee = self.stack[slot].clone();
+        match callee {
+            LiteralValue::Callable(CallableImpl::Closure(closure)) => {
+                self.call_closure(closure, count, false)
+            }
+            LiteralValue::Callable(CallableImpl::BoundMethod(bound)) => {
+                self.stack[slot] = bound.receiver.clone();
+                self.call_closure(bound.method.clone(), count, false)
+            }
+            LiteralValue::Callable(CallableImpl::NativeFunction(native)) => {
+                self.check_depth()?;
+                native
+                    .check_arity(count, self.span())
+                    .map_err(|err| err.with_trace(self.trace()))?;
+                self.step()?;
+                let arguments = self.stack.split_off(slot + 1);
+                self.stack.pop();
+                let value = self
+                    .budget
+                    .call_native(|| (native.fun)(&arguments))
+                    .map_err(|err| err.with_span(self.span()).with_trace(self.trace()))?;
+                check_native_sizes(&self.budget, &value, &arguments, self.span())
+                    .map_err(|err| err.with_trace(self.trace()))?;
+                self.stack.push(value);
+                Ok(())
+            }
+            LiteralValue::VmClass(ref class) => {
+                self.stack[slot] = LiteralValue::LoxInstance {
+                    class: Box::new(callee.clone()),
+                    fields: Rc::new(RefCell::new(vec![])),
+                };
+
+                // Only the class's own init runs, as with the tree-walker
+                let init = class.methods.borrow().get("init").cloned();
+                match init {
+                    Some(init) => {
+                        if init.function.arity != count {
+                            return Err(self.error(
+                                ErrorCode::ArityMismatch,
+                                "Invalid number of arguments in constructor".to_string(),
+                            ));
+                        }
+                        self.call_closure(init, count, true)
+                    }
+                    None => {
+                        self.stack.truncate(slot + 1);
+                        Ok(())
+                    }
+                }
+            }
+            other => Err(self.error(
+                ErrorCode::NotCallable,
+                format!("{} is not callable", other.to_type()),
+            )),
+        }
+    }
+
+    // Natives and classes without init have already returned when call_value is done, the Return
+    // that follows the tail call returns their result like it does for any other value
+    fn tail_call(&mut self, count: usize) -> Result<(), ValkyrieError> {
+        let depth = self.frames.len();
+        self.call_value(count)?;
+        // The frame of an init has to stay, it returns the instance
+        if self.frames.len() == depth || self.frames[depth - 1].constructor {
+            return Ok(());
+        }
+
+        let mut caller = self.frames.remove(depth - 1);
+        self.close_upvalues(caller.base);
+        let tail_caller = caller
+            .tail_caller
+            .take()
+            .unwrap_or_else(|| caller.call_frame());
+        let callee = &mut self.frames[depth - 1];
+        let callee_base = callee.base;
+        callee.base = caller.base;
+        callee.tail_caller = Some(tail_caller);
+        self.stack.drain(caller.base..callee_base);
+        Ok(())
+    }
+
+    fn call_closure(
+        &mut self,
+        closure: Rc<Closure>,
+        count: usize,
+        constructor: bool,
+    ) -> Result<(), ValkyrieError> {
+        let function = &closure.function;
+        if function.arity != count {
+            return Err(self.error(
+                ErrorCode::ArityMismatch,
+                format!(
+                    "Callable {} expected {} arguments but got {}",
+                    function.name, function.arity, count
+                ),
+            ));
+        }
+
+        self.check_depth()?;
+        self.step()?;
+        let call_span = self.span();
+        self.frames.push(Frame {
+            closure,
+            ip: 0,
+            base: self.stack.len() - 1 - count,
+            call_span,
+            constructor,
+            tail_caller: None,
+        });
+        Ok(())
+    }
+
+    // The frame of the script does not count as a call
+    fn check_depth(&self) -> Result<(), ValkyrieError> {
+        if self.frames.len() > self.max_depth {
+            return Err(stack_overflow(self.max_depth, self.span()).with_trace(self.trace()));
+        }
+        Ok(())
+    }
+
+    fn capture_upvalue(&mut self, slot: usize) -> Rc<RefCell<Upvalue>> {
+        for upvalue in &self.open_upvalues {
+            if let Upvalue::Open(open) = &*upvalue.borrow() {
+                if *open == slot {
+                    return upvalue.clone();
+                }
+            }
+        }
+        let upvalue = Rc::new(RefCell::new(Upvalue::Open(slot)));
+        self.open_upvalues.push(upvalue.clone());
+        upvalue
+    }
+
+    // Closes every upvalue that points at the given slot or above it
+    fn close_upvalues(&mut self, from: usize) {
+        let stack = &self.stack;
+        self.open_upvalues.retain(|upvalue| {
+            let slot = match &*upvalue.borrow() {
+                Upvalue::Open(slot) => *slot,
+                Upvalue::Closed(_) => return false,
+            };
+            if slot < from {
+                return true;
+            }
+            *upvalue.borrow_mut() = Upvalue::Closed(stack[slot].clone());
+            false
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::Parser;
+    use crate::scanner::Scanner;
+
+    fn run(source: &str) -> Vm {
+        let tokens = Scanner::new(source).scan_tokens().unwrap();
+        let stmts = Parser::new(tokens).parse().unwrap();
+        let mut vm = Vm::new();
+        vm.interpret(stmts.iter().collect()).unwrap();
+        vm
+    }
+
+    #[test]
+    fn closures_share_captured_variable() {
+        let vm = run("
+            var get; var set;
+            {
+                var a = 1;
+                fun g() { return a; }
+                fun s(x) { a = x; }
+                get = g; set = s;
+            }
+            set(5);
+            var result = get();
+        ");
+        assert_eq!(vm.globals.borrow()["result"], LiteralValue::Number(5.0));
+        assert!(vm.stack.is_empty());
+    }
+
+    #[test]
+    fn break_closes_captured_locals() {
+        let vm = run("
+            var fs = [];
+            for (var i = 0; i < 5; i = i + 1) {
+                var j = i * 10;
+                push(fs, fun () { return j; });
+                if (i == 2) break;
+            }
+            var result = fs[0]() + fs[1]() + fs[2]();
+        ");
+        assert_eq!(vm.globals.borrow()["result"], LiteralValue::Number(30.0));
+        assert!(vm.stack.is_empty());
+    }
+
+    #[test]
+    fn stack_overflow_is_a_runtime_error() {
+        let tokens = Scanner::new("fun f(n) { return 1 + f(n + 1); } f(0);")
+            .scan_tokens()
+            .unwrap();
+        let stmts = Parser::new(tokens).parse().unwrap();
+        let mut vm = Vm::new();
+        vm.set_max_depth(10);
+        let err = vm.interpret(stmts.iter().collect()).unwrap_err();
+        assert_eq!(err.code(), ErrorCode::StackOverflow);
+        assert_eq!(err.trace().len(), 10);
+        assert!(vm.frames.is_empty());
+    }
+
+    #[test]
+    fn caught_errors_unwind_frames_and_stack() {
+        let vm = run("
+            fun f(n) { if (n == 0) throw n; return 1 + f(n - 1); }
+            var result;
+            for (var i = 0; i < 3; i = i + 1) {
+                try { f(5); } catch (e) { result = e; } finally { result = result + 1; }
+            }
+        ");
+        assert_eq!(vm.globals.borrow()["result"], LiteralValue::Number(1.0));
+        assert!(vm.stack.is_empty());
+        assert!(vm.handlers.is_empty());
+        assert!(vm.pending.is_empty());
+    }
+
+    #[test]
+    fn globals_are_kept_between_runs() {
+        let mut vm = run("var a = 1;");
+        let tokens = Scanner::new("a = a + 1;").scan_tokens().unwrap();
+        let stmts = Parser::new(tokens).parse().unwrap();
+        vm.interpret(stmts.iter().collect()).unwrap();
+        assert_eq!(vm.globals.borrow()["a"], LiteralValue::Number(2.0));
+    }
+}